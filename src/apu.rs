@@ -0,0 +1,481 @@
+use std::ops::RangeInclusive;
+
+use crate::apu_channels::{DirectSoundChannel, NoiseChannel, SquareChannel, WaveChannel};
+use crate::resampler::Resampler;
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+use crate::system_memory::SysMem;
+
+const CYCLES_PER_SECOND: u32 = 1 << 24;
+// The mixer runs at 32768 Hz by default, up to 262144 Hz depending on SOUNDBIAS
+const BASE_SAMPLE_CYCLES: u32 = 512;
+pub const DEFAULT_OUTPUT_RATE: u32 = 48000;
+// The frame sequencer clocks lengths, sweep and envelopes at 512 Hz
+pub const FRAME_SEQUENCER_CYCLES: u32 = CYCLES_PER_SECOND / 512;
+
+const SOUND_REGS_START: usize = 0x060;
+const SOUNDCNT_L: usize = 0x080;
+const SOUNDCNT_H: usize = 0x082;
+const SOUNDCNT_X: usize = 0x084;
+const SOUNDBIAS: usize = 0x088;
+const WAVE_RAM: RangeInclusive<usize> = 0x090..=0x09F;
+const FIFO_A: RangeInclusive<usize> = 0x0A0..=0x0A3;
+const FIFO_B: RangeInclusive<usize> = 0x0A4..=0x0A7;
+
+pub const FIFO_A_ADDRESS: u32 = 0x0400_00A0;
+pub const FIFO_B_ADDRESS: u32 = 0x0400_00A4;
+// DMA refills a FIFO once half of it has been played
+const FIFO_REFILL_THRESHOLD: usize = 16;
+
+const SOUNDCNT_X_MASTER_ENABLE: u8 = 1 << 7;
+const DEFAULT_BIAS: u16 = 0x200;
+
+// Readable bits of every byte of the 0x060-0x08F block, write-only and unused bits read as 0
+const READ_MASKS: [u8; 0x30] = [
+    0x7F, 0x00, 0xC0, 0xFF, 0x00, 0x40, 0x00, 0x00, // SOUND1CNT
+    0xC0, 0xFF, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00, // SOUND2CNT
+    0xE0, 0x00, 0x00, 0xE0, 0x00, 0x40, 0x00, 0x00, // SOUND3CNT
+    0x00, 0xFF, 0x00, 0x00, 0xFF, 0x40, 0x00, 0x00, // SOUND4CNT
+    0x77, 0xFF, 0x0F, 0x77, 0x80, 0x00, 0x00, 0x00, // SOUNDCNT_L/H/X
+    0xFE, 0xC3, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // SOUNDBIAS
+];
+
+#[allow(clippy::upper_case_acronyms)]
+pub struct APU {
+    square1: SquareChannel,
+    square2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+    direct_sound: [DirectSoundChannel; 2],
+
+    registers: [u8; 0x30],
+    frame_sequencer_step: u8,
+
+    // Converts the mixer output to the host sample rate
+    resampler: Resampler
+}
+
+impl APU {
+    pub fn new() -> Self {
+        APU {
+            square1: SquareChannel::new(true),
+            square2: SquareChannel::new(false),
+            wave: WaveChannel::new(),
+            noise: NoiseChannel::new(),
+            direct_sound: [DirectSoundChannel::new(); 2],
+            registers: Self::initial_registers(),
+            frame_sequencer_step: 0,
+            resampler: Resampler::new(DEFAULT_OUTPUT_RATE)
+        }
+    }
+
+    fn initial_registers() -> [u8; 0x30] {
+        let mut registers = [0; 0x30];
+        registers[SOUNDBIAS - SOUND_REGS_START..=SOUNDBIAS + 1 - SOUND_REGS_START].copy_from_slice(&DEFAULT_BIAS.to_le_bytes());
+
+        registers
+    }
+
+    fn register16(&self, offset: usize) -> u16 {
+        let index = offset - SOUND_REGS_START;
+        u16::from_le_bytes([self.registers[index], self.registers[index + 1]])
+    }
+
+    fn is_enabled(&self) -> bool {
+        (self.registers[SOUNDCNT_X - SOUND_REGS_START] & SOUNDCNT_X_MASTER_ENABLE) != 0
+    }
+
+    pub fn read8(&self, offset: usize) -> u8 {
+        if WAVE_RAM.contains(&offset) {
+            return self.wave.read_wave_ram(offset - WAVE_RAM.start());
+        }
+
+        let index = offset - SOUND_REGS_START;
+
+        match offset {
+            // The low bits of SOUNDCNT_X report which PSG channels are playing
+            SOUNDCNT_X => (self.registers[index] & READ_MASKS[index])
+                | (self.square1.enabled as u8)
+                | ((self.square2.enabled as u8) << 1)
+                | ((self.wave.enabled as u8) << 2)
+                | ((self.noise.enabled as u8) << 3),
+            _ if index < READ_MASKS.len() => self.registers[index] & READ_MASKS[index],
+            _ => 0
+        }
+    }
+
+    pub fn write8(&mut self, offset: usize, value: u8) {
+        if WAVE_RAM.contains(&offset) {
+            self.wave.write_wave_ram(offset - WAVE_RAM.start(), value);
+            return;
+        } else if FIFO_A.contains(&offset) {
+            self.direct_sound[0].push(value);
+            return;
+        } else if FIFO_B.contains(&offset) {
+            self.direct_sound[1].push(value);
+            return;
+        }
+
+        // The PSG registers are reset and can't be written while the sound is turned off
+        if offset <= SOUNDCNT_L + 1 && !self.is_enabled() {
+            return;
+        }
+
+        let index = offset - SOUND_REGS_START;
+
+        if index >= self.registers.len() {
+            return;
+        }
+
+        self.registers[index] = value;
+
+        match offset {
+            0x060..=0x065 => self.square1.write8(offset - 0x060, value),
+            0x068 | 0x069 => self.square2.write8(offset - 0x068 + 2, value),
+            0x06C | 0x06D => self.square2.write8(offset - 0x06C + 4, value),
+            0x070..=0x075 => self.wave.write8(offset - 0x070, value),
+            0x078 | 0x079 => self.noise.write8(offset - 0x078, value),
+            0x07C | 0x07D => self.noise.write8(offset - 0x07C + 4, value),
+            // The FIFO reset bits always read as 0
+            0x083 => {
+                if (value & (1 << 3)) != 0 {
+                    self.direct_sound[0].reset();
+                }
+
+                if (value & (1 << 7)) != 0 {
+                    self.direct_sound[1].reset();
+                }
+            },
+            SOUNDCNT_X if (value & SOUNDCNT_X_MASTER_ENABLE) == 0 => self.reset_psg(),
+            _ => {}
+        }
+    }
+
+    fn reset_psg(&mut self) {
+        self.square1 = SquareChannel::new(true);
+        self.square2 = SquareChannel::new(false);
+        self.wave.reset();
+        self.noise = NoiseChannel::new();
+
+        self.registers[..=SOUNDCNT_L + 1 - SOUND_REGS_START].fill(0);
+    }
+
+    /// Steps of 0, 2, 4 and 6 clock the lengths, 2 and 6 the sweep, 7 the envelopes.
+    pub fn step_frame_sequencer(&mut self) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let step = self.frame_sequencer_step;
+
+        if (step & 1) == 0 {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+
+        if step == 2 || step == 6 {
+            self.square1.clock_sweep();
+        }
+
+        if step == 7 {
+            self.square1.clock_envelope();
+            self.square2.clock_envelope();
+            self.noise.clock_envelope();
+        }
+
+        self.frame_sequencer_step = (step + 1) % 8;
+    }
+
+    /// Timer overflows play the next sample of the FIFOs using that timer.
+    /// Returns which FIFOs are running low and want a DMA transfer.
+    pub fn advance_fifos(&mut self, timer_index: usize) -> [bool; 2] {
+        let control = self.register16(SOUNDCNT_H);
+        let mut needs_refill = [false; 2];
+
+        if !self.is_enabled() {
+            return needs_refill;
+        }
+
+        for (fifo_index, channel) in self.direct_sound.iter_mut().enumerate() {
+            let selected_timer = ((control >> (10 + 4 * fifo_index)) & 1) as usize;
+
+            if selected_timer == timer_index {
+                channel.advance();
+                needs_refill[fifo_index] = channel.queued_samples() <= FIFO_REFILL_THRESHOLD;
+            }
+        }
+
+        needs_refill
+    }
+
+    /// SOUNDBIAS bits 14-15 trade amplitude resolution (9 to 6 bits) for a higher sampling rate.
+    fn resolution(&self) -> u32 {
+        (self.register16(SOUNDBIAS) >> 14) as u32
+    }
+
+    pub fn sample_cycles(&self) -> u32 {
+        BASE_SAMPLE_CYCLES >> self.resolution()
+    }
+
+    /// Runs the channels for one sample period, then mixes their output and resamples it.
+    pub fn generate_sample(&mut self) {
+        let sample_cycles = self.sample_cycles();
+
+        let frame = if self.is_enabled() {
+            self.square1.step(sample_cycles);
+            self.square2.step(sample_cycles);
+            self.wave.step(sample_cycles);
+            self.noise.step(sample_cycles);
+
+            self.mix()
+        } else {
+            [0; 2]
+        };
+
+        self.resampler.push(frame, CYCLES_PER_SECOND / sample_cycles);
+    }
+
+    /// Mixes the PSG and Direct Sound like the hardware does, on 10 bits around SOUNDBIAS.
+    /// The result is centered and scaled to the i16 range.
+    fn mix(&self) -> [i16; 2] {
+        let control = self.register16(SOUNDCNT_H);
+        let bias = (self.register16(SOUNDBIAS) & 0x3FE) as i32;
+        // Lower bits are dropped with the resolution
+        let resolution_mask = !((2 << self.resolution()) - 1);
+
+        let (psg_left, psg_right) = self.mix_psg();
+        // SOUNDCNT_H bits 0-1: 25%, 50% or 100% of the PSG output
+        let psg_shift = 2 - ((control & 3) as i32).min(2);
+
+        let mut frame = [0; 2];
+
+        for (side, psg) in [psg_left, psg_right].into_iter().enumerate() {
+            let mut level = (psg as i32) >> psg_shift;
+
+            for (fifo_index, channel) in self.direct_sound.iter().enumerate() {
+                let fifo_control = control >> (4 * fifo_index);
+                // Right enable is bit 8, left enable bit 9
+                let side_enable = if side == 0 { 1 << 9 } else { 1 << 8 };
+
+                if (fifo_control & side_enable) != 0 {
+                    // 50% or 100% volume
+                    let volume_shift = if (control & (4 << fifo_index)) != 0 { 2 } else { 1 };
+                    level += (channel.output() as i32) << volume_shift;
+                }
+            }
+
+            let output = (level + bias).clamp(0, 0x3FF) & resolution_mask;
+            frame[side] = ((output - bias) * 64).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        }
+
+        frame
+    }
+
+    /// PSG output of both sides, between -480 and 480 each.
+    fn mix_psg(&self) -> (i16, i16) {
+        let control = self.register16(SOUNDCNT_L);
+        let outputs = [self.square1.output(), self.square2.output(), self.wave.output(), self.noise.output()];
+
+        let mix_side = |volume_shift: u16, enable_shift: u16| {
+            let sum: i16 = (0..4)
+                .filter(|channel| (control & (1 << (enable_shift + channel))) != 0)
+                .map(|channel| outputs[channel as usize])
+                .sum();
+
+            sum * (((control >> volume_shift) & 7) as i16 + 1)
+        };
+
+        (mix_side(4, 12), mix_side(0, 8))
+    }
+
+    pub fn output_rate(&self) -> u32 {
+        self.resampler.output_rate()
+    }
+
+    pub fn set_output_rate(&mut self, output_rate: u32) {
+        self.resampler.set_output_rate(output_rate);
+    }
+
+    /// Interleaved stereo samples at the output rate, produced since the last call to `clear_samples`.
+    pub fn samples(&self) -> &[f32] {
+        self.resampler.output()
+    }
+
+    pub fn clear_samples(&mut self) {
+        self.resampler.clear_output();
+    }
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SysMem {
+    /// Plays the next Direct Sound samples on a timer 0/1 overflow, asking DMA 1/2 for more when needed.
+    pub fn advance_direct_sound(&mut self, timer_index: usize) {
+        let needs_refill = self.apu.advance_fifos(timer_index);
+
+        if needs_refill[0] {
+            self.trigger_sound_dma(FIFO_A_ADDRESS);
+        }
+
+        if needs_refill[1] {
+            self.trigger_sound_dma(FIFO_B_ADDRESS);
+        }
+    }
+}
+
+impl SaveState for APU {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.square1.save_state(writer);
+        self.square2.save_state(writer);
+        self.wave.save_state(writer);
+        self.noise.save_state(writer);
+
+        for channel in self.direct_sound.iter() {
+            channel.save_state(writer);
+        }
+
+        writer.write_bytes(&self.registers);
+        writer.write_u8(self.frame_sequencer_step);
+        self.resampler.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.square1.load_state(reader)?;
+        self.square2.load_state(reader)?;
+        self.wave.load_state(reader)?;
+        self.noise.load_state(reader)?;
+
+        for channel in self.direct_sound.iter_mut() {
+            channel.load_state(reader)?;
+        }
+
+        reader.read_into(&mut self.registers)?;
+        self.frame_sequencer_step = reader.read_u8()?;
+        self.resampler.load_state(reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled_apu() -> APU {
+        let mut apu = APU::new();
+        apu.write8(SOUNDCNT_X, SOUNDCNT_X_MASTER_ENABLE);
+        apu
+    }
+
+    #[test]
+    fn psg_registers_are_locked_while_sound_is_off() {
+        let mut apu = APU::new();
+
+        apu.write8(0x062, 0x80);
+        assert_eq!(apu.read8(0x062), 0);
+
+        apu.write8(SOUNDCNT_X, SOUNDCNT_X_MASTER_ENABLE);
+        apu.write8(0x062, 0xBF);
+        assert_eq!(apu.read8(0x062), 0x80); // Length bits are write-only
+    }
+
+    #[test]
+    fn soundcnt_x_reports_playing_channels() {
+        let mut apu = enabled_apu();
+
+        apu.write8(0x069, 0xF0);
+        apu.write8(0x06D, 0x80);
+        assert_eq!(apu.read8(SOUNDCNT_X), 0x82);
+
+        apu.write8(SOUNDCNT_X, 0);
+        assert_eq!(apu.read8(SOUNDCNT_X), 0);
+        assert_eq!(apu.read8(0x069), 0);
+    }
+
+    #[test]
+    fn mixer_applies_master_volume_and_panning() {
+        let mut apu = enabled_apu();
+
+        apu.write8(0x069, 0xF0); // Square 2 at volume 15
+        apu.write8(0x06D, 0x80);
+        apu.write8(0x080, 0x07); // Right volume 8/8, left volume 1/8
+        apu.write8(0x081, 0x22); // Square 2 on both sides
+
+        let (left, right) = apu.mix_psg();
+        assert_eq!(left.abs(), 15);
+        assert_eq!(right.abs(), 15 * 8);
+
+        apu.write8(0x081, 0x02); // Right side only
+        assert_eq!(apu.mix_psg().0, 0);
+    }
+
+    #[test]
+    fn direct_sound_is_mixed_around_the_bias() {
+        let mut apu = enabled_apu();
+
+        apu.write8(0x082, 0x0C); // Both FIFOs at 100%
+        apu.write8(0x083, 0x62); // FIFO A left, FIFO B left with timer 1
+        apu.write8(0x0A0, 0x40);
+        apu.write8(0x0A4, 0xC0);
+
+        apu.advance_fifos(0);
+        assert_eq!(apu.mix(), [0x40 * 4 * 64, 0]);
+
+        apu.advance_fifos(1);
+        assert_eq!(apu.mix(), [0, 0]);
+    }
+
+    #[test]
+    fn fifo_requests_refill_when_half_empty() {
+        let mut apu = enabled_apu();
+
+        for _ in 0..18 {
+            apu.write8(0x0A0, 1);
+        }
+
+        assert_eq!(apu.advance_fifos(0), [false, true]);
+        assert_eq!(apu.advance_fifos(0), [true, true]);
+    }
+
+    #[test]
+    fn frame_sequencer_runs_length_counters_at_256hz() {
+        let mut apu = enabled_apu();
+
+        apu.write8(0x078, 63); // Length 1
+        apu.write8(0x079, 0xF0);
+        apu.write8(0x07D, 0xC0);
+        assert_eq!(apu.read8(SOUNDCNT_X) & 8, 8);
+
+        apu.step_frame_sequencer();
+        assert_eq!(apu.read8(SOUNDCNT_X) & 8, 0);
+    }
+
+    #[test]
+    fn frame_sequencer_runs_without_triggered_channels() {
+        let mut apu = enabled_apu();
+
+        apu.write8(0x060, 0x11); // Square 1 sweep, period 1
+        apu.write8(0x063, 0xF1); // Square 1 envelope, period 1
+        apu.write8(0x079, 0xF1); // Square 2 envelope, period 1
+
+        for _ in 0..16 {
+            apu.step_frame_sequencer();
+        }
+
+        assert_eq!(apu.read8(SOUNDCNT_X) & 0x0F, 0);
+
+        // A period written after the trigger, which loaded the timer with 0
+        apu.write8(0x069, 0xF0);
+        apu.write8(0x06D, 0x80);
+        apu.write8(0x069, 0xF1);
+
+        for _ in 0..16 {
+            apu.step_frame_sequencer();
+        }
+
+        assert_eq!(apu.read8(SOUNDCNT_X) & 0x0F, 0x02);
+    }
+}
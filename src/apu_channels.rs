@@ -0,0 +1,881 @@
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+
+// Legacy Game Boy sound channels. Their timers count GBA cycles, 4 times the Game Boy clock.
+
+const SQUARE_DUTY_PATTERNS: [[bool; 8]; 4] = [
+    [false, false, false, false, false, false, false, true],
+    [true, false, false, false, false, false, false, true],
+    [true, false, false, false, false, true, true, true],
+    [false, true, true, true, true, true, true, false]
+];
+
+const WAVE_BANK_SIZE: usize = 16;
+const WAVE_BANK_SAMPLES: usize = WAVE_BANK_SIZE * 2;
+
+const CONTROL_LENGTH_ENABLE: u8 = 1 << 6;
+const CONTROL_TRIGGER: u8 = 1 << 7;
+
+/// Disables its channel once it reaches 0, when the length flag of the channel is set.
+#[derive(Clone, Copy)]
+pub struct LengthCounter {
+    counter: u16,
+    max_length: u16,
+    enabled: bool
+}
+
+impl LengthCounter {
+    fn new(max_length: u16) -> Self {
+        LengthCounter {
+            counter: 0,
+            max_length,
+            enabled: false
+        }
+    }
+
+    fn load(&mut self, length: u16) {
+        self.counter = self.max_length - length;
+    }
+
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max_length;
+        }
+    }
+
+    /// Returns false when the channel has to be turned off.
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter != 0;
+        }
+
+        true
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+
+    volume: u8,
+    timer: u8
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope {
+            initial_volume: 0,
+            increase: false,
+            period: 0,
+            volume: 0,
+            timer: 0
+        }
+    }
+
+    fn write(&mut self, value: u8) {
+        self.period = value & 7;
+        self.increase = (value & 8) != 0;
+        self.initial_volume = value >> 4;
+    }
+
+    // The DAC is off when the envelope can only output silence
+    fn is_dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        // The period may have been written after the trigger loaded the timer
+        self.timer = self.timer.saturating_sub(1);
+
+        if self.timer == 0 {
+            self.timer = self.period;
+
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+/// Frequency sweep, only available on square channel 1.
+#[derive(Clone, Copy)]
+pub struct Sweep {
+    shift: u8,
+    decrease: bool,
+    period: u8,
+
+    enabled: bool,
+    shadow_frequency: u16,
+    timer: u8
+}
+
+impl Sweep {
+    fn new() -> Self {
+        Sweep {
+            shift: 0,
+            decrease: false,
+            period: 0,
+            enabled: false,
+            shadow_frequency: 0,
+            timer: 0
+        }
+    }
+
+    fn write(&mut self, value: u8) {
+        self.shift = value & 7;
+        self.decrease = (value & 8) != 0;
+        self.period = (value >> 4) & 7;
+    }
+
+    fn reload_timer(&mut self) {
+        // A period of 0 is handled as 8 by the timer
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn next_frequency(&self) -> u16 {
+        let delta = self.shadow_frequency >> self.shift;
+
+        if self.decrease {
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct SquareChannel {
+    pub(crate) enabled: bool,
+    duty: usize,
+    frequency: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+
+    timer: i32,
+    duty_step: usize
+}
+
+impl SquareChannel {
+    pub fn new(has_sweep: bool) -> Self {
+        SquareChannel {
+            enabled: false,
+            duty: 0,
+            frequency: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            sweep: if has_sweep { Some(Sweep::new()) } else { None },
+            timer: 0,
+            duty_step: 0
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 16
+    }
+
+    /// Register offsets: 0 = sweep, 2/3 = length, duty and envelope, 4/5 = frequency and control.
+    pub fn write8(&mut self, offset: usize, value: u8) {
+        match offset {
+            0 => if let Some(sweep) = self.sweep.as_mut() {
+                sweep.write(value);
+            },
+            2 => {
+                self.length.load((value & 0x3F) as u16);
+                self.duty = (value >> 6) as usize;
+            },
+            3 => {
+                self.envelope.write(value);
+                self.enabled &= self.envelope.is_dac_enabled();
+            },
+            4 => self.frequency = (self.frequency & 0x700) | value as u16,
+            5 => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 7) << 8);
+                self.length.enabled = (value & CONTROL_LENGTH_ENABLE) != 0;
+
+                if (value & CONTROL_TRIGGER) != 0 {
+                    self.trigger();
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.is_dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow_frequency = self.frequency;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+
+            if sweep.shift != 0 && sweep.next_frequency() > 0x7FF {
+                self.enabled = false;
+            }
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        self.enabled &= self.length.clock();
+    }
+
+    pub fn clock_envelope(&mut self) {
+        if self.enabled {
+            self.envelope.clock();
+        }
+    }
+
+    pub fn clock_sweep(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        let Some(sweep) = self.sweep.as_mut() else {
+            return;
+        };
+
+        sweep.timer = sweep.timer.saturating_sub(1);
+
+        if sweep.timer != 0 {
+            return;
+        }
+
+        sweep.reload_timer();
+
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+
+        let new_frequency = sweep.next_frequency();
+
+        if new_frequency > 0x7FF {
+            self.enabled = false;
+        } else if sweep.shift != 0 {
+            sweep.shadow_frequency = new_frequency;
+            self.frequency = new_frequency;
+
+            // The new frequency is checked again for overflow, without being written back
+            if sweep.next_frequency() > 0x7FF {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// Signed output, between -15 and 15.
+    pub fn output(&self) -> i16 {
+        if !self.enabled {
+            return 0;
+        }
+
+        let volume = self.envelope.volume as i16;
+
+        if SQUARE_DUTY_PATTERNS[self.duty][self.duty_step] { volume } else { -volume }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct WaveChannel {
+    pub(crate) enabled: bool,
+    dac_enabled: bool,
+    two_banks: bool,
+    bank: usize,
+    volume: u8,
+    force_volume_75: bool,
+    frequency: u16,
+    length: LengthCounter,
+    wave_ram: [[u8; WAVE_BANK_SIZE]; 2],
+
+    timer: i32,
+    sample_index: usize
+}
+
+impl WaveChannel {
+    pub fn new() -> Self {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            two_banks: false,
+            bank: 0,
+            volume: 0,
+            force_volume_75: false,
+            frequency: 0,
+            length: LengthCounter::new(256),
+            wave_ram: [[0; WAVE_BANK_SIZE]; 2],
+            timer: 0,
+            sample_index: 0
+        }
+    }
+
+    /// Wave RAM contents survive the sound being turned off, unlike the rest of the channel.
+    pub fn reset(&mut self) {
+        *self = WaveChannel {
+            wave_ram: self.wave_ram,
+            ..WaveChannel::new()
+        };
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 8
+    }
+
+    /// Register offsets: 0 = bank control, 2/3 = length and volume, 4/5 = frequency and control.
+    pub fn write8(&mut self, offset: usize, value: u8) {
+        match offset {
+            0 => {
+                self.two_banks = (value & (1 << 5)) != 0;
+                self.bank = ((value >> 6) & 1) as usize;
+                self.dac_enabled = (value & (1 << 7)) != 0;
+                self.enabled &= self.dac_enabled;
+            },
+            2 => self.length.load(value as u16),
+            3 => {
+                self.volume = (value >> 5) & 3;
+                self.force_volume_75 = (value & (1 << 7)) != 0;
+            },
+            4 => self.frequency = (self.frequency & 0x700) | value as u16,
+            5 => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 7) << 8);
+                self.length.enabled = (value & CONTROL_LENGTH_ENABLE) != 0;
+
+                if (value & CONTROL_TRIGGER) != 0 {
+                    self.enabled = self.dac_enabled;
+                    self.length.trigger();
+                    self.timer = self.period();
+                    self.sample_index = 0;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    /// The CPU always accesses the bank that isn't being played.
+    pub fn read_wave_ram(&self, index: usize) -> u8 {
+        self.wave_ram[self.bank ^ 1][index]
+    }
+
+    pub fn write_wave_ram(&mut self, index: usize, value: u8) {
+        self.wave_ram[self.bank ^ 1][index] = value;
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        let samples_count = if self.two_banks { 2 * WAVE_BANK_SAMPLES } else { WAVE_BANK_SAMPLES };
+
+        self.timer -= cycles as i32;
+
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.sample_index = (self.sample_index + 1) % samples_count;
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        self.enabled &= self.length.clock();
+    }
+
+    fn current_sample(&self) -> u8 {
+        // Two banks mode plays the selected bank first, then the other one
+        let bank = (self.bank + self.sample_index / WAVE_BANK_SAMPLES) % 2;
+        let index = self.sample_index % WAVE_BANK_SAMPLES;
+        let byte = self.wave_ram[bank][index / 2];
+
+        // Each byte holds two samples, the upper nibble being played first
+        if (index & 1) == 0 { byte >> 4 } else { byte & 0xF }
+    }
+
+    /// Signed output, between -15 and 15.
+    pub fn output(&self) -> i16 {
+        if !self.enabled {
+            return 0;
+        }
+
+        let sample = self.current_sample() as i16 * 2 - 15;
+
+        if self.force_volume_75 {
+            return sample * 3 / 4;
+        }
+
+        match self.volume {
+            0 => 0,
+            1 => sample,
+            2 => sample / 2,
+            _ => sample / 4
+        }
+    }
+}
+
+impl Default for WaveChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct NoiseChannel {
+    pub(crate) enabled: bool,
+    length: LengthCounter,
+    envelope: Envelope,
+    divisor_code: u8,
+    short_width: bool,
+    shift: u8,
+
+    timer: i32,
+    lfsr: u16
+}
+
+impl NoiseChannel {
+    pub fn new() -> Self {
+        NoiseChannel {
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            divisor_code: 0,
+            short_width: false,
+            shift: 0,
+            timer: 0,
+            lfsr: 0
+        }
+    }
+
+    fn period(&self) -> i32 {
+        let divisor = if self.divisor_code == 0 { 8 } else { 16 * self.divisor_code as i32 };
+
+        (divisor << self.shift) * 4
+    }
+
+    /// Register offsets: 0/1 = length and envelope, 4/5 = frequency and control.
+    pub fn write8(&mut self, offset: usize, value: u8) {
+        match offset {
+            0 => self.length.load((value & 0x3F) as u16),
+            1 => {
+                self.envelope.write(value);
+                self.enabled &= self.envelope.is_dac_enabled();
+            },
+            4 => {
+                self.divisor_code = value & 7;
+                self.short_width = (value & 8) != 0;
+                self.shift = value >> 4;
+            },
+            5 => {
+                self.length.enabled = (value & CONTROL_LENGTH_ENABLE) != 0;
+
+                if (value & CONTROL_TRIGGER) != 0 {
+                    self.enabled = self.envelope.is_dac_enabled();
+                    self.length.trigger();
+                    self.envelope.trigger();
+                    self.timer = self.period();
+                    self.lfsr = if self.short_width { 0x7F } else { 0x7FFF };
+                }
+            },
+            _ => {}
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+
+        while self.timer <= 0 {
+            self.timer += self.period();
+
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+
+            if self.short_width {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+            }
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        self.enabled &= self.length.clock();
+    }
+
+    pub fn clock_envelope(&mut self) {
+        if self.enabled {
+            self.envelope.clock();
+        }
+    }
+
+    /// Signed output, between -15 and 15.
+    pub fn output(&self) -> i16 {
+        if !self.enabled {
+            return 0;
+        }
+
+        let volume = self.envelope.volume as i16;
+
+        if (self.lfsr & 1) == 0 { volume } else { -volume }
+    }
+}
+
+impl Default for NoiseChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const FIFO_SIZE: usize = 32;
+
+/// Direct Sound channel, playing signed 8-bit samples queued in a 32 bytes FIFO.
+#[derive(Clone, Copy)]
+pub struct DirectSoundChannel {
+    fifo: [i8; FIFO_SIZE],
+    read_index: usize,
+    length: usize,
+
+    // Sample being played until the next overflow of the selected timer
+    current_sample: i8
+}
+
+impl DirectSoundChannel {
+    pub fn new() -> Self {
+        DirectSoundChannel {
+            fifo: [0; FIFO_SIZE],
+            read_index: 0,
+            length: 0,
+            current_sample: 0
+        }
+    }
+
+    pub fn queued_samples(&self) -> usize {
+        self.length
+    }
+
+    /// Bytes written while the FIFO is full are lost.
+    pub fn push(&mut self, sample: u8) {
+        if self.length < FIFO_SIZE {
+            self.fifo[(self.read_index + self.length) % FIFO_SIZE] = sample as i8;
+            self.length += 1;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.read_index = 0;
+        self.length = 0;
+    }
+
+    /// Moves on to the next queued sample, the current one keeps playing when the FIFO is empty.
+    pub fn advance(&mut self) {
+        if self.length > 0 {
+            self.current_sample = self.fifo[self.read_index];
+            self.read_index = (self.read_index + 1) % FIFO_SIZE;
+            self.length -= 1;
+        }
+    }
+
+    pub fn output(&self) -> i16 {
+        self.current_sample as i16
+    }
+}
+
+impl Default for DirectSoundChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SaveState for LengthCounter {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.counter);
+        writer.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.counter = reader.read_u16()?;
+        self.enabled = reader.read_bool()?;
+
+        Ok(())
+    }
+}
+
+impl SaveState for Envelope {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.initial_volume);
+        writer.write_bool(self.increase);
+        writer.write_u8(self.period);
+        writer.write_u8(self.volume);
+        writer.write_u8(self.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.initial_volume = reader.read_u8()?;
+        self.increase = reader.read_bool()?;
+        self.period = reader.read_u8()?;
+        self.volume = reader.read_u8()?;
+        self.timer = reader.read_u8()?;
+
+        Ok(())
+    }
+}
+
+impl SaveState for Sweep {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.shift);
+        writer.write_bool(self.decrease);
+        writer.write_u8(self.period);
+        writer.write_bool(self.enabled);
+        writer.write_u16(self.shadow_frequency);
+        writer.write_u8(self.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.shift = reader.read_u8()?;
+        self.decrease = reader.read_bool()?;
+        self.period = reader.read_u8()?;
+        self.enabled = reader.read_bool()?;
+        self.shadow_frequency = reader.read_u16()?;
+        self.timer = reader.read_u8()?;
+
+        Ok(())
+    }
+}
+
+impl SaveState for SquareChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_usize(self.duty);
+        writer.write_u16(self.frequency);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+
+        // Whether the channel has a sweep unit is fixed, not part of the state
+        if let Some(sweep) = self.sweep.as_ref() {
+            sweep.save_state(writer);
+        }
+
+        writer.write_i32(self.timer);
+        writer.write_usize(self.duty_step);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = reader.read_bool()?;
+        self.duty = reader.read_index(SQUARE_DUTY_PATTERNS.len())?;
+        self.frequency = reader.read_u16()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.load_state(reader)?;
+        }
+
+        self.timer = reader.read_i32()?;
+        self.duty_step = reader.read_index(8)?;
+
+        Ok(())
+    }
+}
+
+impl SaveState for WaveChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.dac_enabled);
+        writer.write_bool(self.two_banks);
+        writer.write_usize(self.bank);
+        writer.write_u8(self.volume);
+        writer.write_bool(self.force_volume_75);
+        writer.write_u16(self.frequency);
+        self.length.save_state(writer);
+
+        for bank in self.wave_ram.iter() {
+            writer.write_bytes(bank);
+        }
+
+        writer.write_i32(self.timer);
+        writer.write_usize(self.sample_index);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = reader.read_bool()?;
+        self.dac_enabled = reader.read_bool()?;
+        self.two_banks = reader.read_bool()?;
+        self.bank = reader.read_index(2)?;
+        self.volume = reader.read_u8()?;
+        self.force_volume_75 = reader.read_bool()?;
+        self.frequency = reader.read_u16()?;
+        self.length.load_state(reader)?;
+
+        for bank in self.wave_ram.iter_mut() {
+            reader.read_into(bank)?;
+        }
+
+        self.timer = reader.read_i32()?;
+        self.sample_index = reader.read_index(2 * WAVE_BANK_SAMPLES)?;
+
+        Ok(())
+    }
+}
+
+impl SaveState for NoiseChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        writer.write_u8(self.divisor_code);
+        writer.write_bool(self.short_width);
+        writer.write_u8(self.shift);
+        writer.write_i32(self.timer);
+        writer.write_u16(self.lfsr);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = reader.read_bool()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        self.divisor_code = reader.read_u8()?;
+        self.short_width = reader.read_bool()?;
+        self.shift = reader.read_u8()?;
+        self.timer = reader.read_i32()?;
+        self.lfsr = reader.read_u16()?;
+
+        Ok(())
+    }
+}
+
+impl SaveState for DirectSoundChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for &sample in self.fifo.iter() {
+            writer.write_u8(sample as u8);
+        }
+
+        writer.write_usize(self.read_index);
+        writer.write_usize(self.length);
+        writer.write_u8(self.current_sample as u8);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        for sample in self.fifo.iter_mut() {
+            *sample = reader.read_u8()? as i8;
+        }
+
+        self.read_index = reader.read_index(FIFO_SIZE)?;
+        self.length = reader.read_index(FIFO_SIZE + 1)?;
+        self.current_sample = reader.read_u8()? as i8;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_counter_silences_channel() {
+        let mut square = SquareChannel::new(false);
+
+        square.write8(2, 62); // Length 2
+        square.write8(3, 0xF0); // Volume 15
+        square.write8(5, CONTROL_TRIGGER | CONTROL_LENGTH_ENABLE);
+        assert!(square.enabled);
+
+        square.clock_length();
+        assert!(square.enabled);
+
+        square.clock_length();
+        assert!(!square.enabled);
+        assert_eq!(square.output(), 0);
+    }
+
+    #[test]
+    fn sweep_overflow_disables_square1() {
+        let mut square = SquareChannel::new(true);
+
+        square.write8(0, 0x11); // Period 1, increase, shift 1
+        square.write8(3, 0xF0);
+        square.write8(4, 0x00);
+        square.write8(5, CONTROL_TRIGGER | 0x05); // Frequency 0x500
+
+        square.clock_sweep();
+        assert_eq!(square.frequency, 0x780);
+        assert!(!square.enabled); // 0x780 + 0x3C0 overflows
+    }
+
+    #[test]
+    fn envelope_fades_volume_out() {
+        let mut noise = NoiseChannel::new();
+
+        noise.write8(1, 0x21); // Volume 2, decrease every step
+        noise.write8(5, CONTROL_TRIGGER);
+
+        noise.clock_envelope();
+        assert_eq!(noise.envelope.volume, 1);
+        noise.clock_envelope();
+        noise.clock_envelope();
+        assert_eq!(noise.envelope.volume, 0);
+    }
+
+    #[test]
+    fn wave_channel_plays_upper_nibble_first_from_selected_bank() {
+        let mut wave = WaveChannel::new();
+
+        // Written while bank 1 plays, so it ends up in bank 0
+        wave.write8(0, 0xC0);
+        wave.write_wave_ram(0, 0xF0);
+        wave.write8(0, 0x80);
+        wave.write8(3, 0x20); // 100% volume
+        wave.write8(5, CONTROL_TRIGGER);
+
+        assert_eq!(wave.output(), 15);
+
+        wave.step(wave.period() as u32);
+        assert_eq!(wave.output(), -15);
+    }
+
+    #[test]
+    fn direct_sound_fifo_plays_in_order_and_drops_overflow() {
+        let mut channel = DirectSoundChannel::new();
+
+        for sample in 0..40 {
+            channel.push(sample as u8);
+        }
+
+        assert_eq!(channel.queued_samples(), FIFO_SIZE);
+
+        channel.advance();
+        channel.advance();
+        assert_eq!(channel.output(), 1);
+
+        channel.reset();
+        channel.advance();
+        assert_eq!(channel.output(), 1);
+    }
+
+    #[test]
+    fn noise_short_mode_repeats_every_127_steps() {
+        let mut noise = NoiseChannel::new();
+
+        noise.write8(1, 0xF0);
+        noise.write8(4, 0x08); // 7-bit LFSR
+        noise.write8(5, CONTROL_TRIGGER);
+
+        let period = noise.period() as u32;
+        let initial_lfsr = noise.lfsr & 0x7F;
+
+        noise.step(period * 127);
+        assert_eq!(noise.lfsr & 0x7F, initial_lfsr);
+    }
+}
@@ -1,20 +1,26 @@
 use crate::{arm_instructions::arm_decode_cond_bits, system_memory::{MemoryOperation, SysMem}};
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+use crate::waitstates::{AccessType, AccessWidth};
 
 const SP: usize = 13;
 const LR: usize = 14;
 const PC: usize = 15;
 
+// For the MRS/MSR instructions, still to be written
+#[allow(dead_code)]
 const MODE_BITS_MASK: u32 = 0x0000001F;
 
 const EXCEPTIONS_HANDLERS_ADDRESSES: [u32; 8] = [0x00000000, 0x00000004, 0x00000008, 0x0000000C, 0x00000010, 0x00000014, 0x00000018, 0x0000001C];
 
 #[derive(Clone, Copy, Eq, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 enum CpuStateMode {
     ARM = 0,
     THUMB = 1
 }
 
 #[derive(Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
 enum OperationModes {
     User = 16,
     FIQ = 17,
@@ -25,6 +31,8 @@ enum OperationModes {
     System = 31
 }
 
+#[repr(u32)]
+#[allow(dead_code)]
 enum CPSRBitsMask {
     N = 0x80000000,
     Z = 0x40000000,
@@ -36,6 +44,7 @@ enum CPSRBitsMask {
 }
 
 #[derive(Clone, Copy)]
+#[allow(dead_code)]
 enum ExceptionType {
     Reset,
     UndefinedInstruction,
@@ -110,48 +119,63 @@ impl ARM7TDMI {
     }
 
     fn flush_pipeline(&mut self, sys_mem: &mut SysMem) {
-        if self.cpu_mode == CpuStateMode::ARM {
-            self.pipeline[0] = Some(sys_mem.read32(self.pc() as usize));
-            self.increment_pc();
-            self.pipeline[1] = Some(sys_mem.read32(self.pc() as usize));
-            self.increment_pc();
-        }
-        else {
-            self.pipeline[0] = Some(sys_mem.read16(self.pc() as usize) as u32);
-            self.increment_pc();
-            self.pipeline[1] = Some(sys_mem.read16(self.pc() as usize) as u32);
-            self.increment_pc();
-        }
+        self.pipeline[0] = Some(self.fetch_opcode(sys_mem, AccessType::NonSequential));
+        self.pipeline[1] = Some(self.fetch_opcode(sys_mem, AccessType::Sequential));
+    }
+
+    /// Reads the opcode at PC and moves PC to the next one, counting the cycles the fetch takes.
+    fn fetch_opcode(&mut self, sys_mem: &mut SysMem, access: AccessType) -> u32 {
+        let address = self.pc();
+
+        let opcode = if self.cpu_mode == CpuStateMode::ARM {
+            self.instruction_cycles += sys_mem.waitstates.opcode_fetch_cycles(address, AccessWidth::Word, access);
+            sys_mem.read32(address as usize)
+        } else {
+            self.instruction_cycles += sys_mem.waitstates.opcode_fetch_cycles(address, AccessWidth::Halfword, access);
+            sys_mem.read16(address as usize) as u32
+        };
+
+        self.increment_pc();
+
+        opcode
     }
 
-    pub fn run_instruction(&mut self, sys_mem: &mut SysMem) -> u8 {
+    /// Runs the next instruction, returns the number of cycles it took.
+    pub fn run_instruction(&mut self, sys_mem: &mut SysMem) -> u32 {
+        self.instruction_cycles = 0;
+
+        if sys_mem.interrupts.irq_line() && !self.get_cpsr_bit(CPSRBitsMask::I) {
+            // The IRQ handler returns with SUBS PC, LR, #4 to the instruction that was about to execute
+            let return_address = if self.cpu_mode == CpuStateMode::ARM { self.pc().wrapping_sub(4) } else { self.pc() };
+
+            self.arise_exception(ExceptionType::NormalInterrupt, return_address);
+            self.flush_pipeline(sys_mem);
+        }
+
         let opcode: u32 = self.pipeline[0].unwrap();
         self.pipeline.rotate_left(1);
 
-        if self.cpu_mode == CpuStateMode::ARM {
-            self.pipeline[1] = Some(sys_mem.read32(self.pc() as usize));
-            self.increment_pc();
+        self.pipeline[1] = Some(self.fetch_opcode(sys_mem, AccessType::Sequential));
 
+        if self.cpu_mode == CpuStateMode::ARM {
             if arm_decode_cond_bits(opcode) > 0 { // Execute this instruction
                 let instruction_ptr = self.decode_arm_instruction(opcode);
                 instruction_ptr(opcode, sys_mem);
             }
         }
         else {
-            self.pipeline[1] = Some(sys_mem.read16(self.pc() as usize) as u32);
-            self.increment_pc();
-
             // TODO: Thumb Mode
         }
 
-        // TODO: Return cycles needed for the executed instruction
-        0
+        // TODO: Add the cycles of the executed instruction itself (only the opcode fetches are counted for now)
+        self.instruction_cycles
     }
 
     fn pc(&self) -> u32 {
        self.gpr[PC]
     }
 
+    #[allow(dead_code)]
     fn pc_mut(&mut self, value: u32) {
         self.gpr[PC] = value;
     }
@@ -173,79 +197,67 @@ impl ARM7TDMI {
         self.cpsr &= !(bit_mask as u32);
     }
 
-    fn get_cpsr_bit(self, bit_mask: CPSRBitsMask) -> bool {
+    fn get_cpsr_bit(&self, bit_mask: CPSRBitsMask) -> bool {
         (self.cpsr & bit_mask as u32) > 0 
     }
 
     fn enter_operation_mode(&mut self, new_mode: OperationModes) {    
         let prev_mode = self.operation_mode;
         
-        // self.cpsr = (self.cpsr & 0x0000001F) | mode as u32;
         self.cpsr = (self.cpsr & 0xFFFFFFE0) | new_mode as u32;
         self.operation_mode = new_mode;
         
+        // Bank out the registers of the previous mode (r8-r12 are only banked in FIQ mode)
         match prev_mode {
-            // OperationModes::User => {},
             OperationModes::FIQ => {
-                self.spsr_fiq = self.cpsr;
                 self.banked_fiq_regs.copy_from_slice(&self.gpr[8..PC]);
             },
-            OperationModes::IRQ => {
-                self.spsr_irq = self.cpsr;      
-                self.banked_irq_regs.copy_from_slice(&self.gpr[SP..PC]);     
-            },
-            OperationModes::Supervisor => {
-                self.spsr_svc = self.cpsr;
-                self.banked_svc_regs.copy_from_slice(&self.gpr[SP..PC]);     
-            },
-            OperationModes::Abort => {
-                self.spsr_abt = self.cpsr;
-                self.banked_abt_regs.copy_from_slice(&self.gpr[SP..PC]);     
-            },
-            OperationModes::Undefined => {
-                self.spsr_und = self.cpsr;
-                self.banked_und_regs.copy_from_slice(&self.gpr[SP..PC]);     
-            },
-            // OperationModes::System => {} 
             _ => {
-                self.spsr_user_sys = self.cpsr;
-                self.banked_user_sys_regs.copy_from_slice(&self.gpr);     
+                self.banked_user_sys_regs[8..SP].copy_from_slice(&self.gpr[8..SP]);
+
+                match prev_mode {
+                    OperationModes::IRQ => self.banked_irq_regs.copy_from_slice(&self.gpr[SP..PC]),
+                    OperationModes::Supervisor => self.banked_svc_regs.copy_from_slice(&self.gpr[SP..PC]),
+                    OperationModes::Abort => self.banked_abt_regs.copy_from_slice(&self.gpr[SP..PC]),
+                    OperationModes::Undefined => self.banked_und_regs.copy_from_slice(&self.gpr[SP..PC]),
+                    _ => self.banked_user_sys_regs[SP..PC].copy_from_slice(&self.gpr[SP..PC])
+                }
             }
         }
 
-        self.gpr[LR] = self.gpr[PC]; // Copy prev. mode PC reg into LR reg for new mode (generic regs)
-
+        // Bank in the registers of the new mode
         match new_mode {
-            // OperationModes::User => {},
             OperationModes::FIQ => {
-                // self.banked_fiq_regs[LR - 8] = self.gpr[PC];
                 self.gpr[8..PC].copy_from_slice(&self.banked_fiq_regs);
             },
-            OperationModes::IRQ => {
-                // self.banked_irq_regs[1] = self.gpr[PC];
-                self.gpr[SP..PC].copy_from_slice(&self.banked_irq_regs);
-            },
-            OperationModes::Supervisor => {
-                // self.banked_svc_regs[1] = self.gpr[PC];
-                self.gpr[SP..PC].copy_from_slice(&self.banked_svc_regs);
-            },
-            OperationModes::Abort => {
-                // self.banked_abt_regs[1] = self.gpr[PC];
-                self.gpr[SP..PC].copy_from_slice(&self.banked_abt_regs);
-            },
-            OperationModes::Undefined => {
-                // self.banked_und_regs[1] = self.gpr[PC];
-                self.gpr[SP..PC].copy_from_slice(&self.banked_und_regs);
-            },
-            // OperationModes::System => {}
             _ => {
-                // self.banked_user_sys_regs[LR] = self.gpr[PC];
-                self.gpr.copy_from_slice(&self.banked_user_sys_regs);
+                self.gpr[8..SP].copy_from_slice(&self.banked_user_sys_regs[8..SP]);
+
+                match new_mode {
+                    OperationModes::IRQ => self.gpr[SP..PC].copy_from_slice(&self.banked_irq_regs),
+                    OperationModes::Supervisor => self.gpr[SP..PC].copy_from_slice(&self.banked_svc_regs),
+                    OperationModes::Abort => self.gpr[SP..PC].copy_from_slice(&self.banked_abt_regs),
+                    OperationModes::Undefined => self.gpr[SP..PC].copy_from_slice(&self.banked_und_regs),
+                    _ => self.gpr[SP..PC].copy_from_slice(&self.banked_user_sys_regs[SP..PC])
+                }
             }
         }
     }
 
-    fn arise_exception(&mut self, exception: ExceptionType) {
+    fn set_spsr(&mut self, value: u32) {
+        match self.operation_mode {
+            OperationModes::FIQ => self.spsr_fiq = value,
+            OperationModes::IRQ => self.spsr_irq = value,
+            OperationModes::Supervisor => self.spsr_svc = value,
+            OperationModes::Abort => self.spsr_abt = value,
+            OperationModes::Undefined => self.spsr_und = value,
+            _ => self.spsr_user_sys = value
+        }
+    }
+
+    fn arise_exception(&mut self, exception: ExceptionType, return_address: u32) {
+        let prev_cpsr = self.cpsr;
+
         match exception {
             ExceptionType::Reset => {
                 self.enter_operation_mode(OperationModes::Supervisor);
@@ -275,7 +287,10 @@ impl ARM7TDMI {
             }
         }
 
-        self.set_cpsr_bit(CPSRBitsMask::T);
+        self.set_spsr(prev_cpsr);
+        self.gpr[LR] = return_address;
+
+        self.clear_cpsr_bit(CPSRBitsMask::T);
         self.cpu_mode = CpuStateMode::ARM;
 
         self.set_cpsr_bit(CPSRBitsMask::I);
@@ -284,6 +299,92 @@ impl ARM7TDMI {
     }
 }
 
+impl Default for ARM7TDMI {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OperationModes {
+    fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            16 => Some(OperationModes::User),
+            17 => Some(OperationModes::FIQ),
+            18 => Some(OperationModes::IRQ),
+            19 => Some(OperationModes::Supervisor),
+            23 => Some(OperationModes::Abort),
+            27 => Some(OperationModes::Undefined),
+            31 => Some(OperationModes::System),
+            _ => None
+        }
+    }
+}
+
+impl SaveState for ARM7TDMI {
+    fn save_state(&self, writer: &mut StateWriter) {
+        let registers = self.gpr.iter()
+            .chain(&self.banked_user_sys_regs)
+            .chain(&self.banked_fiq_regs)
+            .chain(&self.banked_svc_regs)
+            .chain(&self.banked_abt_regs)
+            .chain(&self.banked_irq_regs)
+            .chain(&self.banked_und_regs);
+
+        for &register in registers {
+            writer.write_u32(register);
+        }
+
+        for psr in [self.cpsr, self.spsr_user_sys, self.spsr_fiq, self.spsr_svc, self.spsr_abt, self.spsr_irq, self.spsr_und] {
+            writer.write_u32(psr);
+        }
+
+        writer.write_u8(self.cpu_mode as u8);
+        writer.write_u32(self.operation_mode as u32);
+
+        for opcode in self.pipeline {
+            writer.write_bool(opcode.is_some());
+            writer.write_u32(opcode.unwrap_or(0));
+        }
+
+        writer.write_u32(self.instruction_cycles);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let registers = self.gpr.iter_mut()
+            .chain(&mut self.banked_user_sys_regs)
+            .chain(&mut self.banked_fiq_regs)
+            .chain(&mut self.banked_svc_regs)
+            .chain(&mut self.banked_abt_regs)
+            .chain(&mut self.banked_irq_regs)
+            .chain(&mut self.banked_und_regs);
+
+        for register in registers {
+            *register = reader.read_u32()?;
+        }
+
+        for psr in [&mut self.cpsr, &mut self.spsr_user_sys, &mut self.spsr_fiq, &mut self.spsr_svc, &mut self.spsr_abt, &mut self.spsr_irq, &mut self.spsr_und] {
+            *psr = reader.read_u32()?;
+        }
+
+        self.cpu_mode = match reader.read_u8()? {
+            0 => CpuStateMode::ARM,
+            1 => CpuStateMode::THUMB,
+            _ => return Err(SaveStateError::InvalidValue)
+        };
+        self.operation_mode = OperationModes::from_bits(reader.read_u32()?).ok_or(SaveStateError::InvalidValue)?;
+
+        for opcode in self.pipeline.iter_mut() {
+            let is_fetched = reader.read_bool()?;
+            let value = reader.read_u32()?;
+            *opcode = if is_fetched { Some(value) } else { None };
+        }
+
+        self.instruction_cycles = reader.read_u32()?;
+
+        Ok(())
+    }
+}
+
 // impl MemoryOperation for ARM7TDMI {
 //     fn read8(&self, address: usize) -> u8 {
         
@@ -313,4 +414,21 @@ mod tests {
 
         assert_eq!(cpu.pc(), expected_pc);
     }
+
+    #[test]
+    fn opcode_fetches_from_rom_follow_waitstates_and_prefetch() {
+        let mut sys_mem = SysMem::new();
+        let mut cpu = ARM7TDMI::new();
+
+        cpu.pc_mut(0x0800_0000);
+        cpu.reset(&mut sys_mem);
+
+        // Reset WAITCNT: WS0 sequential 32-bit fetches take 2 * (1 + 2) cycles
+        assert_eq!(cpu.run_instruction(&mut sys_mem), 6);
+
+        sys_mem.write16(0x0400_0204, 0x4014); // WS0 3/1, prefetch on
+        cpu.run_instruction(&mut sys_mem);
+        sys_mem.waitstates.idle_cycles(32);
+        assert_eq!(cpu.run_instruction(&mut sys_mem), 2);
+    }
 }
\ No newline at end of file
@@ -1,3 +1,6 @@
+// Opcode format constants are grouped by the decoding fields instead of by nibbles
+#![allow(clippy::unusual_byte_groupings, clippy::needless_return)]
+
 use crate::system_memory::SysMem;
 
 use super::arm7tdmi::ARM7TDMI;
@@ -36,11 +39,14 @@ pub fn arm_decode_cond_bits(instruction: u32) -> u8 {
     ((instruction >> 28) & 0xF) as u8
 }
 
+// For the data processing instructions, still to be written
+#[allow(dead_code)]
 #[inline]
 pub fn arm_decode_dataproc_opcode(instruction: u32) -> u8 {
     ((instruction >> 21) & 0xF) as u8
 }
 
+#[allow(dead_code)]
 #[inline]
 pub fn arm_is_dataproc_immediate(instruction: u32) -> bool {
     ((instruction >> 25) & 1) == 1
@@ -308,7 +314,11 @@ impl ARM7TDMI {
         // Default
         nop
     }
+}
 
+// Instruction handlers, still to be written and wired to the decoder
+#[allow(dead_code, unused_variables)]
+impl ARM7TDMI {
     fn mul_mla(&mut self, instruction: u32, sys_mem: &mut SysMem) {
 
     }
@@ -0,0 +1,166 @@
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Sample type an audio sink receives.
+pub enum SampleFormat {
+    /// Signed 16-bit samples
+    I16,
+    /// Between -1.0 and 1.0
+    F32
+}
+
+/// Interleaved stereo samples (left first) handed over to an audio sink, in the format it asked for.
+#[derive(Clone, Copy)]
+pub enum AudioSamples<'a> {
+    /// Samples for `SampleFormat::I16`
+    I16(&'a [i16]),
+    /// Samples for `SampleFormat::F32`
+    F32(&'a [f32])
+}
+
+/// Receives the audio output of the GBA, at the sample rate set with `GBA::set_audio_sample_rate`.
+/// Samples are handed over once per frame.
+pub trait AudioSink {
+    /// Format of the samples given to `push_samples`, 16-bit by default.
+    fn sample_format(&self) -> SampleFormat {
+        SampleFormat::I16
+    }
+
+    /// Called with the samples produced since the previous call.
+    fn push_samples(&mut self, samples: AudioSamples);
+}
+
+pub fn f32_to_i16(sample: f32) -> i16 {
+    (sample * 32768.0).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+/// Wraps interleaved 16-bit stereo samples in a WAV file (RIFF, uncompressed PCM).
+pub fn encode_wav(sample_rate: u32, samples: &[i16]) -> Vec<u8> {
+    const CHANNELS: u16 = 2;
+    const BYTES_PER_SAMPLE: u16 = 2;
+    const FORMAT_PCM: u16 = 1;
+
+    let data_length = (samples.len() * BYTES_PER_SAMPLE as usize) as u32;
+    let mut wav = Vec::with_capacity(44 + data_length as usize);
+
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_length).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&FORMAT_PCM.to_le_bytes());
+    wav.extend_from_slice(&CHANNELS.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    // Bytes per second, then per stereo frame
+    wav.extend_from_slice(&(sample_rate * (CHANNELS * BYTES_PER_SAMPLE) as u32).to_le_bytes());
+    wav.extend_from_slice(&(CHANNELS * BYTES_PER_SAMPLE).to_le_bytes());
+    wav.extend_from_slice(&(BYTES_PER_SAMPLE * 8).to_le_bytes());
+
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_length.to_le_bytes());
+    for sample in samples {
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+
+    wav
+}
+
+/// Fixed size FIFO of interleaved stereo samples. When nobody reads it, the oldest samples are
+/// dropped to keep the latency bounded.
+pub struct AudioRingBuffer {
+    samples: Box<[f32]>,
+    read_index: usize,
+    length: usize
+}
+
+impl AudioRingBuffer {
+    pub fn new(capacity_frames: usize) -> Self {
+        AudioRingBuffer {
+            samples: vec![0.0; capacity_frames * 2].into_boxed_slice(),
+            read_index: 0,
+            length: 0
+        }
+    }
+
+    /// Number of queued samples, twice the number of stereo frames.
+    pub fn queued_samples(&self) -> usize {
+        self.length
+    }
+
+    pub fn clear(&mut self) {
+        self.length = 0;
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        let capacity = self.samples.len();
+
+        for &sample in samples {
+            if self.length == capacity {
+                // Drops a whole frame, so that the channels stay in order
+                self.read_index = (self.read_index + 2) % capacity;
+                self.length -= 2;
+            }
+
+            self.samples[(self.read_index + self.length) % capacity] = sample;
+            self.length += 1;
+        }
+    }
+
+    /// Fills `output` with as many whole frames as available, returns the number of samples written.
+    pub fn pop_f32(&mut self, output: &mut [f32]) -> usize {
+        self.pop_with(output, |sample| sample)
+    }
+
+    pub fn pop_i16(&mut self, output: &mut [i16]) -> usize {
+        self.pop_with(output, f32_to_i16)
+    }
+
+    fn pop_with<T>(&mut self, output: &mut [T], convert: impl Fn(f32) -> T) -> usize {
+        let count = output.len().min(self.length) & !1;
+
+        for destination in output[..count].iter_mut() {
+            *destination = convert(self.samples[self.read_index]);
+            self.read_index = (self.read_index + 1) % self.samples.len();
+        }
+
+        self.length -= count;
+
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_buffer_drops_oldest_frames_when_full() {
+        let mut ring_buffer = AudioRingBuffer::new(2);
+
+        ring_buffer.push(&[0.0, 0.0, 0.25, -0.25, 0.5, -0.5]);
+        assert_eq!(ring_buffer.queued_samples(), 4);
+
+        let mut output = [0i16; 3];
+        assert_eq!(ring_buffer.pop_i16(&mut output), 2);
+        assert_eq!(output[..2], [8192, -8192]);
+
+        let mut output = [0.0f32; 4];
+        assert_eq!(ring_buffer.pop_f32(&mut output), 2);
+        assert_eq!(output[..2], [0.5, -0.5]);
+        assert_eq!(ring_buffer.queued_samples(), 0);
+    }
+
+    #[test]
+    fn wav_header_describes_16_bit_stereo() {
+        let wav = encode_wav(48000, &[1, -1, 0x1234, -0x1234]);
+
+        assert_eq!(wav.len(), 44 + 8);
+        assert_eq!(wav[..4], *b"RIFF");
+        assert_eq!(wav[4..8], 44u32.to_le_bytes());
+        assert_eq!(wav[22..24], 2u16.to_le_bytes());
+        assert_eq!(wav[24..28], 48000u32.to_le_bytes());
+        assert_eq!(wav[28..32], 192_000u32.to_le_bytes());
+        assert_eq!(wav[36..40], *b"data");
+        assert_eq!(wav[40..44], 8u32.to_le_bytes());
+        assert_eq!(wav[44..], [0x01, 0x00, 0xFF, 0xFF, 0x34, 0x12, 0xCC, 0xED]);
+    }
+}
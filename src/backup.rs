@@ -0,0 +1,268 @@
+use crate::backup_eeprom::{Eeprom, EepromSize};
+use crate::backup_flash::{Flash, FlashChip};
+use crate::cartridge::{self, GAME_CODE_LENGTH};
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+
+pub const SRAM_SIZE: usize = 32 * 1024;
+
+// Titles known to be misdetected from their library ID strings
+const BACKUP_TYPE_OVERRIDES: [(&[u8; GAME_CODE_LENGTH], BackupType); 6] = [
+    (b"AWRE", BackupType::Flash(FlashChip::Macronix64K)),
+    (b"AW2E", BackupType::Flash(FlashChip::Macronix64K)),
+    (b"ALFE", BackupType::Eeprom(Some(EepromSize::Large))),
+    // No save memory at all, the strings come from linked but unused libraries
+    (b"A2YE", BackupType::None),
+    (b"AI2E", BackupType::None),
+    (b"AI2P", BackupType::None)
+];
+
+/// Kind of save memory of a cartridge.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BackupType {
+    /// No save memory
+    None,
+    /// 32 KiB of battery-backed SRAM
+    Sram,
+    /// Flash memory of the given chip
+    Flash(FlashChip),
+    /// EEPROM, the size is found out from the first DMA or the save file when not given
+    Eeprom(Option<EepromSize>)
+}
+
+impl BackupType {
+    /// Looks for the ID string the Nintendo SDK save library leaves in the ROM, after checking the
+    /// game code against the titles known to get it wrong.
+    pub fn detect(rom: &[u8]) -> Self {
+        if let Some(game_code) = cartridge::game_code(rom) {
+            for (code, backup_type) in BACKUP_TYPE_OVERRIDES {
+                if game_code == code {
+                    return backup_type;
+                }
+            }
+        }
+
+        // The strings are word aligned
+        for offset in (0..rom.len()).step_by(4) {
+            let text = &rom[offset..];
+
+            if text.starts_with(b"EEPROM_V") {
+                return BackupType::Eeprom(None);
+            } else if text.starts_with(b"SRAM_V") {
+                return BackupType::Sram;
+            } else if text.starts_with(b"FLASH1M_V") {
+                return BackupType::Flash(FlashChip::Sanyo128K);
+            } else if text.starts_with(b"FLASH_V") || text.starts_with(b"FLASH512_V") {
+                return BackupType::Flash(FlashChip::Panasonic64K);
+            }
+        }
+
+        // Homebrew usually goes without the SDK library and uses SRAM, which is harmless otherwise
+        BackupType::Sram
+    }
+
+    pub(crate) fn create(self) -> Backup {
+        match self {
+            BackupType::None => Backup::None,
+            BackupType::Sram => Backup::Sram(Sram::new()),
+            BackupType::Flash(chip) => Backup::Flash(Flash::new(chip)),
+            BackupType::Eeprom(size) => Backup::Eeprom(Eeprom::new(size))
+        }
+    }
+}
+
+/// Battery-backed SRAM, on an 8-bit bus and mirrored over the whole backup area.
+pub struct Sram {
+    data: Box<[u8]>,
+    dirty: bool
+}
+
+impl Sram {
+    pub fn new() -> Self {
+        Sram {
+            data: vec![0xFF; SRAM_SIZE].into_boxed_slice(),
+            dirty: false
+        }
+    }
+
+    pub fn read8(&self, address: usize) -> u8 {
+        self.data[address & (SRAM_SIZE - 1)]
+    }
+
+    pub fn write8(&mut self, address: usize, value: u8) {
+        self.data[address & (SRAM_SIZE - 1)] = value;
+        self.dirty = true;
+    }
+}
+
+impl Default for Sram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Save memory of the cartridge, mapped from 0x0E000000.
+pub enum Backup {
+    None,
+    Sram(Sram),
+    Flash(Flash),
+    // Mapped in the Game Pak ROM area instead, see Cartridge
+    Eeprom(Eeprom)
+}
+
+impl Backup {
+    pub fn backup_type(&self) -> BackupType {
+        match self {
+            Backup::None => BackupType::None,
+            Backup::Sram(_) => BackupType::Sram,
+            Backup::Flash(flash) => BackupType::Flash(flash.chip()),
+            Backup::Eeprom(eeprom) => BackupType::Eeprom(eeprom.size())
+        }
+    }
+
+    pub fn read8(&self, address: usize) -> u8 {
+        match self {
+            // Nothing drives the data bus
+            Backup::None => 0xFF,
+            Backup::Sram(sram) => sram.read8(address),
+            Backup::Flash(flash) => flash.read8(address),
+            Backup::Eeprom(_) => 0xFF
+        }
+    }
+
+    pub fn write8(&mut self, address: usize, value: u8) {
+        match self {
+            Backup::None => {},
+            Backup::Sram(sram) => sram.write8(address, value),
+            Backup::Flash(flash) => flash.write8(address, value),
+            Backup::Eeprom(_) => {}
+        }
+    }
+
+    /// Raw contents, as stored in .sav files.
+    pub fn data(&self) -> &[u8] {
+        match self {
+            Backup::None => &[],
+            Backup::Sram(sram) => &sram.data,
+            Backup::Flash(flash) => flash.data(),
+            Backup::Eeprom(eeprom) => eeprom.data()
+        }
+    }
+
+    /// Restores contents from a .sav file. Shorter files only fill the start of the memory.
+    pub fn load(&mut self, save_data: &[u8]) {
+        let data = match self {
+            Backup::None => return,
+            Backup::Sram(sram) => &mut sram.data,
+            Backup::Flash(flash) => flash.data_mut(),
+            Backup::Eeprom(eeprom) => return eeprom.load(save_data)
+        };
+
+        let length = save_data.len().min(data.len());
+        data[..length].copy_from_slice(&save_data[..length]);
+    }
+
+    /// True when the contents changed since they were last saved.
+    pub fn is_dirty(&self) -> bool {
+        match self {
+            Backup::None => false,
+            Backup::Sram(sram) => sram.dirty,
+            Backup::Flash(flash) => flash.is_dirty(),
+            Backup::Eeprom(eeprom) => eeprom.is_dirty()
+        }
+    }
+
+    pub fn clear_dirty(&mut self) {
+        match self {
+            Backup::None => {},
+            Backup::Sram(sram) => sram.dirty = false,
+            Backup::Flash(flash) => flash.clear_dirty(),
+            Backup::Eeprom(eeprom) => eeprom.clear_dirty()
+        }
+    }
+}
+
+impl SaveState for Backup {
+    fn save_state(&self, writer: &mut StateWriter) {
+        match self {
+            Backup::None => writer.write_u8(0),
+            Backup::Sram(sram) => {
+                writer.write_u8(1);
+                writer.write_bytes(&sram.data);
+            },
+            Backup::Flash(flash) => {
+                writer.write_u8(2);
+                writer.write_u8(flash.chip() as u8);
+                flash.save_state(writer);
+            },
+            Backup::Eeprom(eeprom) => {
+                writer.write_u8(3);
+                eeprom.save_state(writer);
+            }
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let kind = reader.read_u8()?;
+
+        match self {
+            Backup::None if kind == 0 => {},
+            Backup::Sram(sram) if kind == 1 => {
+                reader.read_into(&mut sram.data)?;
+                // The save file has to follow the restored contents
+                sram.dirty = true;
+            },
+            Backup::Flash(flash) if kind == 2 => {
+                if reader.read_u8()? != flash.chip() as u8 {
+                    return Err(SaveStateError::CartridgeMismatch);
+                }
+
+                flash.load_state(reader)?;
+            },
+            Backup::Eeprom(eeprom) if kind == 3 => eeprom.load_state(reader)?,
+            _ => return Err(SaveStateError::CartridgeMismatch)
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sram_is_mirrored_and_tracks_writes() {
+        let mut backup = Backup::Sram(Sram::new());
+
+        assert_eq!(backup.read8(0x0E00_0010), 0xFF);
+        assert!(!backup.is_dirty());
+
+        backup.write8(0x0E00_0010, 0x42);
+        assert_eq!(backup.read8(0x0E00_8010), 0x42);
+        assert_eq!(backup.read8(0x0F00_0010), 0x42);
+        assert!(backup.is_dirty());
+
+        backup.clear_dirty();
+        backup.load(&[1, 2, 3]);
+        assert_eq!(backup.data()[..4], [1, 2, 3, 0xFF]);
+    }
+
+    #[test]
+    fn backup_type_is_detected_from_rom() {
+        let mut rom = vec![0; 0x400];
+
+        assert_eq!(BackupType::detect(&rom), BackupType::Sram);
+
+        rom[0x200..0x20C].copy_from_slice(b"FLASH1M_V103");
+        assert_eq!(BackupType::detect(&rom), BackupType::Flash(FlashChip::Sanyo128K));
+
+        rom[0x200..0x20C].copy_from_slice(b"FLASH512_V13");
+        assert_eq!(BackupType::detect(&rom), BackupType::Flash(FlashChip::Panasonic64K));
+
+        rom[0x200..0x20C].copy_from_slice(b"EEPROM_V124\0");
+        assert_eq!(BackupType::detect(&rom), BackupType::Eeprom(None));
+
+        rom[0xAC..0xB0].copy_from_slice(b"AI2E");
+        assert_eq!(BackupType::detect(&rom), BackupType::None);
+    }
+}
@@ -0,0 +1,310 @@
+use std::cell::Cell;
+
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+
+const EEPROM_BLOCK_BITS: u32 = 64;
+const EEPROM_MAX_SIZE: usize = 8 * 1024;
+
+const COMMAND_READ: u128 = 0b11;
+const COMMAND_WRITE: u128 = 0b10;
+
+// A write with a 14-bit address: command, address, data and stop bit
+const MAX_REQUEST_BITS: u32 = 2 + 14 + EEPROM_BLOCK_BITS + 1;
+
+// Reads of a block start with 4 meaningless bits before the 64 data bits
+const READ_DUMMY_BITS: u32 = 4;
+
+// A block write keeps the chip busy for about 6.9ms
+const EEPROM_WRITE_CYCLES: u64 = 115_000;
+
+/// Capacity of an EEPROM chip.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EepromSize {
+    /// 512 bytes, addressed with 6 bits
+    Small,
+    /// 8 KiB, addressed with 14 bits of which only the lower 10 are used
+    Large
+}
+
+impl EepromSize {
+    /// Capacity in bytes.
+    pub fn bytes(self) -> usize {
+        match self {
+            EepromSize::Small => 512,
+            EepromSize::Large => EEPROM_MAX_SIZE
+        }
+    }
+
+    fn address_bits(self) -> u32 {
+        match self {
+            EepromSize::Small => 6,
+            EepromSize::Large => 14
+        }
+    }
+
+    /// Tells the chip size from the length of a DMA sending a read request (command, address
+    /// and stop bit) or a write (command, address, 64 data bits and stop bit).
+    pub fn from_transfer_length(length: u32) -> Option<Self> {
+        match length {
+            9 | 73 => Some(EepromSize::Small),
+            17 | 81 => Some(EepromSize::Large),
+            _ => None
+        }
+    }
+}
+
+/// Serial EEPROM, accessed one bit at a time through bit 0 of 16-bit accesses to the top of the
+/// Game Pak ROM area. Games always talk to it with DMA 3.
+pub struct Eeprom {
+    // None until a transfer length or a save file gives it away
+    size: Option<EepromSize>,
+    data: Box<[u8]>,
+
+    // Bits received so far, the first one being the most significant
+    serial_buffer: u128,
+    serial_length: u32,
+
+    read_block: u64,
+    // Reading doesn't need the bus to be mutable, hence the cell
+    read_bits_left: Cell<u32>,
+
+    busy_until: u64,
+    dirty: bool
+}
+
+impl Eeprom {
+    pub fn new(size: Option<EepromSize>) -> Self {
+        Eeprom {
+            size,
+            data: vec![0xFF; EEPROM_MAX_SIZE].into_boxed_slice(),
+            serial_buffer: 0,
+            serial_length: 0,
+            read_block: 0,
+            read_bits_left: Cell::new(0),
+            busy_until: 0,
+            dirty: false
+        }
+    }
+
+    pub fn size(&self) -> Option<EepromSize> {
+        self.size
+    }
+
+    pub fn detect_size(&mut self, transfer_length: u32) {
+        if self.size.is_none() {
+            self.size = EepromSize::from_transfer_length(transfer_length);
+        }
+    }
+
+    // Without any hint, the bigger chip is assumed since it's the most common one
+    fn size_or_default(&self) -> EepromSize {
+        self.size.unwrap_or(EepromSize::Large)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.size_or_default().bytes()]
+    }
+
+    pub fn load(&mut self, save_data: &[u8]) {
+        if self.size.is_none() && save_data.len() == EepromSize::Small.bytes() {
+            self.size = Some(EepromSize::Small);
+        }
+
+        let length = save_data.len().min(self.data.len());
+        self.data[..length].copy_from_slice(&save_data[..length]);
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn clear_dirty(&mut self) {
+        self.dirty = false;
+    }
+
+    fn block_offset(&self, address: u128) -> usize {
+        let blocks = self.size_or_default().bytes() / 8;
+        (address as usize % blocks) * 8
+    }
+
+    pub fn read16(&self, timestamp: u64) -> u16 {
+        let bits_left = self.read_bits_left.get();
+
+        if bits_left == 0 {
+            // Bit 0 is set once the chip is ready for another command
+            return (timestamp >= self.busy_until) as u16;
+        }
+
+        self.read_bits_left.set(bits_left - 1);
+
+        if bits_left > EEPROM_BLOCK_BITS {
+            0
+        } else {
+            ((self.read_block >> (bits_left - 1)) & 1) as u16
+        }
+    }
+
+    pub fn write16(&mut self, value: u16, timestamp: u64) {
+        self.serial_buffer = (self.serial_buffer << 1) | (value & 1) as u128;
+        self.serial_length += 1;
+
+        if self.serial_length < 2 {
+            return;
+        }
+
+        let address_bits = self.size_or_default().address_bits();
+        let command = self.serial_buffer >> (self.serial_length - 2);
+
+        let request_length = match command {
+            COMMAND_READ => 2 + address_bits + 1,
+            COMMAND_WRITE => 2 + address_bits + EEPROM_BLOCK_BITS + 1,
+            _ => {
+                // Not a command, waits for the start of the next one
+                self.serial_length = 0;
+                self.serial_buffer = 0;
+                return;
+            }
+        };
+
+        if self.serial_length < request_length {
+            return;
+        }
+
+        // The stop bit is ignored
+        let request = self.serial_buffer >> 1;
+
+        if command == COMMAND_READ {
+            let offset = self.block_offset(request & ((1 << address_bits) - 1));
+            let mut block = [0; 8];
+            block.copy_from_slice(&self.data[offset..offset + 8]);
+
+            self.read_block = u64::from_be_bytes(block);
+            self.read_bits_left.set(READ_DUMMY_BITS + EEPROM_BLOCK_BITS);
+        } else {
+            let block = request as u64;
+            let offset = self.block_offset((request >> EEPROM_BLOCK_BITS) & ((1 << address_bits) - 1));
+
+            self.data[offset..offset + 8].copy_from_slice(&block.to_be_bytes());
+            self.busy_until = timestamp + EEPROM_WRITE_CYCLES;
+            self.dirty = true;
+        }
+
+        self.serial_length = 0;
+        self.serial_buffer = 0;
+    }
+}
+
+impl SaveState for Eeprom {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(match self.size {
+            None => 0,
+            Some(EepromSize::Small) => 1,
+            Some(EepromSize::Large) => 2
+        });
+        writer.write_bytes(&self.data);
+
+        writer.write_u64(self.serial_buffer as u64);
+        writer.write_u64((self.serial_buffer >> 64) as u64);
+        writer.write_u32(self.serial_length);
+        writer.write_u64(self.read_block);
+        writer.write_u32(self.read_bits_left.get());
+        writer.write_u64(self.busy_until);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.size = match reader.read_u8()? {
+            0 => None,
+            1 => Some(EepromSize::Small),
+            2 => Some(EepromSize::Large),
+            _ => return Err(SaveStateError::InvalidValue)
+        };
+        reader.read_into(&mut self.data)?;
+
+        self.serial_buffer = reader.read_u64()? as u128 | ((reader.read_u64()? as u128) << 64);
+        self.serial_length = reader.read_u32()?;
+        self.read_block = reader.read_u64()?;
+        self.read_bits_left.set(reader.read_u32()?);
+
+        if self.serial_length > MAX_REQUEST_BITS || self.read_bits_left.get() > READ_DUMMY_BITS + EEPROM_BLOCK_BITS {
+            return Err(SaveStateError::InvalidValue);
+        }
+
+        self.busy_until = reader.read_u64()?;
+        // The save file has to follow the restored contents
+        self.dirty = true;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_bits(eeprom: &mut Eeprom, value: u64, length: u32, timestamp: u64) {
+        for bit in (0..length).rev() {
+            eeprom.write16(((value >> bit) & 1) as u16, timestamp);
+        }
+    }
+
+    #[test]
+    fn transfer_length_gives_away_the_address_width() {
+        assert_eq!(EepromSize::from_transfer_length(9), Some(EepromSize::Small));
+        assert_eq!(EepromSize::from_transfer_length(81), Some(EepromSize::Large));
+        assert_eq!(EepromSize::from_transfer_length(68), None);
+    }
+
+    #[test]
+    fn written_blocks_are_read_back_after_the_busy_time() {
+        let mut eeprom = Eeprom::new(Some(EepromSize::Small));
+
+        send_bits(&mut eeprom, COMMAND_WRITE as u64, 2, 1000);
+        send_bits(&mut eeprom, 3, 6, 1000);
+        send_bits(&mut eeprom, 0x0123_4567_89AB_CDEF, 64, 1000);
+        send_bits(&mut eeprom, 0, 1, 1000);
+
+        assert_eq!(eeprom.read16(1000), 0);
+        assert_eq!(eeprom.read16(1000 + EEPROM_WRITE_CYCLES), 1);
+        assert_eq!(eeprom.data()[24..32], [0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF]);
+        assert!(eeprom.is_dirty());
+
+        send_bits(&mut eeprom, COMMAND_READ as u64, 2, 0);
+        send_bits(&mut eeprom, 3, 6, 0);
+        send_bits(&mut eeprom, 0, 1, 0);
+
+        let mut block = 0u64;
+        for bit in 0..READ_DUMMY_BITS + EEPROM_BLOCK_BITS {
+            let value = eeprom.read16(u64::MAX) as u64;
+
+            if bit < READ_DUMMY_BITS {
+                assert_eq!(value, 0);
+            } else {
+                block = (block << 1) | value;
+            }
+        }
+
+        assert_eq!(block, 0x0123_4567_89AB_CDEF);
+    }
+
+    #[test]
+    fn save_states_with_impossible_transfer_lengths_are_rejected() {
+        let load = |eeprom: &Eeprom| {
+            let mut writer = StateWriter::new();
+            eeprom.save_state(&mut writer);
+            let data = writer.finish();
+
+            Eeprom::new(None).load_state(&mut StateReader::new(&data).unwrap())
+        };
+
+        let mut eeprom = Eeprom::new(Some(EepromSize::Large));
+        eeprom.serial_length = MAX_REQUEST_BITS;
+        assert_eq!(load(&eeprom), Ok(()));
+
+        eeprom.serial_length = 130;
+        assert_eq!(load(&eeprom), Err(SaveStateError::InvalidValue));
+
+        eeprom.serial_length = 0;
+        eeprom.read_bits_left.set(READ_DUMMY_BITS + EEPROM_BLOCK_BITS + 1);
+        assert_eq!(load(&eeprom), Err(SaveStateError::InvalidValue));
+    }
+}
@@ -0,0 +1,308 @@
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+
+const FLASH_BANK_SIZE: usize = 64 * 1024;
+const FLASH_SECTOR_SIZE: usize = 4 * 1024;
+// Atmel chips are written a whole 128 bytes page at a time instead of byte by byte
+const ATMEL_PAGE_SIZE: usize = 128;
+
+// Command sequences start with 0xAA written at 0x5555, then 0x55 at 0x2AAA
+const COMMAND_ADDRESS_1: usize = 0x5555;
+const COMMAND_ADDRESS_2: usize = 0x2AAA;
+
+const COMMAND_ENTER_ID_MODE: u8 = 0x90;
+const COMMAND_EXIT_ID_MODE: u8 = 0xF0;
+const COMMAND_PREPARE_ERASE: u8 = 0x80;
+const COMMAND_ERASE_CHIP: u8 = 0x10;
+const COMMAND_ERASE_SECTOR: u8 = 0x30;
+const COMMAND_PROGRAM_BYTE: u8 = 0xA0;
+const COMMAND_SWITCH_BANK: u8 = 0xB0;
+
+/// Flash chips found in cartridges, named after their maker and capacity in bytes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[allow(missing_docs)]
+pub enum FlashChip {
+    Panasonic64K,
+    Atmel64K,
+    Macronix64K,
+    Macronix128K,
+    Sanyo128K
+}
+
+impl FlashChip {
+    /// Manufacturer and device codes, as read in ID mode.
+    pub fn id(self) -> (u8, u8) {
+        match self {
+            FlashChip::Panasonic64K => (0x32, 0x1B),
+            FlashChip::Atmel64K => (0x1F, 0x3D),
+            FlashChip::Macronix64K => (0xC2, 0x1C),
+            FlashChip::Macronix128K => (0xC2, 0x09),
+            FlashChip::Sanyo128K => (0x62, 0x13)
+        }
+    }
+
+    /// Capacity in bytes, one or two 64 KiB banks.
+    pub fn size(self) -> usize {
+        match self {
+            FlashChip::Macronix128K | FlashChip::Sanyo128K => 2 * FLASH_BANK_SIZE,
+            _ => FLASH_BANK_SIZE
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum FlashState {
+    Ready,
+    // 0xAA was written at 0x5555
+    CommandStart,
+    // 0x55 followed at 0x2AAA, waiting for the command byte
+    CommandSecond,
+    // The next write is the byte to program
+    ProgramByte,
+    // Bytes left to fill the current Atmel page
+    ProgramPage(usize),
+    // The next write to 0x0000 selects the bank
+    SwitchBank
+}
+
+pub struct Flash {
+    chip: FlashChip,
+    data: Box<[u8]>,
+    state: FlashState,
+    // Erase commands need a second AA/55 sequence after the 0x80 one
+    erase_prepared: bool,
+    id_mode: bool,
+    bank: usize,
+    dirty: bool
+}
+
+impl Flash {
+    pub fn new(chip: FlashChip) -> Self {
+        Flash {
+            chip,
+            data: vec![0xFF; chip.size()].into_boxed_slice(),
+            state: FlashState::Ready,
+            erase_prepared: false,
+            id_mode: false,
+            bank: 0,
+            dirty: false
+        }
+    }
+
+    pub fn chip(&self) -> FlashChip {
+        self.chip
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn clear_dirty(&mut self) {
+        self.dirty = false;
+    }
+
+    fn offset(&self, address: usize) -> usize {
+        self.bank * FLASH_BANK_SIZE + (address & (FLASH_BANK_SIZE - 1))
+    }
+
+    pub fn read8(&self, address: usize) -> u8 {
+        let (manufacturer, device) = self.chip.id();
+
+        match address & (FLASH_BANK_SIZE - 1) {
+            0 if self.id_mode => manufacturer,
+            1 if self.id_mode => device,
+            _ => self.data[self.offset(address)]
+        }
+    }
+
+    pub fn write8(&mut self, address: usize, value: u8) {
+        let address_in_bank = address & (FLASH_BANK_SIZE - 1);
+
+        self.state = match (self.state, address_in_bank, value) {
+            (FlashState::ProgramByte, _, _) => {
+                let offset = self.offset(address);
+                self.data[offset] = value;
+                self.dirty = true;
+                FlashState::Ready
+            },
+            (FlashState::ProgramPage(bytes_left), _, _) => {
+                let offset = self.offset(address);
+                self.data[offset] = value;
+                self.dirty = true;
+                if bytes_left > 1 { FlashState::ProgramPage(bytes_left - 1) } else { FlashState::Ready }
+            },
+            (FlashState::SwitchBank, 0, _) => {
+                if self.chip.size() > FLASH_BANK_SIZE {
+                    self.bank = (value & 1) as usize;
+                }
+                FlashState::Ready
+            },
+            (FlashState::CommandStart, COMMAND_ADDRESS_2, 0x55) => FlashState::CommandSecond,
+            (FlashState::CommandSecond, _, _) => self.run_command(address_in_bank, value),
+            (_, COMMAND_ADDRESS_1, 0xAA) => FlashState::CommandStart,
+            _ => FlashState::Ready
+        };
+    }
+
+    fn run_command(&mut self, address_in_bank: usize, command: u8) -> FlashState {
+        let erase_prepared = std::mem::replace(&mut self.erase_prepared, false);
+
+        match (address_in_bank, command) {
+            (COMMAND_ADDRESS_1, COMMAND_ENTER_ID_MODE) => self.id_mode = true,
+            (COMMAND_ADDRESS_1, COMMAND_EXIT_ID_MODE) => self.id_mode = false,
+            (COMMAND_ADDRESS_1, COMMAND_PREPARE_ERASE) => self.erase_prepared = true,
+            (COMMAND_ADDRESS_1, COMMAND_ERASE_CHIP) if erase_prepared => {
+                self.data.fill(0xFF);
+                self.dirty = true;
+            },
+            // The sector to erase is selected by the address bits 12-15
+            (_, COMMAND_ERASE_SECTOR) if erase_prepared => {
+                let start = self.offset(address_in_bank & !(FLASH_SECTOR_SIZE - 1));
+                self.data[start..start + FLASH_SECTOR_SIZE].fill(0xFF);
+                self.dirty = true;
+            },
+            (COMMAND_ADDRESS_1, COMMAND_PROGRAM_BYTE) if self.chip == FlashChip::Atmel64K => {
+                return FlashState::ProgramPage(ATMEL_PAGE_SIZE);
+            },
+            (COMMAND_ADDRESS_1, COMMAND_PROGRAM_BYTE) => return FlashState::ProgramByte,
+            (COMMAND_ADDRESS_1, COMMAND_SWITCH_BANK) => return FlashState::SwitchBank,
+            _ => {}
+        }
+
+        FlashState::Ready
+    }
+}
+
+impl FlashState {
+    fn to_bits(self) -> (u8, u8) {
+        match self {
+            FlashState::Ready => (0, 0),
+            FlashState::CommandStart => (1, 0),
+            FlashState::CommandSecond => (2, 0),
+            FlashState::ProgramByte => (3, 0),
+            FlashState::ProgramPage(bytes_left) => (4, bytes_left as u8),
+            FlashState::SwitchBank => (5, 0)
+        }
+    }
+
+    fn from_bits(kind: u8, bytes_left: u8) -> Option<Self> {
+        match kind {
+            0 => Some(FlashState::Ready),
+            1 => Some(FlashState::CommandStart),
+            2 => Some(FlashState::CommandSecond),
+            3 => Some(FlashState::ProgramByte),
+            4 if (1..=ATMEL_PAGE_SIZE).contains(&(bytes_left as usize)) => Some(FlashState::ProgramPage(bytes_left as usize)),
+            5 => Some(FlashState::SwitchBank),
+            _ => None
+        }
+    }
+}
+
+impl SaveState for Flash {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.data);
+
+        let (state, bytes_left) = self.state.to_bits();
+        writer.write_u8(state);
+        writer.write_u8(bytes_left);
+        writer.write_bool(self.erase_prepared);
+        writer.write_bool(self.id_mode);
+        writer.write_usize(self.bank);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        reader.read_into(&mut self.data)?;
+
+        self.state = FlashState::from_bits(reader.read_u8()?, reader.read_u8()?).ok_or(SaveStateError::InvalidValue)?;
+        self.erase_prepared = reader.read_bool()?;
+        self.id_mode = reader.read_bool()?;
+        self.bank = reader.read_index(self.chip.size() / FLASH_BANK_SIZE)?;
+        // The save file has to follow the restored contents
+        self.dirty = true;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_command(flash: &mut Flash, command: u8) {
+        flash.write8(0x0E00_5555, 0xAA);
+        flash.write8(0x0E00_2AAA, 0x55);
+        flash.write8(0x0E00_5555, command);
+    }
+
+    #[test]
+    fn id_mode_reports_chip_codes() {
+        let mut flash = Flash::new(FlashChip::Sanyo128K);
+
+        send_command(&mut flash, COMMAND_ENTER_ID_MODE);
+        assert_eq!(flash.read8(0x0E00_0000), 0x62);
+        assert_eq!(flash.read8(0x0E00_0001), 0x13);
+
+        send_command(&mut flash, COMMAND_EXIT_ID_MODE);
+        assert_eq!(flash.read8(0x0E00_0000), 0xFF);
+    }
+
+    #[test]
+    fn bytes_are_programmed_and_sectors_erased() {
+        let mut flash = Flash::new(FlashChip::Panasonic64K);
+
+        flash.write8(0x0E00_1234, 0x00); // Ignored outside of a command
+        assert_eq!(flash.read8(0x0E00_1234), 0xFF);
+
+        send_command(&mut flash, COMMAND_PROGRAM_BYTE);
+        flash.write8(0x0E00_1234, 0x42);
+        send_command(&mut flash, COMMAND_PROGRAM_BYTE);
+        flash.write8(0x0E00_2000, 0x24);
+        assert_eq!(flash.read8(0x0E00_1234), 0x42);
+        assert!(flash.is_dirty());
+
+        send_command(&mut flash, COMMAND_PREPARE_ERASE);
+        flash.write8(0x0E00_5555, 0xAA);
+        flash.write8(0x0E00_2AAA, 0x55);
+        flash.write8(0x0E00_1000, COMMAND_ERASE_SECTOR);
+        assert_eq!(flash.read8(0x0E00_1234), 0xFF);
+        assert_eq!(flash.read8(0x0E00_2000), 0x24);
+
+        send_command(&mut flash, COMMAND_PREPARE_ERASE);
+        send_command(&mut flash, COMMAND_ERASE_CHIP);
+        assert!(flash.data().iter().all(|&byte| byte == 0xFF));
+    }
+
+    #[test]
+    fn bank_switch_selects_upper_64k() {
+        let mut flash = Flash::new(FlashChip::Macronix128K);
+
+        send_command(&mut flash, COMMAND_SWITCH_BANK);
+        flash.write8(0x0E00_0000, 1);
+        send_command(&mut flash, COMMAND_PROGRAM_BYTE);
+        flash.write8(0x0E00_0010, 0x99);
+
+        assert_eq!(flash.data()[0x10010], 0x99);
+        assert_eq!(flash.data()[0x10], 0xFF);
+    }
+
+    #[test]
+    fn atmel_programs_whole_pages() {
+        let mut flash = Flash::new(FlashChip::Atmel64K);
+
+        send_command(&mut flash, COMMAND_PROGRAM_BYTE);
+
+        for offset in 0..ATMEL_PAGE_SIZE {
+            flash.write8(0x0E00_0080 + offset, offset as u8);
+        }
+
+        assert_eq!(flash.read8(0x0E00_00FF), 0x7F);
+        assert_eq!(flash.state, FlashState::Ready);
+    }
+}
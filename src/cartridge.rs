@@ -0,0 +1,256 @@
+use crate::backup::Backup;
+use crate::gpio::Gpio;
+use crate::rtc::RtcClock;
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+use crate::sensors::TiltSensor;
+
+// Largest ROM the 32 MiB Game Pak address space can hold
+pub const MAX_ROM_SIZE: usize = 32 * 1024 * 1024;
+
+// Game code in the cartridge header, e.g. "AXVE"
+const GAME_CODE_OFFSET: usize = 0xAC;
+pub const GAME_CODE_LENGTH: usize = 4;
+
+// The EEPROM takes the whole WS2 upper half when the ROM fits in 16 MiB, only its last 256 bytes otherwise
+const EEPROM_AREA_SMALL_ROM: usize = 0x0D00_0000;
+const EEPROM_AREA_LARGE_ROM: usize = 0x0DFF_FF00;
+const EEPROM_AREA_END: usize = 0x0DFF_FFFF;
+
+// Hardware beside the ROM and the save memory
+pub const HARDWARE_RTC: u8 = 1 << 0;
+pub const HARDWARE_SOLAR_SENSOR: u8 = 1 << 1;
+pub const HARDWARE_GYRO_SENSOR: u8 = 1 << 2;
+pub const HARDWARE_RUMBLE: u8 = 1 << 3;
+pub const HARDWARE_TILT_SENSOR: u8 = 1 << 4;
+// Devices connected through the GPIO port
+const GPIO_HARDWARE: u8 = HARDWARE_RTC | HARDWARE_SOLAR_SENSOR | HARDWARE_GYRO_SENSOR | HARDWARE_RUMBLE;
+
+// Titles with extra hardware, by game code without the region letter
+const CARTRIDGE_HARDWARE: [(&[u8; 3], u8); 10] = [
+    (b"AXV", HARDWARE_RTC), // Pokémon Ruby
+    (b"AXP", HARDWARE_RTC), // Pokémon Sapphire
+    (b"BPE", HARDWARE_RTC), // Pokémon Emerald
+    (b"U3I", HARDWARE_RTC | HARDWARE_SOLAR_SENSOR), // Boktai
+    (b"U32", HARDWARE_RTC | HARDWARE_SOLAR_SENSOR), // Boktai 2
+    (b"U33", HARDWARE_RTC | HARDWARE_SOLAR_SENSOR), // Shin Bokura no Taiyou
+    (b"RZW", HARDWARE_GYRO_SENSOR | HARDWARE_RUMBLE), // WarioWare: Twisted!
+    (b"V49", HARDWARE_RUMBLE), // Drill Dozer
+    (b"KYG", HARDWARE_TILT_SENSOR), // Yoshi Topsy-Turvy
+    (b"KHP", HARDWARE_TILT_SENSOR) // Koro Koro Puzzle
+];
+
+pub fn game_code(rom: &[u8]) -> Option<&[u8]> {
+    rom.get(GAME_CODE_OFFSET..GAME_CODE_OFFSET + GAME_CODE_LENGTH)
+}
+
+/// `HARDWARE_*` flags of the cartridge with the given ROM.
+pub fn detect_hardware(rom: &[u8]) -> u8 {
+    let Some(game_code) = game_code(rom) else {
+        return 0;
+    };
+
+    CARTRIDGE_HARDWARE.iter()
+        .find(|(code, _)| game_code.starts_with(*code))
+        .map_or(0, |&(_, hardware)| hardware)
+}
+
+/// Game Pak contents: the ROM, mapped at 0x08000000 and mirrored in the WS1 and WS2 areas,
+/// and the save memory.
+pub struct Cartridge {
+    rom: Box<[u8]>,
+    pub(crate) backup: Backup,
+    pub(crate) gpio: Option<Gpio>,
+    pub(crate) tilt_sensor: Option<TiltSensor>
+}
+
+impl Cartridge {
+    pub fn new(rom: Vec<u8>, backup: Backup, rtc_clock: RtcClock) -> Self {
+        let hardware = detect_hardware(&rom);
+
+        Cartridge {
+            rom: rom.into_boxed_slice(),
+            backup,
+            gpio: ((hardware & GPIO_HARDWARE) != 0).then(|| Gpio::new(hardware, rtc_clock)),
+            tilt_sensor: ((hardware & HARDWARE_TILT_SENSOR) != 0).then(TiltSensor::new)
+        }
+    }
+
+    pub fn empty() -> Self {
+        Self::new(Vec::new(), Backup::None, RtcClock::Host)
+    }
+
+    /// The ROM itself can't be written, only the GPIO registers over it.
+    pub fn write_rom8(&mut self, address: usize, value: u8, timestamp: u64) {
+        let offset = address & (MAX_ROM_SIZE - 1);
+
+        if let Some(gpio) = self.gpio.as_mut() {
+            if Gpio::is_register(offset) {
+                gpio.write8(offset, value, timestamp);
+            }
+        }
+    }
+
+    /// The tilt sensor registers sit among the save memory ones.
+    pub fn read_backup8(&self, address: usize) -> u8 {
+        match self.tilt_sensor.as_ref() {
+            Some(sensor) if TiltSensor::is_register(address) => sensor.read8(address),
+            _ => self.backup.read8(address)
+        }
+    }
+
+    pub fn write_backup8(&mut self, address: usize, value: u8) {
+        match self.tilt_sensor.as_mut() {
+            Some(sensor) if TiltSensor::is_register(address) => sensor.write8(address, value),
+            _ => self.backup.write8(address, value)
+        }
+    }
+
+    pub fn is_eeprom_address(&self, address: usize) -> bool {
+        if !matches!(self.backup, Backup::Eeprom(_)) {
+            return false;
+        }
+
+        let start = if self.rom.len() > MAX_ROM_SIZE / 2 { EEPROM_AREA_LARGE_ROM } else { EEPROM_AREA_SMALL_ROM };
+
+        (start..=EEPROM_AREA_END).contains(&address)
+    }
+
+    pub fn read_eeprom16(&self, timestamp: u64) -> u16 {
+        match &self.backup {
+            Backup::Eeprom(eeprom) => eeprom.read16(timestamp),
+            _ => 0
+        }
+    }
+
+    pub fn write_eeprom16(&mut self, value: u16, timestamp: u64) {
+        if let Backup::Eeprom(eeprom) = &mut self.backup {
+            eeprom.write16(value, timestamp);
+        }
+    }
+
+    /// Called with the length of DMAs sending data to the EEPROM, which tells its size.
+    pub fn detect_eeprom_size(&mut self, transfer_length: u32) {
+        if let Backup::Eeprom(eeprom) = &mut self.backup {
+            eeprom.detect_size(transfer_length);
+        }
+    }
+
+    pub fn read_rom8(&self, address: usize) -> u8 {
+        let offset = address & (MAX_ROM_SIZE - 1);
+
+        if let Some(gpio) = self.gpio.as_ref() {
+            if Gpio::is_register(offset) && gpio.is_readable() {
+                return gpio.read8(offset);
+            }
+        }
+
+        match self.rom.get(offset) {
+            Some(&value) => value,
+            None => {
+                // Past the end of the ROM, the bus still holds the halfword address that was sent
+                let halfword = (offset >> 1) as u16;
+                if (offset & 1) == 0 { halfword as u8 } else { (halfword >> 8) as u8 }
+            }
+        }
+    }
+}
+
+impl SaveState for Cartridge {
+    // The ROM isn't saved, only enough of it to make sure the state is loaded with the same game
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_usize(self.rom.len());
+        writer.write_bytes(game_code(&self.rom).unwrap_or(&[0; GAME_CODE_LENGTH]));
+
+        self.backup.save_state(writer);
+
+        if let Some(gpio) = self.gpio.as_ref() {
+            gpio.save_state(writer);
+        }
+
+        if let Some(sensor) = self.tilt_sensor.as_ref() {
+            sensor.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let rom_length = reader.read_usize()?;
+        let saved_game_code = reader.read_bytes(GAME_CODE_LENGTH)?;
+
+        if rom_length != self.rom.len() || saved_game_code != game_code(&self.rom).unwrap_or(&[0; GAME_CODE_LENGTH]) {
+            return Err(SaveStateError::CartridgeMismatch);
+        }
+
+        self.backup.load_state(reader)?;
+
+        if let Some(gpio) = self.gpio.as_mut() {
+            gpio.load_state(reader)?;
+        }
+
+        if let Some(sensor) = self.tilt_sensor.as_mut() {
+            sensor.load_state(reader)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup_eeprom::Eeprom;
+
+    #[test]
+    fn reads_past_the_rom_return_the_address() {
+        let cartridge = Cartridge::new(vec![0xAA; 4], Backup::None, RtcClock::Host);
+
+        assert_eq!(cartridge.read_rom8(0x0800_0003), 0xAA);
+        assert_eq!(cartridge.read_rom8(0x0A00_0002), 0xAA);
+        assert_eq!(cartridge.read_rom8(0x0800_2468), 0x34);
+        assert_eq!(cartridge.read_rom8(0x0800_2469), 0x12);
+    }
+
+    #[test]
+    fn gpio_registers_overlay_rom_when_readable() {
+        let mut rom = vec![0xAA; 0x200];
+        rom[0xAC..0xB0].copy_from_slice(b"AXVE");
+
+        let mut cartridge = Cartridge::new(rom, Backup::None, RtcClock::Host);
+        assert!(cartridge.gpio.is_some());
+
+        cartridge.write_rom8(0x0800_00C6, 0x07, 0);
+        assert_eq!(cartridge.read_rom8(0x0800_00C6), 0xAA);
+
+        cartridge.write_rom8(0x0800_00C8, 0x01, 0);
+        assert_eq!(cartridge.read_rom8(0x0800_00C6), 0x07);
+        assert_eq!(cartridge.read_rom8(0x0800_00CA), 0xAA);
+    }
+
+    #[test]
+    fn hardware_is_detected_in_every_region() {
+        let mut rom = vec![0; 0x200];
+
+        rom[0xAC..0xB0].copy_from_slice(b"BPEJ");
+        assert_eq!(detect_hardware(&rom), HARDWARE_RTC);
+
+        rom[0xAC..0xB0].copy_from_slice(b"KYGE");
+        let cartridge = Cartridge::new(rom, Backup::None, RtcClock::Host);
+        assert!(cartridge.gpio.is_none());
+        assert!(cartridge.tilt_sensor.is_some());
+
+        assert_eq!(detect_hardware(&[0; 16]), 0);
+    }
+
+    #[test]
+    fn eeprom_area_depends_on_rom_size() {
+        let cartridge = Cartridge::new(vec![0; 1024], Backup::Eeprom(Eeprom::new(None)), RtcClock::Host);
+        assert!(cartridge.is_eeprom_address(0x0D00_0000));
+        assert!(!cartridge.is_eeprom_address(0x0CFF_FFFE));
+
+        let cartridge = Cartridge::new(vec![0; MAX_ROM_SIZE], Backup::Eeprom(Eeprom::new(None)), RtcClock::Host);
+        assert!(!cartridge.is_eeprom_address(0x0D00_0000));
+        assert!(cartridge.is_eeprom_address(0x0DFF_FF00));
+
+        let cartridge = Cartridge::new(vec![0; 1024], Backup::None, RtcClock::Host);
+        assert!(!cartridge.is_eeprom_address(0x0D00_0000));
+    }
+}
@@ -0,0 +1,405 @@
+use crate::interrupts::InterruptType;
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+use crate::scheduler::{EventType, Scheduler};
+use crate::system_memory::{MemoryOperation, SysMem};
+use crate::waitstates::{AccessType, AccessWidth};
+
+const DMA_CHANNELS: usize = 4;
+const DMA_CHANNEL_REGS_SIZE: usize = 12;
+const DMA_REGS_START: usize = 0x0B0;
+
+const DMA_STARTUP_CYCLES: u64 = 2;
+// Internal cycles spent by every transfer before it gets going
+const DMA_TRANSFER_INTERNAL_CYCLES: u32 = 2;
+// Sound FIFO transfers always move 4 words to a fixed destination
+const SOUND_DMA_WORDS: u32 = 4;
+
+const DMA_REPEAT: u16 = 1 << 9;
+const DMA_32BIT_TRANSFER: u16 = 1 << 10;
+const DMA_IRQ_ENABLE: u16 = 1 << 14;
+const DMA_ENABLE: u16 = 1 << 15;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DMAStartTiming {
+    Immediate = 0,
+    VBlank = 1,
+    HBlank = 2,
+    Special = 3
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum AddressControl {
+    Increment = 0,
+    Decrement = 1,
+    Fixed = 2,
+    IncrementReload = 3
+}
+
+impl AddressControl {
+    fn from_bits(bits: u16) -> Self {
+        match bits & 3 {
+            0 => AddressControl::Increment,
+            1 => AddressControl::Decrement,
+            2 => AddressControl::Fixed,
+            _ => AddressControl::IncrementReload
+        }
+    }
+
+    fn step(self, unit_size: u32) -> u32 {
+        match self {
+            AddressControl::Increment | AddressControl::IncrementReload => unit_size,
+            AddressControl::Decrement => unit_size.wrapping_neg(),
+            AddressControl::Fixed => 0
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct DMAChannel {
+    source: u32,
+    destination: u32,
+    word_count: u16,
+    control: u16,
+
+    // Registers latched when the channel gets enabled, updated as the transfer goes on
+    internal_source: u32,
+    internal_destination: u32,
+    internal_count: u32
+}
+
+impl DMAChannel {
+    fn new() -> Self {
+        DMAChannel {
+            source: 0,
+            destination: 0,
+            word_count: 0,
+            control: 0,
+            internal_source: 0,
+            internal_destination: 0,
+            internal_count: 0
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        (self.control & DMA_ENABLE) != 0
+    }
+
+    fn start_timing(&self) -> DMAStartTiming {
+        match (self.control >> 12) & 3 {
+            0 => DMAStartTiming::Immediate,
+            1 => DMAStartTiming::VBlank,
+            2 => DMAStartTiming::HBlank,
+            _ => DMAStartTiming::Special
+        }
+    }
+
+    fn destination_control(&self) -> AddressControl {
+        AddressControl::from_bits(self.control >> 5)
+    }
+
+    fn source_control(&self) -> AddressControl {
+        AddressControl::from_bits(self.control >> 7)
+    }
+}
+
+pub struct DMAController {
+    channels: [DMAChannel; DMA_CHANNELS]
+}
+
+impl DMAController {
+    pub fn new() -> Self {
+        DMAController {
+            channels: [DMAChannel::new(); DMA_CHANNELS]
+        }
+    }
+
+    fn source_mask(channel_index: usize) -> u32 {
+        if channel_index == 0 { 0x07FF_FFFF } else { 0x0FFF_FFFF }
+    }
+
+    fn destination_mask(channel_index: usize) -> u32 {
+        if channel_index == 3 { 0x0FFF_FFFF } else { 0x07FF_FFFF }
+    }
+
+    fn max_count(channel_index: usize) -> u32 {
+        if channel_index == 3 { 0x10000 } else { 0x4000 }
+    }
+
+    fn reload_count(channel_index: usize, word_count: u16) -> u32 {
+        let count = word_count as u32 & (Self::max_count(channel_index) - 1);
+
+        if count == 0 { Self::max_count(channel_index) } else { count }
+    }
+
+    pub fn read8(&self, offset: usize) -> u8 {
+        let channel_index = (offset - DMA_REGS_START) / DMA_CHANNEL_REGS_SIZE;
+        let channel = &self.channels[channel_index];
+
+        // Only the control register can be read back
+        match (offset - DMA_REGS_START) % DMA_CHANNEL_REGS_SIZE {
+            10 => channel.control as u8,
+            11 => (channel.control >> 8) as u8,
+            _ => 0
+        }
+    }
+
+    pub fn write8(&mut self, offset: usize, value: u8, scheduler: &mut Scheduler) {
+        let channel_index = (offset - DMA_REGS_START) / DMA_CHANNEL_REGS_SIZE;
+        let register_offset = (offset - DMA_REGS_START) % DMA_CHANNEL_REGS_SIZE;
+        let channel = &mut self.channels[channel_index];
+
+        match register_offset {
+            0..=3 => {
+                let shift = register_offset * 8;
+                channel.source = (channel.source & !(0xFF << shift)) | ((value as u32) << shift);
+            },
+            4..=7 => {
+                let shift = (register_offset - 4) * 8;
+                channel.destination = (channel.destination & !(0xFF << shift)) | ((value as u32) << shift);
+            },
+            8 => channel.word_count = (channel.word_count & 0xFF00) | value as u16,
+            9 => channel.word_count = (channel.word_count & 0x00FF) | ((value as u16) << 8),
+            10 => channel.control = (channel.control & 0xFF00) | (value as u16 & 0xE0),
+            _ => {
+                let was_enabled = channel.is_enabled();
+                channel.control = (channel.control & 0x00FF) | ((value as u16) << 8);
+
+                if !was_enabled && channel.is_enabled() {
+                    channel.internal_source = channel.source & Self::source_mask(channel_index);
+                    channel.internal_destination = channel.destination & Self::destination_mask(channel_index);
+                    channel.internal_count = Self::reload_count(channel_index, channel.word_count);
+
+                    if channel.start_timing() == DMAStartTiming::Immediate {
+                        scheduler.schedule(EventType::DmaStart(channel_index), DMA_STARTUP_CYCLES);
+                    }
+                } else if !channel.is_enabled() {
+                    scheduler.cancel(EventType::DmaStart(channel_index));
+                }
+            }
+        }
+    }
+}
+
+impl Default for DMAController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SysMem {
+    /// Starts every enabled channel waiting for `timing`, in priority order.
+    pub fn trigger_dma(&mut self, timing: DMAStartTiming) {
+        for channel_index in 0..DMA_CHANNELS {
+            let channel = &self.dma.channels[channel_index];
+
+            if channel.is_enabled() && channel.start_timing() == timing {
+                self.run_dma(channel_index);
+            }
+        }
+    }
+
+    /// DMA 1 and 2 with the special start timing feed the Direct Sound FIFO they point to.
+    pub fn trigger_sound_dma(&mut self, fifo_address: u32) {
+        for channel_index in 1..=2 {
+            let channel = &self.dma.channels[channel_index];
+
+            if channel.is_enabled() && channel.start_timing() == DMAStartTiming::Special && channel.internal_destination == fifo_address {
+                self.run_dma(channel_index);
+            }
+        }
+    }
+
+    fn is_sound_dma(channel_index: usize, channel: &DMAChannel) -> bool {
+        (channel_index == 1 || channel_index == 2) && channel.start_timing() == DMAStartTiming::Special
+    }
+
+    pub fn run_dma(&mut self, channel_index: usize) {
+        let mut channel = self.dma.channels[channel_index];
+
+        if !channel.is_enabled() {
+            return;
+        }
+
+        let is_sound_dma = Self::is_sound_dma(channel_index, &channel);
+        let is_32bit_transfer = is_sound_dma || (channel.control & DMA_32BIT_TRANSFER) != 0;
+        let unit_size: u32 = if is_32bit_transfer { 4 } else { 2 };
+        let source_step = channel.source_control().step(unit_size);
+        let destination_step = if is_sound_dma { 0 } else { channel.destination_control().step(unit_size) };
+        let count = if is_sound_dma { SOUND_DMA_WORDS } else { channel.internal_count };
+
+        // The EEPROM address width can only be told from the length of the requests sent to it
+        if channel_index == 3 && self.cartridge.is_eeprom_address(channel.internal_destination as usize) {
+            self.cartridge.detect_eeprom_size(count);
+        }
+
+        let width = if is_32bit_transfer { AccessWidth::Word } else { AccessWidth::Halfword };
+        let mut cycles = DMA_TRANSFER_INTERNAL_CYCLES;
+
+        for unit in 0..count {
+            // Only the first unit is a non-sequential access
+            let access = if unit == 0 { AccessType::NonSequential } else { AccessType::Sequential };
+            cycles += self.waitstates.data_access_cycles(channel.internal_source, width, access);
+            cycles += self.waitstates.data_access_cycles(channel.internal_destination, width, access);
+
+            if is_32bit_transfer {
+                let value = self.read32((channel.internal_source & !3) as usize);
+                self.write32((channel.internal_destination & !3) as usize, value);
+            } else {
+                let value = self.read16((channel.internal_source & !1) as usize);
+                self.write16((channel.internal_destination & !1) as usize, value);
+            }
+
+            channel.internal_source = channel.internal_source.wrapping_add(source_step);
+            channel.internal_destination = channel.internal_destination.wrapping_add(destination_step);
+        }
+
+        // The CPU is stalled while the DMA owns the bus
+        self.scheduler.advance(cycles);
+
+        if is_sound_dma {
+            // The FIFO destination and the word count are left alone
+        } else if (channel.control & DMA_REPEAT) != 0 && channel.start_timing() != DMAStartTiming::Immediate {
+            channel.internal_count = DMAController::reload_count(channel_index, channel.word_count);
+
+            if channel.destination_control() == AddressControl::IncrementReload {
+                channel.internal_destination = channel.destination & DMAController::destination_mask(channel_index);
+            }
+        } else {
+            channel.control &= !DMA_ENABLE;
+        }
+
+        if (channel.control & DMA_IRQ_ENABLE) != 0 {
+            let interrupt = match channel_index {
+                0 => InterruptType::Dma0,
+                1 => InterruptType::Dma1,
+                2 => InterruptType::Dma2,
+                _ => InterruptType::Dma3
+            };
+
+            self.interrupts.request(interrupt);
+        }
+
+        self.dma.channels[channel_index] = channel;
+    }
+}
+
+impl SaveState for DMAController {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for channel in self.channels.iter() {
+            writer.write_u32(channel.source);
+            writer.write_u32(channel.destination);
+            writer.write_u16(channel.word_count);
+            writer.write_u16(channel.control);
+            writer.write_u32(channel.internal_source);
+            writer.write_u32(channel.internal_destination);
+            writer.write_u32(channel.internal_count);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        for channel in self.channels.iter_mut() {
+            channel.source = reader.read_u32()?;
+            channel.destination = reader.read_u32()?;
+            channel.word_count = reader.read_u16()?;
+            channel.control = reader.read_u16()?;
+            channel.internal_source = reader.read_u32()?;
+            channel.internal_destination = reader.read_u32()?;
+            channel.internal_count = reader.read_u32()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::Backup;
+    use crate::backup_eeprom::Eeprom;
+    use crate::cartridge::Cartridge;
+    use crate::rtc::RtcClock;
+
+    #[test]
+    fn immediate_dma_copies_words() {
+        let mut sys_mem = SysMem::new();
+
+        for i in 0..4 {
+            sys_mem.write32(0x0200_0000 + i * 4, 0x1111_1111 * (i as u32 + 1));
+        }
+
+        sys_mem.write32(0x0400_00D4, 0x0200_0000); // DMA3SAD
+        sys_mem.write32(0x0400_00D8, 0x0300_0000); // DMA3DAD
+        sys_mem.write32(0x0400_00DC, 0x8400_0004); // 4 words, 32-bit, enabled
+
+        assert!(sys_mem.scheduler.is_scheduled(EventType::DmaStart(3)));
+        sys_mem.run_dma(3);
+
+        assert_eq!(sys_mem.read32(0x0300_0000), 0x1111_1111);
+        assert_eq!(sys_mem.read32(0x0300_000C), 0x4444_4444);
+        assert_eq!(sys_mem.read16(0x0400_00DE) & DMA_ENABLE, 0);
+    }
+
+    #[test]
+    fn eeprom_size_is_detected_from_dma_length() {
+        let mut sys_mem = SysMem::new();
+        sys_mem.cartridge = Cartridge::new(vec![0; 1024], Backup::Eeprom(Eeprom::new(None)), RtcClock::Host);
+
+        // Write request to the 512 bytes EEPROM: command, 6 address bits, 64 data bits, stop bit
+        let bits = [1, 0].into_iter()
+            .chain([0, 0, 0, 0, 0, 1])
+            .chain((0..64).map(|bit| (bit & 1) as u16))
+            .chain([0]);
+
+        for (index, bit) in bits.enumerate() {
+            sys_mem.write16(0x0200_0000 + index * 2, bit);
+        }
+
+        sys_mem.write32(0x0400_00D4, 0x0200_0000);
+        sys_mem.write32(0x0400_00D8, 0x0D00_0000);
+        sys_mem.write32(0x0400_00DC, 0x8000_0000 | 73); // 73 halfwords, enabled
+        sys_mem.run_dma(3);
+
+        assert_eq!(sys_mem.cartridge.backup.data().len(), 512);
+        assert_eq!(sys_mem.cartridge.backup.data()[8..16], [0x55; 8]);
+        assert_eq!(sys_mem.read16(0x0D00_0000), 0); // Busy
+    }
+
+    #[test]
+    fn sound_dma_refills_fifo_with_four_words() {
+        let mut sys_mem = SysMem::new();
+
+        for i in 0..8 {
+            sys_mem.write32(0x0200_0000 + i * 4, 0x0101_0101);
+        }
+
+        sys_mem.write8(0x0400_0084, 0x80); // Sound on
+        sys_mem.write32(0x0400_00BC, 0x0200_0000);
+        sys_mem.write32(0x0400_00C0, 0x0400_00A0);
+        sys_mem.write16(0x0400_00C6, 0xB600); // Enabled, special timing, repeat, 32-bit
+
+        sys_mem.advance_direct_sound(0);
+        assert_eq!(sys_mem.dma.channels[1].internal_source, 0x0200_0010);
+        assert_eq!(sys_mem.dma.channels[1].internal_destination, 0x0400_00A0);
+        assert!(sys_mem.dma.channels[1].is_enabled());
+    }
+
+    #[test]
+    fn repeating_hblank_dma_reloads_destination() {
+        let mut sys_mem = SysMem::new();
+
+        sys_mem.write16(0x0200_0000, 0xAAAA);
+        sys_mem.write16(0x0200_0002, 0xBBBB);
+
+        sys_mem.write32(0x0400_00B0, 0x0200_0000);
+        sys_mem.write32(0x0400_00B4, 0x0300_0000);
+        sys_mem.write16(0x0400_00B8, 1);
+        sys_mem.write16(0x0400_00BA, 0xA260); // Enabled, H-Blank, repeat, reload destination
+
+        sys_mem.trigger_dma(DMAStartTiming::HBlank);
+        sys_mem.trigger_dma(DMAStartTiming::HBlank);
+
+        assert_eq!(sys_mem.read16(0x0300_0000), 0xBBBB);
+        assert_eq!(sys_mem.read16(0x0300_0002), 0x0000);
+        assert_ne!(sys_mem.read16(0x0400_00BA) & DMA_ENABLE, 0);
+    }
+}
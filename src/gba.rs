@@ -1,29 +1,865 @@
 use crate::arm7tdmi::ARM7TDMI;
-use crate::system_memory::SysMem;
+use crate::audio::{self, AudioRingBuffer, AudioSamples, AudioSink, SampleFormat};
+use crate::backup::BackupType;
+use crate::keypad::Button;
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::rewind::RewindBuffer;
+use crate::rtc::RtcClock;
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+use crate::scheduler::EventType;
+use crate::screenshot::ImageFormat;
+use crate::system_memory::{SysMem, BIOS_SIZE};
+use crate::video::{self, ColorCorrection, PixelFormat, Pixels, VideoSink, FRAMEBUFFER_PIXELS};
 
 use std::boxed::Box;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 
-const CYCLES_PER_FRAME: u32 = 280_896;
+const CYCLES_PER_FRAME: u64 = 280_896;
+/// Refresh rate of the screen, about 59.73 Hz.
+pub const FRAMES_PER_SECOND: f64 = (1 << 24) as f64 / CYCLES_PER_FRAME as f64;
+// About a third of a second at 48000 Hz
+const AUDIO_BUFFER_FRAMES: usize = 16384;
+// Modified save memory is written back to its file about once per second at most
+const SAVE_FLUSH_INTERVAL_FRAMES: u32 = 60;
 
+/// The whole console: load a BIOS and a ROM, then call `run_frame` about `FRAMES_PER_SECOND`
+/// times per second.
 pub struct GBA {
     sys_mem: Box<SysMem>,
-    cpu: Box<ARM7TDMI>
+    cpu: Box<ARM7TDMI>,
+
+    video_sink: Option<Box<dyn VideoSink>>,
+    // Conversion buffer for sinks asking for RGBA8888 pixels
+    rgba_buffer: Vec<u8>,
+    // Applied to every RGB888 or RGBA8888 output
+    color_correction: ColorCorrection,
+
+    audio_sink: Option<Box<dyn AudioSink>>,
+    // Conversion buffer for sinks asking for 16-bit samples
+    i16_audio_buffer: Vec<i16>,
+    // Keeps the audio output when no sink is set
+    audio_buffer: AudioRingBuffer,
+
+    // Where the cartridge backup memory is persisted, when the ROM came from a file
+    save_path: Option<PathBuf>,
+    // Backup type used for the next ROMs instead of the detected one
+    backup_type_override: Option<BackupType>,
+    // Time source of the cartridge RTC, kept for the next ROMs
+    rtc_clock: RtcClock,
+    frames_since_save_flush: u32,
+
+    // Save states of the last frames, empty until a depth is set
+    rewind_buffer: RewindBuffer
 }
 
 impl GBA {
+    /// A powered on console, without BIOS nor cartridge.
     pub fn new() -> GBA {
-        GBA {
+        let mut gba = GBA {
             sys_mem: Box::new(SysMem::new()),
-            cpu: Box::new(ARM7TDMI::new())
-        }
+            cpu: Box::new(ARM7TDMI::new()),
+            video_sink: None,
+            rgba_buffer: vec![0; FRAMEBUFFER_PIXELS * 4],
+            color_correction: ColorCorrection::None,
+            audio_sink: None,
+            i16_audio_buffer: Vec::new(),
+            audio_buffer: AudioRingBuffer::new(AUDIO_BUFFER_FRAMES),
+            save_path: None,
+            backup_type_override: None,
+            rtc_clock: RtcClock::Host,
+            frames_since_save_flush: 0,
+            rewind_buffer: RewindBuffer::new(0)
+        };
+
+        gba.cpu.reset(&mut gba.sys_mem);
+        gba.sys_mem.scheduler.schedule(EventType::FrameEnd, CYCLES_PER_FRAME);
+
+        gba
     }
 
+    /// Runs until the end of the current frame, then hands the audio of the frame over.
     pub fn run_frame(&mut self) {
-        let mut total_cycles: u32 = 0;
+        let mut frame_finished = false;
+
+        while !frame_finished {
+            // The next event may move closer while running, e.g. when an I/O write registers a new one
+            while self.sys_mem.scheduler.timestamp() < self.sys_mem.scheduler.next_event_timestamp() {
+                if self.sys_mem.interrupts.is_cpu_halted() {
+                    // Nothing but an event can raise the interrupt that wakes up the CPU
+                    self.sys_mem.scheduler.skip_to_next_event();
+                    break;
+                }
+
+                let instruction_executed_cycles: u32 = self.cpu.run_instruction(&mut self.sys_mem);
+                self.sys_mem.scheduler.advance(instruction_executed_cycles);
+            }
+
+            while let Some((event_type, timestamp)) = self.sys_mem.scheduler.pop_pending_event() {
+                frame_finished |= self.handle_event(event_type, timestamp);
+            }
+        }
+
+        self.output_audio();
+
+        self.frames_since_save_flush += 1;
+
+        if self.frames_since_save_flush >= SAVE_FLUSH_INTERVAL_FRAMES {
+            // A failed write leaves the backup dirty, so it's tried again later
+            let _ = self.flush_save();
+        }
+
+        if self.rewind_buffer.depth() > 0 {
+            let state = self.save_state();
+            self.rewind_buffer.push(&state);
+        }
+    }
+
+    /// Sets the sink receiving every rendered line and frame, replacing the previous one.
+    pub fn set_video_sink(&mut self, sink: Box<dyn VideoSink>) {
+        self.video_sink = Some(sink);
+    }
+
+    /// Removes the video sink, giving it back to the caller.
+    pub fn take_video_sink(&mut self) -> Option<Box<dyn VideoSink>> {
+        self.video_sink.take()
+    }
+
+    /// Current picture in RGB555, 240x160 pixels row by row. Complete once `run_frame` returns.
+    pub fn framebuffer(&self) -> &[u16] {
+        self.sys_mem.ppu.framebuffer()
+    }
+
+    /// Current picture converted to RGBA8888, 4 bytes per pixel.
+    pub fn framebuffer_rgba8888(&self) -> Vec<u8> {
+        let mut pixels = vec![0; FRAMEBUFFER_PIXELS * 4];
+        video::convert_to_rgba8888(self.framebuffer(), &mut pixels, self.color_correction);
+
+        pixels
+    }
+
+    /// Current picture converted to RGB888, 3 bytes per pixel.
+    pub fn framebuffer_rgb888(&self) -> Vec<u8> {
+        let mut pixels = vec![0; FRAMEBUFFER_PIXELS * 3];
+        video::convert_to_rgb888(self.framebuffer(), &mut pixels, self.color_correction);
+
+        pixels
+    }
+
+    /// Colour correction of the RGB888 and RGBA8888 outputs, none by default.
+    /// RGB555 outputs keep the colours written by the game.
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.color_correction = correction;
+    }
 
-        while total_cycles < CYCLES_PER_FRAME {
-            let instruction_executed_cycles: u8 = self.cpu.run_instruction(&mut self.sys_mem);
-            total_cycles += instruction_executed_cycles as u32;
+    /// Writes the current picture to `path`, as a PNG or PPM file depending on its extension.
+    pub fn save_screenshot(&self, path: &Path) -> io::Result<()> {
+        let format = ImageFormat::from_path(path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "screenshots are saved as .png or .ppm files"))?;
+
+        fs::write(path, format.encode(SCREEN_WIDTH, SCREEN_HEIGHT, &self.framebuffer_rgb888()))
+    }
+
+    /// Maps a BIOS image and restarts the CPU from its reset vector. Without one, the BIOS area
+    /// reads as zeros.
+    pub fn load_bios(&mut self, bios: &[u8]) -> io::Result<()> {
+        if bios.len() != BIOS_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("BIOS has {} bytes instead of {}", bios.len(), BIOS_SIZE)));
+        }
+
+        self.sys_mem.load_bios(bios);
+        *self.cpu = ARM7TDMI::new();
+        self.cpu.reset(&mut self.sys_mem);
+
+        Ok(())
+    }
+
+    /// Reads a BIOS image from a file, see `load_bios`.
+    pub fn load_bios_file(&mut self, bios_path: &Path) -> io::Result<()> {
+        self.load_bios(&fs::read(bios_path)?)
+    }
+
+    /// Inserts a Game Pak, its ROM is mapped from 0x08000000. The save memory of the previous
+    /// one is written back to its file first, the new one has none until `load_save_file`.
+    pub fn load_rom(&mut self, rom: Vec<u8>) -> io::Result<()> {
+        self.flush_save()?;
+        self.save_path = None;
+
+        self.sys_mem.load_rom(rom, self.backup_type_override, self.rtc_clock);
+        self.rewind_buffer.clear();
+
+        Ok(())
+    }
+
+    /// Forces the save memory of the ROMs loaded from now on, `None` goes back to detecting it
+    /// from the ROM contents.
+    pub fn set_backup_type_override(&mut self, backup_type: Option<BackupType>) {
+        self.backup_type_override = backup_type;
+    }
+
+    /// Save memory of the current cartridge.
+    pub fn backup_type(&self) -> BackupType {
+        self.sys_mem.cartridge.backup.backup_type()
+    }
+
+    /// Sets where the cartridge RTC takes the time from, the host clock by default. Applies to
+    /// the current cartridge and the ones loaded afterwards, if they have an RTC.
+    pub fn set_rtc_clock(&mut self, clock: RtcClock) {
+        self.rtc_clock = clock;
+
+        if let Some(rtc) = self.sys_mem.cartridge.gpio.as_mut().and_then(|gpio| gpio.rtc_mut()) {
+            rtc.set_clock(clock);
+        }
+    }
+
+    /// Light reaching the solar sensor of Boktai cartridges, from 0 (dark) to 255 (direct sunlight).
+    pub fn set_solar_light_level(&mut self, light_level: u8) {
+        if let Some(sensor) = self.sys_mem.cartridge.gpio.as_mut().and_then(|gpio| gpio.solar_sensor_mut()) {
+            sensor.set_light_level(light_level);
+        }
+    }
+
+    /// Tilt of cartridges with an accelerometer, from -1.0 to 1.0 on both axes.
+    /// Positive x is to the right, positive y to the bottom.
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        if let Some(sensor) = self.sys_mem.cartridge.tilt_sensor.as_mut() {
+            sensor.set_tilt(x, y);
+        }
+    }
+
+    /// Rotation speed of cartridges with a gyro sensor, from -1.0 to 1.0.
+    pub fn set_gyro_rotation(&mut self, rotation: f32) {
+        if let Some(sensor) = self.sys_mem.cartridge.gpio.as_mut().and_then(|gpio| gpio.gyro_sensor_mut()) {
+            sensor.set_rotation(rotation);
         }
     }
-}
\ No newline at end of file
+
+    /// Whether the rumble motor of the cartridge is currently running.
+    pub fn is_rumbling(&self) -> bool {
+        self.sys_mem.cartridge.gpio.as_ref().is_some_and(|gpio| gpio.is_rumbling())
+    }
+
+    /// Loads a ROM file, along with its save memory from the .sav file next to it when there is one.
+    pub fn load_rom_file(&mut self, rom_path: &Path) -> io::Result<()> {
+        self.load_rom(fs::read(rom_path)?)?;
+        self.load_save_file(&rom_path.with_extension("sav"))
+    }
+
+    /// Loads the save memory of the current ROM from `save_path` when the file exists, and writes
+    /// it back there from now on.
+    pub fn load_save_file(&mut self, save_path: &Path) -> io::Result<()> {
+        if save_path.exists() {
+            self.sys_mem.cartridge.backup.load(&fs::read(save_path)?);
+        }
+
+        self.save_path = Some(save_path.to_path_buf());
+
+        Ok(())
+    }
+
+    /// File the save memory is written to, if any.
+    pub fn save_path(&self) -> Option<&Path> {
+        self.save_path.as_deref()
+    }
+
+    /// Writes the save memory to its .sav file if it was modified since the last flush.
+    pub fn flush_save(&mut self) -> io::Result<()> {
+        self.frames_since_save_flush = 0;
+
+        let backup = &mut self.sys_mem.cartridge.backup;
+
+        if let Some(save_path) = self.save_path.as_ref() {
+            if backup.is_dirty() {
+                fs::write(save_path, backup.data())?;
+                backup.clear_dirty();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Snapshot of the whole machine, to be given back to `load_state` with the same ROM loaded.
+    /// Sinks, buffered audio output and host settings such as the audio sample rate aren't part of it.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+
+        self.cpu.save_state(&mut writer);
+        self.sys_mem.save_state(&mut writer);
+
+        writer.finish()
+    }
+
+    /// Restores a snapshot taken by `save_state`. On error, the machine is left as it was.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(state)?;
+        let previous_state = self.save_state();
+
+        let result = self.cpu.load_state(&mut reader)
+            .and_then(|_| self.sys_mem.load_state(&mut reader))
+            .and_then(|_| if reader.is_at_end() { Ok(()) } else { Err(SaveStateError::InvalidFormat) });
+
+        if result.is_err() {
+            // Can't fail, it was just saved
+            let mut reader = StateReader::new(&previous_state).unwrap();
+            let _ = self.cpu.load_state(&mut reader);
+            let _ = self.sys_mem.load_state(&mut reader);
+        }
+
+        result
+    }
+
+    /// Keeps the state of the last `frames` frames for `rewind`, 0 (the default) disables it.
+    /// Snapshots are stored as deltas against a keyframe taken once per second.
+    pub fn set_rewind_depth(&mut self, frames: usize) {
+        self.rewind_buffer = RewindBuffer::new(frames);
+    }
+
+    /// Goes back to the state at the end of the frame run `frames` frames ago, as far as the
+    /// rewind depth allows. Returns the number of frames actually rewound.
+    pub fn rewind(&mut self, frames: usize) -> Result<usize, SaveStateError> {
+        match self.rewind_buffer.rewind(frames) {
+            Some((state, frames)) => self.load_state(&state).map(|_| frames),
+            None => Ok(0)
+        }
+    }
+
+    /// Sample rate of the audio output, 48000 Hz by default. A rate of 0 is rejected.
+    pub fn set_audio_sample_rate(&mut self, sample_rate: u32) -> io::Result<()> {
+        if sample_rate == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "audio sample rate can't be 0"));
+        }
+
+        self.sys_mem.apu.set_output_rate(sample_rate);
+
+        Ok(())
+    }
+
+    /// Sample rate the audio output is currently resampled to.
+    pub fn audio_sample_rate(&self) -> u32 {
+        self.sys_mem.apu.output_rate()
+    }
+
+    /// Runs `frames` frames and writes their audio output to a 16-bit stereo WAV file at `path`.
+    /// Audio queued before isn't part of the recording, and the audio sink gets nothing meanwhile.
+    pub fn record_wav(&mut self, path: &Path, frames: u32) -> io::Result<()> {
+        let audio_sink = self.audio_sink.take();
+        self.audio_buffer.clear();
+
+        let mut samples = Vec::new();
+
+        for _ in 0..frames {
+            self.run_frame();
+            self.append_audio_samples(&mut samples);
+        }
+
+        self.audio_sink = audio_sink;
+
+        fs::write(path, audio::encode_wav(self.audio_sample_rate(), &samples))
+    }
+
+    /// Sets the sink receiving the audio output after every frame, instead of the internal ring buffer.
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink>) {
+        self.audio_sink = Some(sink);
+    }
+
+    /// Removes the audio sink, the output goes back to the internal ring buffer.
+    pub fn take_audio_sink(&mut self) -> Option<Box<dyn AudioSink>> {
+        self.audio_sink.take()
+    }
+
+    /// Pops interleaved stereo samples (left first) from the internal ring buffer.
+    /// Returns the number of samples written to `output`, always a whole number of frames.
+    pub fn read_audio_samples(&mut self, output: &mut [i16]) -> usize {
+        self.audio_buffer.pop_i16(output)
+    }
+
+    /// Pops every queued sample from the internal ring buffer, appending them to `output`.
+    pub fn append_audio_samples(&mut self, output: &mut Vec<i16>) {
+        let start = output.len();
+        output.resize(start + self.queued_audio_samples(), 0);
+
+        let sample_count = self.read_audio_samples(&mut output[start..]);
+        output.truncate(start + sample_count);
+    }
+
+    /// Same as `read_audio_samples`, with samples between -1.0 and 1.0.
+    pub fn read_audio_samples_f32(&mut self, output: &mut [f32]) -> usize {
+        self.audio_buffer.pop_f32(output)
+    }
+
+    /// Number of samples waiting in the internal ring buffer, both channels counted.
+    pub fn queued_audio_samples(&self) -> usize {
+        self.audio_buffer.queued_samples()
+    }
+
+    /// Presses or releases a button, it stays that way until the next call.
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.sys_mem.keypad.set_button(button, pressed);
+        // Keypad interrupts can also wake the CPU up from Stop mode
+        self.sys_mem.update_keypad_interrupt();
+    }
+
+    /// True while the button is held down.
+    pub fn is_button_pressed(&self, button: Button) -> bool {
+        self.sys_mem.keypad.is_pressed(button)
+    }
+
+    /// Dispatches a due event to its component. Returns true when the event ends the current frame.
+    fn handle_event(&mut self, event_type: EventType, timestamp: u64) -> bool {
+        match event_type {
+            EventType::FrameEnd => {
+                self.sys_mem.scheduler.schedule_at(EventType::FrameEnd, timestamp + CYCLES_PER_FRAME);
+                true
+            },
+            EventType::HBlankStart => {
+                self.sys_mem.start_hblank(timestamp);
+
+                if self.sys_mem.ppu.is_in_vdraw() {
+                    self.output_scanline(self.sys_mem.ppu.vcount() as usize);
+                }
+
+                false
+            },
+            EventType::HDrawStart => {
+                self.sys_mem.start_hdraw(timestamp);
+
+                if self.sys_mem.ppu.vcount() as usize == SCREEN_HEIGHT {
+                    self.output_frame();
+                }
+
+                false
+            },
+            EventType::DmaStart(channel_index) => {
+                self.sys_mem.run_dma(channel_index);
+                false
+            },
+            EventType::TimerOverflow(timer_index) => {
+                self.sys_mem.handle_timer_overflow(timer_index, timestamp);
+                false
+            },
+            EventType::ApuSample => {
+                self.sys_mem.generate_audio_sample(timestamp);
+                false
+            },
+            EventType::ApuFrameSequencer => {
+                self.sys_mem.step_frame_sequencer(timestamp);
+                false
+            }
+        }
+    }
+
+    fn output_scanline(&mut self, line: usize) {
+        if let Some(sink) = self.video_sink.as_mut() {
+            let pixels = &self.sys_mem.ppu.framebuffer()[line * SCREEN_WIDTH..(line + 1) * SCREEN_WIDTH];
+
+            match sink.pixel_format() {
+                PixelFormat::Rgb555 => sink.scanline_finished(line, Pixels::Rgb555(pixels)),
+                PixelFormat::Rgba8888 => {
+                    let rgba_line = &mut self.rgba_buffer[..SCREEN_WIDTH * 4];
+                    video::convert_to_rgba8888(pixels, rgba_line, self.color_correction);
+                    sink.scanline_finished(line, Pixels::Rgba8888(rgba_line));
+                }
+            }
+        }
+    }
+
+    fn output_audio(&mut self) {
+        let samples = self.sys_mem.apu.samples();
+
+        match self.audio_sink.as_mut() {
+            Some(sink) => match sink.sample_format() {
+                SampleFormat::F32 => sink.push_samples(AudioSamples::F32(samples)),
+                SampleFormat::I16 => {
+                    self.i16_audio_buffer.clear();
+                    self.i16_audio_buffer.extend(samples.iter().map(|&sample| audio::f32_to_i16(sample)));
+                    sink.push_samples(AudioSamples::I16(&self.i16_audio_buffer));
+                }
+            },
+            None => self.audio_buffer.push(samples)
+        }
+
+        self.sys_mem.apu.clear_samples();
+    }
+
+    fn output_frame(&mut self) {
+        if let Some(sink) = self.video_sink.as_mut() {
+            let pixels = self.sys_mem.ppu.framebuffer();
+
+            match sink.pixel_format() {
+                PixelFormat::Rgb555 => sink.frame_finished(Pixels::Rgb555(pixels)),
+                PixelFormat::Rgba8888 => {
+                    video::convert_to_rgba8888(pixels, &mut self.rgba_buffer, self.color_correction);
+                    sink.frame_finished(Pixels::Rgba8888(&self.rgba_buffer));
+                }
+            }
+        }
+    }
+}
+
+impl Default for GBA {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for GBA {
+    fn drop(&mut self) {
+        // Nothing can be done about a failed write at this point
+        let _ = self.flush_save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup_flash::FlashChip;
+    use crate::system_memory::MemoryOperation;
+
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn run_frame_stops_at_frame_boundary() {
+        let mut gba = GBA::new();
+
+        gba.run_frame();
+        assert_eq!(gba.sys_mem.scheduler.timestamp(), CYCLES_PER_FRAME);
+
+        gba.run_frame();
+        assert_eq!(gba.sys_mem.scheduler.timestamp(), 2 * CYCLES_PER_FRAME);
+    }
+
+    struct CountingSink {
+        lines: Rc<Cell<usize>>,
+        frames: Rc<Cell<usize>>
+    }
+
+    impl VideoSink for CountingSink {
+        fn pixel_format(&self) -> PixelFormat {
+            PixelFormat::Rgba8888
+        }
+
+        fn scanline_finished(&mut self, line: usize, _pixels: Pixels) {
+            assert_eq!(line, self.lines.get() % SCREEN_HEIGHT);
+            self.lines.set(self.lines.get() + 1);
+        }
+
+        fn frame_finished(&mut self, pixels: Pixels) {
+            // Forced blank shows a white screen
+            assert!(matches!(pixels, Pixels::Rgba8888(pixels) if pixels.iter().all(|&byte| byte == 0xFF)));
+            self.frames.set(self.frames.get() + 1);
+        }
+    }
+
+    #[test]
+    fn video_sink_receives_lines_and_frames() {
+        let mut gba = GBA::new();
+        let lines = Rc::new(Cell::new(0));
+        let frames = Rc::new(Cell::new(0));
+
+        gba.set_video_sink(Box::new(CountingSink { lines: lines.clone(), frames: frames.clone() }));
+        gba.run_frame();
+        gba.run_frame();
+
+        assert_eq!(lines.get(), 2 * SCREEN_HEIGHT);
+        assert_eq!(frames.get(), 2);
+        assert_eq!(gba.framebuffer_rgba8888().len(), SCREEN_WIDTH * SCREEN_HEIGHT * 4);
+    }
+
+    #[test]
+    fn screenshots_use_the_colour_correction() {
+        let mut gba = GBA::new();
+        gba.sys_mem.write16(0x0400_0000, 0x0403); // Mode 3, BG2
+        gba.sys_mem.write16(0x0600_0000, 0x001F);
+        gba.run_frame();
+
+        let path = std::env::temp_dir().join(format!("fba_screenshot_test_{}.ppm", std::process::id()));
+        gba.save_screenshot(&path).unwrap();
+        let image = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let header_length = b"P6\n240 160\n255\n".len();
+        assert_eq!(image.len(), header_length + FRAMEBUFFER_PIXELS * 3);
+        assert_eq!(image[header_length..header_length + 3], [0xFF, 0, 0]);
+
+        gba.set_color_correction(ColorCorrection::Lcd);
+        assert_eq!(gba.framebuffer_rgb888()[..3], video::rgb555_to_rgb888(0x001F, ColorCorrection::Lcd));
+
+        assert!(gba.save_screenshot(Path::new("frame.bmp")).is_err());
+    }
+
+    #[test]
+    fn audio_is_buffered_at_the_output_rate() {
+        let mut gba = GBA::new();
+
+        assert_eq!(gba.set_audio_sample_rate(0).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(gba.audio_sample_rate(), 48000);
+
+        gba.set_audio_sample_rate(44100).unwrap();
+        gba.run_frame();
+
+        // 280896 cycles at 16.78 MHz last about 16.74 ms
+        let frames = gba.queued_audio_samples() / 2;
+        assert!((737..=740).contains(&frames));
+
+        let mut output = vec![1i16; 2 * frames];
+        assert_eq!(gba.read_audio_samples(&mut output), 2 * frames);
+        assert!(output.iter().all(|&sample| sample == 0));
+        assert_eq!(gba.queued_audio_samples(), 0);
+    }
+
+    #[test]
+    fn wav_recording_holds_the_frames_audio() {
+        let mut gba = GBA::new();
+        start_test_scene(&mut gba);
+        gba.set_audio_sample_rate(32768).unwrap();
+        gba.run_frame();
+
+        let path = std::env::temp_dir().join(format!("fba_wav_test_{}.wav", std::process::id()));
+        gba.record_wav(&path, 10).unwrap();
+        let wav = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(wav[24..28], 32768u32.to_le_bytes());
+
+        // 10 frames of 280896 cycles at 512 cycles per sample
+        let data_length = u32::from_le_bytes(wav[40..44].try_into().unwrap()) as usize;
+        assert_eq!(data_length, wav.len() - 44);
+        assert!((data_length / 4).abs_diff(5486) <= 2);
+        assert!(wav[44..].iter().any(|&byte| byte != 0));
+        assert_eq!(gba.queued_audio_samples(), 0);
+    }
+
+    #[test]
+    fn sram_is_persisted_next_to_the_rom() {
+        let directory = std::env::temp_dir().join(format!("fba_sram_test_{}", std::process::id()));
+        let rom_path = directory.join("game.gba");
+
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(&rom_path, [0u8; 0x200]).unwrap();
+        fs::write(directory.join("game.sav"), [0x12, 0x34]).unwrap();
+
+        let mut gba = GBA::new();
+        gba.load_rom_file(&rom_path).unwrap();
+        assert_eq!(gba.sys_mem.read16(0x0E00_0001), 0x3434);
+
+        gba.sys_mem.write32(0x0E00_0002, 0xAABB_CCDD); // Only the 0xBB byte lane is stored
+        gba.flush_save().unwrap();
+
+        let save = fs::read(directory.join("game.sav")).unwrap();
+        assert_eq!(save.len(), 32 * 1024);
+        assert_eq!(save[..3], [0x12, 0x34, 0xBB]);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn switching_roms_flushes_and_forgets_the_previous_save() {
+        let directory = std::env::temp_dir().join(format!("fba_switch_test_{}", std::process::id()));
+        let rom_path = directory.join("a.gba");
+
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(&rom_path, [0u8; 0x200]).unwrap();
+
+        let mut gba = GBA::new();
+        gba.load_rom_file(&rom_path).unwrap();
+        gba.sys_mem.write8(0x0E00_0000, 0xA1);
+
+        // Written before the cartridge goes away, not left for the next periodic flush
+        gba.load_rom(vec![0u8; 0x200]).unwrap();
+        assert_eq!(gba.save_path(), None);
+        assert_eq!(fs::read(directory.join("a.sav")).unwrap()[0], 0xA1);
+
+        gba.sys_mem.write8(0x0E00_0000, 0xB2);
+        gba.flush_save().unwrap();
+        drop(gba);
+
+        assert_eq!(fs::read(directory.join("a.sav")).unwrap()[0], 0xA1);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn bios_is_mapped_at_address_0() {
+        let mut gba = GBA::new();
+        assert!(gba.load_bios(&[0; 0x1000]).is_err());
+
+        let mut bios = vec![0u8; BIOS_SIZE];
+        bios[..4].copy_from_slice(&0xEA00_0018u32.to_le_bytes());
+        bios[BIOS_SIZE - 1] = 0x5A;
+        gba.load_bios(&bios).unwrap();
+
+        assert_eq!(gba.sys_mem.read32(0), 0xEA00_0018);
+        assert_eq!(gba.sys_mem.read8(BIOS_SIZE - 1), 0x5A);
+        assert_eq!(gba.sys_mem.read8(BIOS_SIZE), 0);
+    }
+
+    #[test]
+    fn backup_type_can_be_overridden() {
+        let mut rom = vec![0u8; 0x200];
+        rom[0x100..0x108].copy_from_slice(b"FLASH_V1");
+
+        let mut gba = GBA::new();
+        gba.load_rom(rom.clone()).unwrap();
+        assert_eq!(gba.backup_type(), BackupType::Flash(FlashChip::Panasonic64K));
+
+        gba.set_backup_type_override(Some(BackupType::None));
+        gba.load_rom(rom).unwrap();
+        assert_eq!(gba.backup_type(), BackupType::None);
+    }
+
+    #[test]
+    fn tilt_sensor_is_read_through_the_sram_area() {
+        let mut rom = vec![0u8; 0x200];
+        rom[0xAC..0xB0].copy_from_slice(b"KYGE");
+
+        let mut gba = GBA::new();
+        gba.load_rom(rom).unwrap();
+        gba.set_tilt(0.5, 0.0);
+
+        gba.sys_mem.write8(0x0E00_8000, 0x55);
+        gba.sys_mem.write8(0x0E00_8100, 0xAA);
+
+        let x = gba.sys_mem.read8(0x0E00_8200) as u16 | ((gba.sys_mem.read8(0x0E00_8300) as u16 & 0xF) << 8);
+        assert_eq!(x, 0x4A0);
+        assert!(!gba.is_rumbling());
+    }
+
+    #[test]
+    fn rtc_clock_set_before_loading_applies_to_the_rom() {
+        let mut rom = vec![0u8; 0x200];
+        rom[0xAC..0xB0].copy_from_slice(b"AXVE");
+
+        // 2004-06-15 13:45:30 UTC, a Tuesday. Hours past noon carry the PM flag (0x80)
+        let mut gba = GBA::new();
+        gba.set_rtc_clock(RtcClock::Fixed(1_087_307_130));
+        gba.load_rom(rom).unwrap();
+
+        let rtc = gba.sys_mem.cartridge.gpio.as_mut().and_then(|gpio| gpio.rtc_mut()).unwrap();
+        assert_eq!(rtc.date_time(0), [0x04, 0x06, 0x15, 0x02, 0x13 | 0x80, 0x45, 0x30]);
+    }
+
+    // Keeps the machine busy with something else than the nop-only CPU: a picture, sound and timers
+    fn start_test_scene(gba: &mut GBA) {
+        let mut rom = vec![0u8; 0x400];
+        rom[0x100..0x108].copy_from_slice(b"FLASH_V1");
+        gba.load_rom(rom).unwrap();
+
+        gba.sys_mem.write16(0x0400_0000, 0x0403); // Mode 3, BG2
+        for i in 0..240 * 160 {
+            gba.sys_mem.write16(0x0600_0000 + i * 2, (i * 7) as u16);
+        }
+
+        gba.sys_mem.write8(0x0400_0084, 0x80); // Sound on
+        gba.sys_mem.write16(0x0400_0080, 0xFF77); // Every PSG channel on both sides at full volume
+        gba.sys_mem.write16(0x0400_0062, 0xF080); // Square 1: 50% duty, volume 15
+        gba.sys_mem.write16(0x0400_0064, 0x8700); // Square 1: trigger
+        gba.sys_mem.write16(0x0400_0102, 0x00C1); // Timer 0: 1/64, IRQ, on
+    }
+
+    #[test]
+    fn save_state_restores_identical_emulation() {
+        let mut gba = GBA::new();
+        start_test_scene(&mut gba);
+        gba.run_frame();
+
+        let state = gba.save_state();
+
+        let run = |gba: &mut GBA| {
+            let mut audio = vec![0i16; 8192];
+            let mut audio_samples = 0;
+
+            for _ in 0..3 {
+                gba.run_frame();
+                audio_samples += gba.read_audio_samples(&mut audio[audio_samples..]);
+            }
+
+            audio.truncate(audio_samples);
+            (gba.framebuffer().to_vec(), audio, gba.save_state())
+        };
+
+        gba.read_audio_samples(&mut [0; 8192]);
+        let expected = run(&mut gba);
+
+        let mut restored = GBA::new();
+        start_test_scene(&mut restored);
+        restored.load_state(&state).unwrap();
+        restored.read_audio_samples(&mut [0; 8192]);
+
+        let result = run(&mut restored);
+        assert!(expected.1.iter().any(|&sample| sample != 0));
+        assert!(result == expected);
+    }
+
+    #[test]
+    fn save_states_keep_the_audio_sample_rate() {
+        let mut gba = GBA::new();
+        start_test_scene(&mut gba);
+        gba.run_frame();
+        let state = gba.save_state();
+
+        let mut restored = GBA::new();
+        start_test_scene(&mut restored);
+        restored.set_audio_sample_rate(22050).unwrap();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.audio_sample_rate(), 22050);
+
+        restored.read_audio_samples(&mut [0; 8192]);
+        restored.run_frame();
+        assert!(restored.queued_audio_samples().abs_diff(2 * 369) <= 4);
+    }
+
+    #[test]
+    fn failed_state_loads_leave_the_machine_alone() {
+        let mut gba = GBA::new();
+        start_test_scene(&mut gba);
+        let state = gba.save_state();
+
+        assert_eq!(gba.load_state(&state[..state.len() - 1]), Err(SaveStateError::Truncated));
+        assert!(gba.save_state() == state);
+
+        let mut other_game = GBA::new();
+        other_game.load_rom(vec![0; 0x400]).unwrap();
+        assert_eq!(other_game.load_state(&state), Err(SaveStateError::CartridgeMismatch));
+    }
+
+    #[test]
+    fn rewind_goes_back_to_previous_frames() {
+        let mut gba = GBA::new();
+        start_test_scene(&mut gba);
+        gba.set_rewind_depth(10);
+
+        let mut states = Vec::new();
+        for _ in 0..20 {
+            gba.run_frame();
+            states.push(gba.save_state());
+        }
+
+        assert_eq!(gba.rewind(3), Ok(3));
+        assert!(gba.save_state() == states[16]);
+
+        // Emulation goes on from there, the same way as the first time
+        gba.run_frame();
+        assert!(gba.save_state() == states[17]);
+
+        assert_eq!(gba.rewind(100), Ok(7));
+        assert!(gba.save_state() == states[10]);
+    }
+
+    #[test]
+    fn keypad_interrupt_wakes_up_from_stop() {
+        let mut gba = GBA::new();
+
+        gba.sys_mem.write16(0x0400_0200, 1 << 12); // IE: keypad
+        gba.sys_mem.write16(0x0400_0132, 0x4001); // KEYCNT: IRQ on A
+        gba.sys_mem.write8(0x0400_0301, 0x80); // HALTCNT: stop
+
+        gba.run_frame();
+        assert!(gba.sys_mem.interrupts.is_cpu_halted());
+
+        gba.set_button(Button::A, true);
+        assert!(!gba.sys_mem.interrupts.is_cpu_halted());
+        assert_eq!(gba.sys_mem.read16(0x0400_0130), 0x03FE);
+    }
+}
@@ -0,0 +1,192 @@
+use crate::cartridge::{HARDWARE_GYRO_SENSOR, HARDWARE_RTC, HARDWARE_RUMBLE, HARDWARE_SOLAR_SENSOR};
+use crate::rtc::{Rtc, RtcClock};
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+use crate::sensors::{GyroSensor, SolarSensor};
+
+// Registers, as offsets in the Game Pak ROM
+const GPIO_DATA: usize = 0xC4;
+const GPIO_DIRECTION: usize = 0xC6;
+const GPIO_CONTROL: usize = 0xC8;
+
+const GPIO_PINS_MASK: u8 = 0xF;
+const GPIO_CONTROL_READ_ENABLE: u8 = 1 << 0;
+
+const RUMBLE_PIN: u8 = 1 << 3;
+
+/// 4-bit general purpose port of some cartridges, mapped over the ROM at 0x080000C4-0x080000C9.
+/// It connects the CPU to the extra hardware of the cartridge.
+pub struct Gpio {
+    // Levels written by the CPU, only driven on the pins set as outputs
+    data: u8,
+    // Bit set when the pin is an output of the GBA
+    direction: u8,
+    control: u8,
+
+    rtc: Option<Rtc>,
+    solar_sensor: Option<SolarSensor>,
+    gyro_sensor: Option<GyroSensor>,
+    // None without a motor, otherwise whether it's running
+    rumble: Option<bool>
+}
+
+impl Gpio {
+    /// Port with the devices among `hardware`, a set of `HARDWARE_*` flags. The RTC, if any,
+    /// takes the time from `rtc_clock`.
+    pub fn new(hardware: u8, rtc_clock: RtcClock) -> Self {
+        Gpio {
+            data: 0,
+            direction: 0,
+            control: 0,
+            rtc: ((hardware & HARDWARE_RTC) != 0).then(|| Rtc::new(rtc_clock)),
+            solar_sensor: ((hardware & HARDWARE_SOLAR_SENSOR) != 0).then(SolarSensor::new),
+            gyro_sensor: ((hardware & HARDWARE_GYRO_SENSOR) != 0).then(GyroSensor::new),
+            rumble: ((hardware & HARDWARE_RUMBLE) != 0).then_some(false)
+        }
+    }
+
+    pub fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+
+    pub fn solar_sensor_mut(&mut self) -> Option<&mut SolarSensor> {
+        self.solar_sensor.as_mut()
+    }
+
+    pub fn gyro_sensor_mut(&mut self) -> Option<&mut GyroSensor> {
+        self.gyro_sensor.as_mut()
+    }
+
+    pub fn is_rumbling(&self) -> bool {
+        self.rumble == Some(true)
+    }
+
+    pub fn is_register(offset: usize) -> bool {
+        (GPIO_DATA..=GPIO_CONTROL + 1).contains(&offset)
+    }
+
+    /// Without the read enable bit, the registers read as the ROM they cover.
+    pub fn is_readable(&self) -> bool {
+        (self.control & GPIO_CONTROL_READ_ENABLE) != 0
+    }
+
+    fn input_pins(&self) -> u8 {
+        self.rtc.as_ref().map_or(0, |rtc| rtc.read_pins())
+            | self.solar_sensor.as_ref().map_or(0, |sensor| sensor.read_pins())
+            | self.gyro_sensor.as_ref().map_or(0, |sensor| sensor.read_pins())
+    }
+
+    pub fn read8(&self, offset: usize) -> u8 {
+        match offset {
+            GPIO_DATA => ((self.data & self.direction) | (self.input_pins() & !self.direction)) & GPIO_PINS_MASK,
+            GPIO_DIRECTION => self.direction,
+            GPIO_CONTROL => self.control,
+            _ => 0
+        }
+    }
+
+    pub fn write8(&mut self, offset: usize, value: u8, timestamp: u64) {
+        match offset {
+            GPIO_DATA => self.data = value & GPIO_PINS_MASK,
+            GPIO_DIRECTION => self.direction = value & GPIO_PINS_MASK,
+            GPIO_CONTROL => self.control = value & GPIO_CONTROL_READ_ENABLE,
+            _ => return
+        }
+
+        let output_pins = self.data & self.direction;
+
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.write_pins(output_pins, timestamp);
+        }
+
+        if let Some(sensor) = self.solar_sensor.as_mut() {
+            sensor.write_pins(output_pins);
+        }
+
+        if let Some(sensor) = self.gyro_sensor.as_mut() {
+            sensor.write_pins(output_pins);
+        }
+
+        if let Some(rumble) = self.rumble.as_mut() {
+            *rumble = (output_pins & RUMBLE_PIN) != 0;
+        }
+    }
+}
+
+impl SaveState for Gpio {
+    // The devices present depend on the game, they were already checked along with the cartridge
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.data);
+        writer.write_u8(self.direction);
+        writer.write_u8(self.control);
+
+        if let Some(rtc) = self.rtc.as_ref() {
+            rtc.save_state(writer);
+        }
+
+        if let Some(sensor) = self.solar_sensor.as_ref() {
+            sensor.save_state(writer);
+        }
+
+        if let Some(sensor) = self.gyro_sensor.as_ref() {
+            sensor.save_state(writer);
+        }
+
+        if let Some(rumble) = self.rumble {
+            writer.write_bool(rumble);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.data = reader.read_u8()? & GPIO_PINS_MASK;
+        self.direction = reader.read_u8()? & GPIO_PINS_MASK;
+        self.control = reader.read_u8()? & GPIO_CONTROL_READ_ENABLE;
+
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.load_state(reader)?;
+        }
+
+        if let Some(sensor) = self.solar_sensor.as_mut() {
+            sensor.load_state(reader)?;
+        }
+
+        if let Some(sensor) = self.gyro_sensor.as_mut() {
+            sensor.load_state(reader)?;
+        }
+
+        if let Some(rumble) = self.rumble.as_mut() {
+            *rumble = reader.read_bool()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_mixes_outputs_and_inputs() {
+        let mut gpio = Gpio::new(0, RtcClock::Host);
+
+        gpio.write8(GPIO_DIRECTION, 0x05, 0);
+        gpio.write8(GPIO_DATA, 0xFF, 0);
+        gpio.write8(GPIO_CONTROL, 0xFF, 0);
+
+        assert!(gpio.is_readable());
+        assert_eq!(gpio.read8(GPIO_DATA), 0x05);
+        assert_eq!(gpio.read8(GPIO_CONTROL), 1);
+    }
+
+    #[test]
+    fn rumble_follows_pin_3() {
+        let mut gpio = Gpio::new(HARDWARE_RUMBLE, RtcClock::Host);
+
+        gpio.write8(GPIO_DIRECTION, 0x08, 0);
+        gpio.write8(GPIO_DATA, 0x08, 0);
+        assert!(gpio.is_rumbling());
+
+        gpio.write8(GPIO_DATA, 0x00, 0);
+        assert!(!gpio.is_rumbling());
+    }
+}
@@ -0,0 +1,180 @@
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InterruptType {
+    VBlank = 0,
+    HBlank = 1,
+    VCounterMatch = 2,
+    Timer0 = 3,
+    Timer1 = 4,
+    Timer2 = 5,
+    Timer3 = 6,
+    Serial = 7,
+    Dma0 = 8,
+    Dma1 = 9,
+    Dma2 = 10,
+    Dma3 = 11,
+    Keypad = 12,
+    GamePak = 13
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PowerState {
+    Running,
+    Halted,
+    Stopped
+}
+
+// Only these sources keep being checked while the system clock is stopped
+const STOP_WAKE_UP_INTERRUPTS_MASK: u16 =
+    (1 << InterruptType::Serial as u16) | (1 << InterruptType::Keypad as u16) | (1 << InterruptType::GamePak as u16);
+
+/// IE/IF/IME registers plus the power state selected through HALTCNT.
+pub struct InterruptController {
+    interrupt_enable: u16,
+    interrupt_flags: u16,
+    master_enable: bool,
+    post_boot_flag: u8,
+    power_state: PowerState
+}
+
+impl InterruptController {
+    pub fn new() -> Self {
+        InterruptController {
+            interrupt_enable: 0,
+            interrupt_flags: 0,
+            master_enable: false,
+            post_boot_flag: 0,
+            power_state: PowerState::Running
+        }
+    }
+
+    pub fn request(&mut self, interrupt: InterruptType) {
+        self.interrupt_flags |= 1 << interrupt as u16;
+        self.update_power_state();
+    }
+
+    /// True when the CPU should take the IRQ exception (still subject to the CPSR I bit).
+    pub fn irq_line(&self) -> bool {
+        self.master_enable && (self.interrupt_enable & self.interrupt_flags) != 0
+    }
+
+    #[cfg(test)]
+    pub fn power_state(&self) -> PowerState {
+        self.power_state
+    }
+
+    pub fn is_cpu_halted(&self) -> bool {
+        self.power_state != PowerState::Running
+    }
+
+    fn update_power_state(&mut self) {
+        let pending_interrupts = self.interrupt_enable & self.interrupt_flags;
+
+        // Waking up doesn't depend on IME, only on an enabled interrupt being requested
+        match self.power_state {
+            PowerState::Halted if pending_interrupts != 0 => self.power_state = PowerState::Running,
+            PowerState::Stopped if (pending_interrupts & STOP_WAKE_UP_INTERRUPTS_MASK) != 0 => self.power_state = PowerState::Running,
+            _ => {}
+        }
+    }
+
+    pub fn read8(&self, offset: usize) -> u8 {
+        match offset {
+            0x200 => self.interrupt_enable as u8,
+            0x201 => (self.interrupt_enable >> 8) as u8,
+            0x202 => self.interrupt_flags as u8,
+            0x203 => (self.interrupt_flags >> 8) as u8,
+            0x208 => self.master_enable as u8,
+            0x300 => self.post_boot_flag,
+            _ => 0
+        }
+    }
+
+    pub fn write8(&mut self, offset: usize, value: u8) {
+        match offset {
+            0x200 => self.interrupt_enable = (self.interrupt_enable & 0xFF00) | value as u16,
+            0x201 => self.interrupt_enable = (self.interrupt_enable & 0x00FF) | ((value as u16 & 0x3F) << 8),
+            // Writing 1 to an IF bit acknowledges the interrupt
+            0x202 => self.interrupt_flags &= !(value as u16),
+            0x203 => self.interrupt_flags &= !((value as u16) << 8),
+            0x208 => self.master_enable = (value & 1) != 0,
+            0x300 => self.post_boot_flag = value & 1,
+            0x301 => {
+                self.power_state = if (value & 0x80) != 0 { PowerState::Stopped } else { PowerState::Halted };
+            },
+            _ => {}
+        }
+
+        self.update_power_state();
+    }
+}
+
+impl Default for InterruptController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SaveState for InterruptController {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.interrupt_enable);
+        writer.write_u16(self.interrupt_flags);
+        writer.write_bool(self.master_enable);
+        writer.write_u8(self.post_boot_flag);
+        writer.write_u8(self.power_state as u8);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.interrupt_enable = reader.read_u16()?;
+        self.interrupt_flags = reader.read_u16()?;
+        self.master_enable = reader.read_bool()?;
+        self.post_boot_flag = reader.read_u8()?;
+        self.power_state = match reader.read_u8()? {
+            0 => PowerState::Running,
+            1 => PowerState::Halted,
+            2 => PowerState::Stopped,
+            _ => return Err(SaveStateError::InvalidValue)
+        };
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn irq_line_needs_ime_ie_and_if() {
+        let mut interrupts = InterruptController::new();
+
+        interrupts.request(InterruptType::VBlank);
+        assert!(!interrupts.irq_line());
+
+        interrupts.write8(0x200, 1);
+        assert!(!interrupts.irq_line());
+
+        interrupts.write8(0x208, 1);
+        assert!(interrupts.irq_line());
+
+        interrupts.write8(0x202, 1);
+        assert!(!interrupts.irq_line());
+    }
+
+    #[test]
+    fn stop_only_wakes_up_on_keypad_serial_or_gamepak() {
+        let mut interrupts = InterruptController::new();
+
+        interrupts.write8(0x200, 0xFF);
+        interrupts.write8(0x201, 0x3F);
+        interrupts.write8(0x301, 0x80);
+        assert_eq!(interrupts.power_state(), PowerState::Stopped);
+
+        interrupts.request(InterruptType::VBlank);
+        assert_eq!(interrupts.power_state(), PowerState::Stopped);
+
+        interrupts.request(InterruptType::Keypad);
+        assert_eq!(interrupts.power_state(), PowerState::Running);
+    }
+}
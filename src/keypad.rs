@@ -0,0 +1,139 @@
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+
+/// Buttons of the console, numbered after their bit in KEYINPUT.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[allow(missing_docs)]
+pub enum Button {
+    A = 0,
+    B = 1,
+    Select = 2,
+    Start = 3,
+    Right = 4,
+    Left = 5,
+    Up = 6,
+    Down = 7,
+    R = 8,
+    L = 9
+}
+
+const BUTTONS_MASK: u16 = 0x03FF;
+
+const KEYCNT_IRQ_ENABLE: u16 = 1 << 14;
+const KEYCNT_IRQ_CONDITION_AND: u16 = 1 << 15;
+
+/// KEYINPUT/KEYCNT registers. KEYINPUT is active-low, a cleared bit means the button is pressed.
+pub struct Keypad {
+    key_input: u16,
+    key_control: u16
+}
+
+impl Keypad {
+    pub fn new() -> Self {
+        Keypad {
+            key_input: BUTTONS_MASK,
+            key_control: 0
+        }
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if pressed {
+            self.key_input &= !(1 << button as u16);
+        } else {
+            self.key_input |= 1 << button as u16;
+        }
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        (self.key_input & (1 << button as u16)) == 0
+    }
+
+    /// Checks the KEYCNT interrupt condition against the buttons currently held.
+    pub fn irq_condition_met(&self) -> bool {
+        if (self.key_control & KEYCNT_IRQ_ENABLE) == 0 {
+            return false;
+        }
+
+        let selected_buttons = self.key_control & BUTTONS_MASK;
+        let pressed_buttons = !self.key_input & BUTTONS_MASK;
+
+        if (self.key_control & KEYCNT_IRQ_CONDITION_AND) != 0 {
+            selected_buttons != 0 && (pressed_buttons & selected_buttons) == selected_buttons
+        } else {
+            (pressed_buttons & selected_buttons) != 0
+        }
+    }
+
+    pub fn read8(&self, offset: usize) -> u8 {
+        match offset {
+            0x130 => self.key_input as u8,
+            0x131 => (self.key_input >> 8) as u8,
+            0x132 => self.key_control as u8,
+            0x133 => (self.key_control >> 8) as u8,
+            _ => 0
+        }
+    }
+
+    pub fn write8(&mut self, offset: usize, value: u8) {
+        match offset {
+            0x132 => self.key_control = (self.key_control & 0xFF00) | value as u16,
+            0x133 => self.key_control = (self.key_control & 0x00FF) | ((value as u16 & 0xC3) << 8),
+            _ => {} // KEYINPUT is read-only
+        }
+    }
+}
+
+impl Default for Keypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SaveState for Keypad {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.key_input);
+        writer.write_u16(self.key_control);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.key_input = reader.read_u16()?;
+        self.key_control = reader.read_u16()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyinput_is_active_low() {
+        let mut keypad = Keypad::new();
+
+        keypad.set_button(Button::A, true);
+        keypad.set_button(Button::L, true);
+        assert_eq!(keypad.read8(0x130), 0xFE);
+        assert_eq!(keypad.read8(0x131), 0x01);
+
+        keypad.set_button(Button::A, false);
+        assert_eq!(keypad.read8(0x130), 0xFF);
+    }
+
+    #[test]
+    fn keycnt_and_or_conditions_work() {
+        let mut keypad = Keypad::new();
+
+        // A + B selected, OR condition
+        keypad.write8(0x132, 0x03);
+        keypad.write8(0x133, 0x40);
+        keypad.set_button(Button::B, true);
+        assert!(keypad.irq_condition_met());
+
+        // Same selection, AND condition
+        keypad.write8(0x133, 0xC0);
+        assert!(!keypad.irq_condition_met());
+
+        keypad.set_button(Button::A, true);
+        assert!(keypad.irq_condition_met());
+    }
+}
@@ -0,0 +1,70 @@
+//! Game Boy Advance emulator core.
+//!
+//! [`GBA`] is the whole console: load a BIOS and a ROM into it, then call [`GBA::run_frame`]
+//! once per frame. The picture is read back with [`GBA::framebuffer`] or pushed to a
+//! [`VideoSink`], the sound with [`GBA::read_audio_samples`] or an [`AudioSink`], and the buttons
+//! are set with [`GBA::set_button`].
+//!
+//! ```no_run
+//! use festboy_advanced::{Button, GBA};
+//! use std::path::Path;
+//!
+//! let mut gba = GBA::new();
+//! gba.load_rom_file(Path::new("game.gba")).expect("can't load the ROM");
+//!
+//! gba.set_button(Button::Start, true);
+//! for _ in 0..60 {
+//!     gba.run_frame();
+//! }
+//!
+//! let pixels = gba.framebuffer_rgba8888();
+//! ```
+//!
+//! The items exported here make up the whole API, the emulated hardware pieces stay internal.
+
+#![warn(missing_docs)]
+
+mod gba;
+mod system_memory;
+mod arm7tdmi;
+mod arm_instructions;
+mod thumb_instructions;
+mod scheduler;
+mod interrupts;
+mod keypad;
+mod ppu;
+mod ppu_bitmap_modes;
+mod ppu_tiled_modes;
+mod ppu_sprites;
+mod ppu_windows;
+mod ppu_effects;
+mod video;
+mod screenshot;
+mod dma;
+mod apu;
+mod apu_channels;
+mod resampler;
+mod audio;
+mod timers;
+mod waitstates;
+mod cartridge;
+mod gpio;
+mod rtc;
+mod sensors;
+mod backup;
+mod backup_eeprom;
+mod backup_flash;
+mod save_state;
+mod rewind;
+
+pub use crate::audio::{encode_wav, AudioSamples, AudioSink, SampleFormat};
+pub use crate::backup::BackupType;
+pub use crate::backup_eeprom::EepromSize;
+pub use crate::backup_flash::FlashChip;
+pub use crate::gba::{GBA, FRAMES_PER_SECOND};
+pub use crate::keypad::Button;
+pub use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+pub use crate::rtc::RtcClock;
+pub use crate::save_state::{SaveStateError, SAVE_STATE_VERSION};
+pub use crate::screenshot::ImageFormat;
+pub use crate::video::{ColorCorrection, PixelFormat, Pixels, VideoSink};
@@ -1,12 +1,221 @@
-pub mod gba;
-pub mod system_memory;
-pub mod arm7tdmi;
-pub mod arm_instructions;
-pub mod thumb_instructions;
-
-fn main() {
-    println!("Hello, world!");
-
-    // let _cpu: arm7tdmi::ARM7TDMI = arm7tdmi::ARM7TDMI::new();
-    let gba: gba::GBA = gba::GBA::new();
-}
\ No newline at end of file
+// Command-line front-end, see `--help`
+
+use festboy_advanced::{encode_wav, ColorCorrection, ImageFormat, GBA, FRAMES_PER_SECOND};
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "\
+Usage: FestBoyAdvanced-Rusty [OPTIONS] <ROM>
+
+Options:
+    --bios <PATH>         BIOS image to boot from (16 KiB)
+    --save <PATH>         Save memory file, defaults to the .sav file next to the ROM
+    --frames <COUNT>      Stops after this many frames
+    --headless            Runs as fast as possible instead of at the GBA refresh rate
+    --dump-frame <PATH>   Writes the last frame as raw RGB555 pixels, 240x160 little-endian words
+    --dump-audio <PATH>   Writes the audio output as raw 16-bit little-endian stereo samples at 48000 Hz
+    --record-wav <PATH>   Records the audio output as a 16-bit stereo WAV file
+    --screenshot <PATH>   Saves the last frame as a .png or .ppm image
+    --color-correction <none|lcd>
+                          Colours of the screenshot, lcd mimics the GBA screen (default: none)
+    -h, --help            Prints this help";
+
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    rom_path: PathBuf,
+    bios_path: Option<PathBuf>,
+    save_path: Option<PathBuf>,
+    frames: Option<u64>,
+    headless: bool,
+    frame_dump_path: Option<PathBuf>,
+    audio_dump_path: Option<PathBuf>,
+    wav_path: Option<PathBuf>,
+    screenshot_path: Option<PathBuf>,
+    color_correction: ColorCorrection
+}
+
+/// Parses the arguments following the program name. `Ok(None)` means help was asked for.
+fn parse_options(arguments: impl IntoIterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut arguments = arguments.into_iter();
+    let mut options = Options::default();
+    let mut rom_path = None;
+
+    while let Some(argument) = arguments.next() {
+        let mut value = |name: &str| arguments.next().ok_or_else(|| format!("{} needs a value", name));
+
+        match argument.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--bios" => options.bios_path = Some(value("--bios")?.into()),
+            "--save" => options.save_path = Some(value("--save")?.into()),
+            "--frames" => {
+                let frames = value("--frames")?;
+                options.frames = Some(frames.parse().map_err(|_| format!("invalid frame count: {}", frames))?);
+            },
+            "--headless" => options.headless = true,
+            "--dump-frame" => options.frame_dump_path = Some(value("--dump-frame")?.into()),
+            "--dump-audio" => options.audio_dump_path = Some(value("--dump-audio")?.into()),
+            "--record-wav" => options.wav_path = Some(value("--record-wav")?.into()),
+            "--screenshot" => {
+                let path = PathBuf::from(value("--screenshot")?);
+                if ImageFormat::from_path(&path).is_none() {
+                    return Err(format!("screenshots are saved as .png or .ppm files: {}", path.display()));
+                }
+                options.screenshot_path = Some(path);
+            },
+            "--color-correction" => {
+                options.color_correction = match value("--color-correction")?.as_str() {
+                    "none" => ColorCorrection::None,
+                    "lcd" => ColorCorrection::Lcd,
+                    correction => return Err(format!("unknown colour correction: {}", correction))
+                };
+            },
+            _ if argument.starts_with('-') => return Err(format!("unknown option: {}", argument)),
+            _ if rom_path.is_none() => rom_path = Some(PathBuf::from(argument)),
+            _ => return Err(format!("unexpected argument: {}", argument))
+        }
+    }
+
+    options.rom_path = rom_path.ok_or("missing ROM path")?;
+
+    // Files are written once the last frame has run, which never happens without a frame count
+    let needs_frames = [
+        ("--headless", options.headless),
+        ("--dump-frame", options.frame_dump_path.is_some()),
+        ("--dump-audio", options.audio_dump_path.is_some()),
+        ("--record-wav", options.wav_path.is_some()),
+        ("--screenshot", options.screenshot_path.is_some())
+    ];
+
+    if let Some((option, _)) = needs_frames.iter().find(|(_, set)| *set).filter(|_| options.frames.is_none()) {
+        return Err(format!("{} needs --frames", option));
+    }
+
+    Ok(Some(options))
+}
+
+/// Loads the BIOS, ROM and save memory. Every error is reported with the file at fault.
+fn load(gba: &mut GBA, options: &Options) -> Result<(), String> {
+    if let Some(bios_path) = options.bios_path.as_ref() {
+        gba.load_bios_file(bios_path).map_err(|error| format!("can't load BIOS {}: {}", bios_path.display(), error))?;
+    }
+
+    let rom = fs::read(&options.rom_path).map_err(|error| format!("can't load ROM {}: {}", options.rom_path.display(), error))?;
+    gba.load_rom(rom).map_err(|error| format!("can't load ROM {}: {}", options.rom_path.display(), error))?;
+
+    let save_path = options.save_path.clone().unwrap_or_else(|| options.rom_path.with_extension("sav"));
+    gba.load_save_file(&save_path).map_err(|error| format!("can't load save {}: {}", save_path.display(), error))
+}
+
+fn run(gba: &mut GBA, options: &Options) -> Result<(), String> {
+    let frame_duration = Duration::from_secs_f64(1.0 / FRAMES_PER_SECOND);
+    let mut next_frame_time = Instant::now();
+
+    // Shared by the raw and WAV outputs
+    let record_audio = options.audio_dump_path.is_some() || options.wav_path.is_some();
+    let mut audio = Vec::new();
+    let mut frame = 0;
+
+    while options.frames.is_none_or(|frames| frame < frames) {
+        gba.run_frame();
+        frame += 1;
+
+        if record_audio {
+            gba.append_audio_samples(&mut audio);
+        }
+
+        if !options.headless {
+            next_frame_time += frame_duration;
+            thread::sleep(next_frame_time.saturating_duration_since(Instant::now()));
+        }
+    }
+
+    if let Some(path) = options.frame_dump_path.as_ref() {
+        let pixels: Vec<u8> = gba.framebuffer().iter().flat_map(|pixel| pixel.to_le_bytes()).collect();
+        fs::write(path, pixels).map_err(|error| format!("can't write frame {}: {}", path.display(), error))?;
+    }
+
+    if let Some(path) = options.screenshot_path.as_ref() {
+        gba.set_color_correction(options.color_correction);
+        gba.save_screenshot(path).map_err(|error| format!("can't write screenshot {}: {}", path.display(), error))?;
+    }
+
+    if let Some(path) = options.audio_dump_path.as_ref() {
+        let samples: Vec<u8> = audio.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        fs::write(path, samples).map_err(|error| format!("can't write audio {}: {}", path.display(), error))?;
+    }
+
+    if let Some(path) = options.wav_path.as_ref() {
+        fs::write(path, encode_wav(gba.audio_sample_rate(), &audio)).map_err(|error| format!("can't write audio {}: {}", path.display(), error))?;
+    }
+
+    gba.flush_save().map_err(|error| format!("can't write save: {}", error))
+}
+
+fn main() -> ExitCode {
+    let options = match parse_options(env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        },
+        Err(error) => {
+            eprintln!("error: {}\n\n{}", error, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let mut gba = GBA::new();
+
+    match load(&mut gba, &options).and_then(|_| run(&mut gba, &options)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(arguments: &[&str]) -> Result<Option<Options>, String> {
+        parse_options(arguments.iter().map(|argument| argument.to_string()))
+    }
+
+    #[test]
+    fn options_are_parsed_in_any_order() {
+        let options = parse(&["--frames", "120", "game.gba", "--headless", "--bios", "gba_bios.bin"]).unwrap().unwrap();
+
+        assert_eq!(options, Options {
+            rom_path: "game.gba".into(),
+            bios_path: Some("gba_bios.bin".into()),
+            frames: Some(120),
+            headless: true,
+            ..Options::default()
+        });
+
+        assert_eq!(parse(&["--help"]), Ok(None));
+    }
+
+    #[test]
+    fn invalid_arguments_are_reported() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["game.gba", "--frames"]).is_err());
+        assert!(parse(&["game.gba", "--frames", "many"]).is_err());
+        assert!(parse(&["game.gba", "--headless"]).is_err());
+        assert!(parse(&["game.gba", "--dump-frame", "frame.raw"]).is_err());
+        assert!(parse(&["game.gba", "--dump-audio", "audio.raw"]).is_err());
+        assert!(parse(&["game.gba", "--record-wav", "audio.wav"]).is_err());
+        assert!(parse(&["game.gba", "--fullscreen"]).is_err());
+        assert!(parse(&["game.gba", "other.gba"]).is_err());
+        assert!(parse(&["game.gba", "--screenshot", "frame.jpg"]).is_err());
+        assert!(parse(&["game.gba", "--screenshot", "frame.png"]).is_err());
+        assert!(parse(&["game.gba", "--color-correction", "sepia"]).is_err());
+    }
+}
@@ -0,0 +1,526 @@
+use crate::interrupts::{InterruptController, InterruptType};
+use crate::ppu_effects::{LayerPixel, LAYER_BACKDROP, LAYER_OBJ};
+use crate::ppu_sprites::ObjPixel;
+use crate::ppu_tiled_modes::AffineBackground;
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+
+/// Width of the screen in pixels.
+pub const SCREEN_WIDTH: usize = 240;
+/// Height of the screen in pixels.
+pub const SCREEN_HEIGHT: usize = 160;
+
+// Colours are 15-bit BGR555, so bit 15 is free to flag pixels where a layer is not drawn
+pub const TRANSPARENT_PIXEL: u16 = 0x8000;
+const WHITE_COLOR: u16 = 0x7FFF;
+
+pub const HDRAW_CYCLES: u64 = 960;
+pub const HBLANK_CYCLES: u64 = 272;
+pub const TOTAL_SCANLINES: u16 = 228;
+
+const VBLANK_FLAG: u16 = 1 << 0;
+const HBLANK_FLAG: u16 = 1 << 1;
+const VCOUNTER_FLAG: u16 = 1 << 2;
+const VBLANK_IRQ_ENABLE: u16 = 1 << 3;
+const HBLANK_IRQ_ENABLE: u16 = 1 << 4;
+const VCOUNTER_IRQ_ENABLE: u16 = 1 << 5;
+const DISPSTAT_WRITABLE_MASK: u16 = 0xFF38;
+
+const DISPCNT_FRAME_SELECT: u16 = 1 << 4;
+const DISPCNT_FORCED_BLANK: u16 = 1 << 7;
+const DISPCNT_OBJ_ENABLE: u16 = 1 << 12;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PPUPhase {
+    HDraw,
+    HBlank
+}
+
+#[allow(clippy::upper_case_acronyms)]
+pub struct PPU {
+    pub(crate) display_control: u16,
+    green_swap: u16,
+    display_status: u16,
+    vcount: u16,
+    phase: PPUPhase,
+
+    pub(crate) bg_control: [u16; 4],
+    pub(crate) bg_hofs: [u16; 4],
+    pub(crate) bg_vofs: [u16; 4],
+    pub(crate) bg_affine: [AffineBackground; 2],
+    pub(crate) mosaic: u16,
+    pub(crate) window_horizontal: [u16; 2],
+    pub(crate) window_vertical: [u16; 2],
+    pub(crate) window_inside: u16,
+    pub(crate) window_outside: u16,
+    pub(crate) blend_control: u16,
+    pub(crate) blend_alpha: u16,
+    pub(crate) blend_brightness: u16,
+
+    pub(crate) bg_lines: [[u16; SCREEN_WIDTH]; 4],
+    pub(crate) obj_line: [ObjPixel; SCREEN_WIDTH],
+    pub(crate) obj_window_line: [bool; SCREEN_WIDTH],
+    pub(crate) window_line: [u8; SCREEN_WIDTH],
+    framebuffer: Box<[u16]>
+}
+
+impl PPU {
+    pub fn new() -> Self {
+        PPU {
+            display_control: 0x0080, // Forced blank until the BIOS/game sets it up
+            green_swap: 0,
+            display_status: 0,
+            vcount: 0,
+            phase: PPUPhase::HDraw,
+            bg_control: [0; 4],
+            bg_hofs: [0; 4],
+            bg_vofs: [0; 4],
+            bg_affine: [AffineBackground::new(); 2],
+            mosaic: 0,
+            window_horizontal: [0; 2],
+            window_vertical: [0; 2],
+            window_inside: 0,
+            window_outside: 0,
+            blend_control: 0,
+            blend_alpha: 0,
+            blend_brightness: 0,
+            bg_lines: [[TRANSPARENT_PIXEL; SCREEN_WIDTH]; 4],
+            obj_line: [ObjPixel::TRANSPARENT; SCREEN_WIDTH],
+            obj_window_line: [false; SCREEN_WIDTH],
+            window_line: [0; SCREEN_WIDTH],
+            framebuffer: vec![WHITE_COLOR; SCREEN_WIDTH * SCREEN_HEIGHT].into_boxed_slice()
+        }
+    }
+
+    /// Last rendered frame as 240x160 BGR555 colours.
+    pub fn framebuffer(&self) -> &[u16] {
+        &self.framebuffer
+    }
+
+    pub fn bg_mode(&self) -> u16 {
+        self.display_control & 7
+    }
+
+    pub fn display_frame_select(&self) -> bool {
+        (self.display_control & DISPCNT_FRAME_SELECT) != 0
+    }
+
+    pub fn is_forced_blank(&self) -> bool {
+        (self.display_control & DISPCNT_FORCED_BLANK) != 0
+    }
+
+    pub fn is_bg_enabled(&self, bg: usize) -> bool {
+        (self.display_control & (1 << (8 + bg))) != 0
+    }
+
+    pub fn is_obj_enabled(&self) -> bool {
+        (self.display_control & DISPCNT_OBJ_ENABLE) != 0
+    }
+
+    pub fn bg_priority(&self, bg: usize) -> u16 {
+        self.bg_control[bg] & 3
+    }
+
+    pub fn palette_color(pal_ram: &[u8], index: usize) -> u16 {
+        (pal_ram[index * 2] as u16 | ((pal_ram[index * 2 + 1] as u16) << 8)) & 0x7FFF
+    }
+
+    pub fn vram_read16(vram: &[u8], address: usize) -> u16 {
+        vram[address] as u16 | ((vram[address + 1] as u16) << 8)
+    }
+
+    /// Renders the current line into the framebuffer, from the VRAM, palette and OAM contents.
+    pub fn render_scanline(&mut self, vram: &[u8], pal_ram: &[u8], oam: &[u8]) {
+        let line = self.vcount as usize;
+
+        if self.is_forced_blank() {
+            self.framebuffer[line * SCREEN_WIDTH..(line + 1) * SCREEN_WIDTH].fill(WHITE_COLOR);
+            return;
+        }
+
+        for bg_line in self.bg_lines.iter_mut() {
+            bg_line.fill(TRANSPARENT_PIXEL);
+        }
+
+        self.obj_line.fill(ObjPixel::TRANSPARENT);
+        self.obj_window_line.fill(false);
+
+        // Text and affine backgrounds available on each mode
+        let (text_bgs, affine_bgs) = match self.bg_mode() {
+            0 => (0..4, 0..0),
+            1 => (0..2, 2..3),
+            2 => (0..0, 2..4),
+            _ => (0..0, 0..0)
+        };
+
+        for bg in text_bgs {
+            if self.is_bg_enabled(bg) {
+                self.render_text_bg(bg, vram, pal_ram);
+            }
+        }
+
+        for bg in affine_bgs {
+            if self.is_bg_enabled(bg) {
+                self.render_affine_bg(bg, vram, pal_ram);
+            }
+        }
+
+        if (3..=5).contains(&self.bg_mode()) && self.is_bg_enabled(2) {
+            self.render_bitmap_bg2(vram, pal_ram);
+        }
+
+        for bg in 0..4 {
+            if self.is_bg_enabled(bg) {
+                self.apply_bg_horizontal_mosaic(bg);
+            }
+        }
+
+        if self.is_obj_enabled() {
+            self.render_sprites(vram, pal_ram, oam);
+        }
+
+        self.compute_window_masks();
+        self.compose_scanline(pal_ram);
+    }
+
+    fn compose_scanline(&mut self, pal_ram: &[u8]) {
+        let line = self.vcount as usize;
+        let backdrop_color = Self::palette_color(pal_ram, 0);
+
+        // Lower priority value goes on top, BGs with the same priority are ordered by number
+        let mut bgs_by_priority: Vec<usize> = (0..4).filter(|&bg| self.is_bg_enabled(bg)).collect();
+        bgs_by_priority.sort_by_key(|&bg| (self.bg_priority(bg), bg));
+
+        for x in 0..SCREEN_WIDTH {
+            let backdrop = LayerPixel { color: backdrop_color, layer: LAYER_BACKDROP, priority: 4 };
+            let mut visible_bgs = bgs_by_priority.iter()
+                .filter(|&&bg| self.is_bg_visible_in_window(bg, x) && self.bg_lines[bg][x] != TRANSPARENT_PIXEL)
+                .map(|&bg| LayerPixel { color: self.bg_lines[bg][x], layer: bg, priority: self.bg_priority(bg) });
+
+            // The two topmost layers are kept, the second one being the one colour effects blend with
+            let mut top = visible_bgs.next().unwrap_or(backdrop);
+            let mut second = visible_bgs.next().unwrap_or(backdrop);
+
+            // OBJs go on top of BGs with the same priority
+            let obj_pixel = self.obj_line[x];
+            let obj_visible = obj_pixel.color != TRANSPARENT_PIXEL && self.is_obj_visible_in_window(x);
+            let obj_layer = LayerPixel { color: obj_pixel.color, layer: LAYER_OBJ, priority: obj_pixel.priority };
+
+            if obj_visible && obj_pixel.priority <= top.priority {
+                second = top;
+                top = obj_layer;
+            } else if obj_visible && obj_pixel.priority <= second.priority {
+                second = obj_layer;
+            }
+
+            self.framebuffer[line * SCREEN_WIDTH + x] = if self.are_effects_enabled_in_window(x) {
+                let is_semi_transparent_obj = top.layer == LAYER_OBJ && obj_pixel.semi_transparent;
+                self.apply_color_effects(top, second, is_semi_transparent_obj)
+            } else {
+                top.color
+            };
+        }
+    }
+
+    pub fn vcount(&self) -> u16 {
+        self.vcount
+    }
+
+    pub fn is_in_vdraw(&self) -> bool {
+        (self.vcount as usize) < SCREEN_HEIGHT
+    }
+
+    pub fn start_hblank(&mut self, interrupts: &mut InterruptController) {
+        self.phase = PPUPhase::HBlank;
+        self.display_status |= HBLANK_FLAG;
+
+        // Unlike H-Blank DMA, the H-Blank interrupt is also requested during V-Blank
+        if (self.display_status & HBLANK_IRQ_ENABLE) != 0 {
+            interrupts.request(InterruptType::HBlank);
+        }
+    }
+
+    pub fn start_hdraw(&mut self, interrupts: &mut InterruptController) {
+        self.phase = PPUPhase::HDraw;
+        self.display_status &= !HBLANK_FLAG;
+
+        if self.is_in_vdraw() {
+            for affine in self.bg_affine.iter_mut() {
+                affine.finish_scanline();
+            }
+        }
+
+        self.vcount = (self.vcount + 1) % TOTAL_SCANLINES;
+
+        match self.vcount as usize {
+            SCREEN_HEIGHT => {
+                self.display_status |= VBLANK_FLAG;
+
+                for affine in self.bg_affine.iter_mut() {
+                    affine.latch_reference_x();
+                    affine.latch_reference_y();
+                }
+
+                if (self.display_status & VBLANK_IRQ_ENABLE) != 0 {
+                    interrupts.request(InterruptType::VBlank);
+                }
+            },
+            // The V-Blank flag is already cleared on the last line
+            227 => self.display_status &= !VBLANK_FLAG,
+            _ => {}
+        }
+
+        self.check_vcount_match(interrupts);
+    }
+
+    fn check_vcount_match(&mut self, interrupts: &mut InterruptController) {
+        let vcount_setting = self.display_status >> 8;
+
+        if self.vcount == vcount_setting {
+            self.display_status |= VCOUNTER_FLAG;
+
+            if (self.display_status & VCOUNTER_IRQ_ENABLE) != 0 {
+                interrupts.request(InterruptType::VCounterMatch);
+            }
+        } else {
+            self.display_status &= !VCOUNTER_FLAG;
+        }
+    }
+
+    pub fn read8(&self, offset: usize) -> u8 {
+        match offset {
+            0x000 => self.display_control as u8,
+            0x001 => (self.display_control >> 8) as u8,
+            0x002 => self.green_swap as u8,
+            0x003 => (self.green_swap >> 8) as u8,
+            0x004 => self.display_status as u8,
+            0x005 => (self.display_status >> 8) as u8,
+            0x006 => self.vcount as u8,
+            0x008..=0x00F => {
+                let bg_control = self.bg_control[(offset - 0x008) / 2];
+                (bg_control >> ((offset & 1) * 8)) as u8
+            },
+            0x048 => self.window_inside as u8,
+            0x049 => (self.window_inside >> 8) as u8,
+            0x04A => self.window_outside as u8,
+            0x04B => (self.window_outside >> 8) as u8,
+            0x050 => self.blend_control as u8,
+            0x051 => (self.blend_control >> 8) as u8,
+            0x052 => self.blend_alpha as u8,
+            0x053 => (self.blend_alpha >> 8) as u8,
+            _ => 0 // Scrolling, affine and window dimensions registers are write-only
+        }
+    }
+
+    pub fn write8(&mut self, offset: usize, value: u8) {
+        match offset {
+            0x000 => self.display_control = (self.display_control & 0xFF00) | value as u16,
+            0x001 => self.display_control = (self.display_control & 0x00FF) | ((value as u16) << 8),
+            0x002 => self.green_swap = (self.green_swap & 0xFF00) | value as u16,
+            0x003 => self.green_swap = (self.green_swap & 0x00FF) | ((value as u16) << 8),
+            0x004 => {
+                let writable_mask = DISPSTAT_WRITABLE_MASK & 0x00FF;
+                self.display_status = (self.display_status & !writable_mask) | (value as u16 & writable_mask);
+            },
+            0x005 => self.display_status = (self.display_status & 0x00FF) | ((value as u16) << 8),
+            0x008..=0x00F => {
+                let bg = (offset - 0x008) / 2;
+                // The wraparound bit only exists on the affine capable BG2/BG3
+                let writable_mask = if bg >= 2 { 0xFFFF } else { 0xDFFF };
+                Self::write_register_byte(&mut self.bg_control[bg], offset, value, writable_mask);
+            },
+            0x010..=0x01F => {
+                let bg = (offset - 0x010) / 4;
+
+                if (offset & 2) == 0 {
+                    Self::write_register_byte(&mut self.bg_hofs[bg], offset, value, 0x01FF);
+                } else {
+                    Self::write_register_byte(&mut self.bg_vofs[bg], offset, value, 0x01FF);
+                }
+            },
+            0x020..=0x03F => self.write_affine_register(offset, value),
+            0x040..=0x043 => Self::write_register_byte(&mut self.window_horizontal[(offset - 0x040) / 2], offset, value, 0xFFFF),
+            0x044..=0x047 => Self::write_register_byte(&mut self.window_vertical[(offset - 0x044) / 2], offset, value, 0xFFFF),
+            0x048..=0x049 => Self::write_register_byte(&mut self.window_inside, offset, value, 0x3F3F),
+            0x04A..=0x04B => Self::write_register_byte(&mut self.window_outside, offset, value, 0x3F3F),
+            0x04C..=0x04D => Self::write_register_byte(&mut self.mosaic, offset, value, 0xFFFF),
+            0x050..=0x051 => Self::write_register_byte(&mut self.blend_control, offset, value, 0x3FFF),
+            0x052..=0x053 => Self::write_register_byte(&mut self.blend_alpha, offset, value, 0x1F1F),
+            0x054..=0x055 => Self::write_register_byte(&mut self.blend_brightness, offset, value, 0x001F),
+            _ => {} // VCOUNT is read-only
+        }
+    }
+
+    fn write_register_byte(register: &mut u16, offset: usize, value: u8, writable_mask: u16) {
+        let shift = (offset & 1) * 8;
+        let byte_mask = 0xFF << shift;
+
+        *register = (*register & !byte_mask) | (((value as u16) << shift) & byte_mask & writable_mask);
+    }
+
+    fn write_affine_register(&mut self, offset: usize, value: u8) {
+        let affine = &mut self.bg_affine[(offset - 0x020) / 0x10];
+        let register_offset = (offset - 0x020) % 0x10;
+
+        let write_parameter = |parameter: &mut i16| {
+            let mut raw = *parameter as u16;
+            Self::write_register_byte(&mut raw, offset, value, 0xFFFF);
+            *parameter = raw as i16;
+        };
+
+        match register_offset {
+            0x0..=0x1 => write_parameter(&mut affine.pa),
+            0x2..=0x3 => write_parameter(&mut affine.pb),
+            0x4..=0x5 => write_parameter(&mut affine.pc),
+            0x6..=0x7 => write_parameter(&mut affine.pd),
+            0x8..=0xB => {
+                let shift = (register_offset - 0x8) * 8;
+                affine.reference_x = (affine.reference_x & !(0xFF << shift)) | ((value as u32) << shift);
+                affine.latch_reference_x();
+            },
+            _ => {
+                let shift = (register_offset - 0xC) * 8;
+                affine.reference_y = (affine.reference_y & !(0xFF << shift)) | ((value as u32) << shift);
+                affine.latch_reference_y();
+            }
+        }
+    }
+}
+
+impl Default for PPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SaveState for PPU {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for register in [self.display_control, self.green_swap, self.display_status, self.vcount] {
+            writer.write_u16(register);
+        }
+
+        writer.write_bool(self.phase == PPUPhase::HBlank);
+
+        for register in self.bg_control.iter().chain(&self.bg_hofs).chain(&self.bg_vofs) {
+            writer.write_u16(*register);
+        }
+
+        for affine in self.bg_affine.iter() {
+            for parameter in [affine.pa, affine.pb, affine.pc, affine.pd] {
+                writer.write_u16(parameter as u16);
+            }
+
+            writer.write_u32(affine.reference_x);
+            writer.write_u32(affine.reference_y);
+            writer.write_i32(affine.internal_x);
+            writer.write_i32(affine.internal_y);
+        }
+
+        writer.write_u16(self.mosaic);
+
+        for register in self.window_horizontal.iter().chain(&self.window_vertical) {
+            writer.write_u16(*register);
+        }
+
+        for register in [self.window_inside, self.window_outside, self.blend_control, self.blend_alpha, self.blend_brightness] {
+            writer.write_u16(register);
+        }
+
+        // The line buffers are only used while a line is rendered, the picture is all there is to keep
+        for &pixel in self.framebuffer.iter() {
+            writer.write_u16(pixel);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        for register in [&mut self.display_control, &mut self.green_swap, &mut self.display_status, &mut self.vcount] {
+            *register = reader.read_u16()?;
+        }
+
+        self.phase = if reader.read_bool()? { PPUPhase::HBlank } else { PPUPhase::HDraw };
+
+        for register in self.bg_control.iter_mut().chain(&mut self.bg_hofs).chain(&mut self.bg_vofs) {
+            *register = reader.read_u16()?;
+        }
+
+        for affine in self.bg_affine.iter_mut() {
+            for parameter in [&mut affine.pa, &mut affine.pb, &mut affine.pc, &mut affine.pd] {
+                *parameter = reader.read_u16()? as i16;
+            }
+
+            affine.reference_x = reader.read_u32()?;
+            affine.reference_y = reader.read_u32()?;
+            affine.internal_x = reader.read_i32()?;
+            affine.internal_y = reader.read_i32()?;
+        }
+
+        self.mosaic = reader.read_u16()?;
+
+        for register in self.window_horizontal.iter_mut().chain(&mut self.window_vertical) {
+            *register = reader.read_u16()?;
+        }
+
+        for register in [&mut self.window_inside, &mut self.window_outside, &mut self.blend_control, &mut self.blend_alpha, &mut self.blend_brightness] {
+            *register = reader.read_u16()?;
+        }
+
+        for pixel in self.framebuffer.iter_mut() {
+            *pixel = reader.read_u16()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_scanline(ppu: &mut PPU, interrupts: &mut InterruptController) {
+        ppu.start_hblank(interrupts);
+        ppu.start_hdraw(interrupts);
+    }
+
+    #[test]
+    fn vblank_flag_is_set_from_line_160_to_226() {
+        let mut ppu = PPU::new();
+        let mut interrupts = InterruptController::new();
+
+        for _ in 0..SCREEN_HEIGHT {
+            assert_eq!(ppu.read8(0x004) & VBLANK_FLAG as u8, 0);
+            run_scanline(&mut ppu, &mut interrupts);
+        }
+
+        assert_eq!(ppu.read8(0x006), 160);
+        assert_ne!(ppu.read8(0x004) & VBLANK_FLAG as u8, 0);
+
+        for _ in SCREEN_HEIGHT..227 {
+            run_scanline(&mut ppu, &mut interrupts);
+        }
+
+        assert_eq!(ppu.read8(0x006), 227);
+        assert_eq!(ppu.read8(0x004) & VBLANK_FLAG as u8, 0);
+
+        run_scanline(&mut ppu, &mut interrupts);
+        assert_eq!(ppu.vcount(), 0);
+    }
+
+    #[test]
+    fn vcount_match_requests_interrupt() {
+        let mut ppu = PPU::new();
+        let mut interrupts = InterruptController::new();
+
+        interrupts.write8(0x200, 1 << InterruptType::VCounterMatch as u8);
+        interrupts.write8(0x208, 1);
+
+        ppu.write8(0x004, VCOUNTER_IRQ_ENABLE as u8);
+        ppu.write8(0x005, 3);
+
+        run_scanline(&mut ppu, &mut interrupts);
+        run_scanline(&mut ppu, &mut interrupts);
+        assert!(!interrupts.irq_line());
+
+        run_scanline(&mut ppu, &mut interrupts);
+        assert!(interrupts.irq_line());
+        assert_ne!(ppu.read8(0x004) & VCOUNTER_FLAG as u8, 0);
+    }
+}
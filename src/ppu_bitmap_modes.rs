@@ -0,0 +1,106 @@
+use crate::ppu::{PPU, SCREEN_HEIGHT, SCREEN_WIDTH, TRANSPARENT_PIXEL};
+
+const MODE3_WIDTH: usize = 240;
+const MODE5_WIDTH: usize = 160;
+const MODE5_HEIGHT: usize = 128;
+
+// Second frame used for page flipping in modes 4 and 5
+const BACK_FRAME_OFFSET: usize = 0xA000;
+
+impl PPU {
+    /// Modes 3, 4 and 5 draw a single bitmap on BG2, which goes through the BG2 rotation/scaling.
+    pub fn render_bitmap_bg2(&mut self, vram: &[u8], pal_ram: &[u8]) {
+        let frame_offset = if self.display_frame_select() { BACK_FRAME_OFFSET } else { 0 };
+        let affine = self.bg_affine[0].moved_back_lines(self.bg_mosaic_line_offset(2));
+
+        let (width, height) = match self.bg_mode() {
+            5 => (MODE5_WIDTH, MODE5_HEIGHT),
+            _ => (SCREEN_WIDTH, SCREEN_HEIGHT)
+        };
+
+        for x in 0..SCREEN_WIDTH {
+            let (texture_x, texture_y) = affine.texture_coordinates(x);
+
+            if !(0..width as i32).contains(&texture_x) || !(0..height as i32).contains(&texture_y) {
+                self.bg_lines[2][x] = TRANSPARENT_PIXEL;
+                continue;
+            }
+
+            let (texture_x, texture_y) = (texture_x as usize, texture_y as usize);
+
+            self.bg_lines[2][x] = match self.bg_mode() {
+                3 => Self::mode3_pixel(vram, texture_x, texture_y),
+                4 => Self::mode4_pixel(vram, pal_ram, frame_offset, texture_x, texture_y),
+                _ => Self::mode5_pixel(vram, frame_offset, texture_x, texture_y)
+            };
+        }
+    }
+
+    // 240x160 15-bit direct colour
+    fn mode3_pixel(vram: &[u8], x: usize, y: usize) -> u16 {
+        Self::vram_read16(vram, (y * MODE3_WIDTH + x) * 2) & 0x7FFF
+    }
+
+    // 240x160 8-bit paletted, palette entry 0 is transparent
+    fn mode4_pixel(vram: &[u8], pal_ram: &[u8], frame_offset: usize, x: usize, y: usize) -> u16 {
+        let palette_index = vram[frame_offset + y * MODE3_WIDTH + x] as usize;
+
+        if palette_index == 0 {
+            TRANSPARENT_PIXEL
+        } else {
+            Self::palette_color(pal_ram, palette_index)
+        }
+    }
+
+    // 160x128 15-bit direct colour, the rest of the screen shows the backdrop
+    fn mode5_pixel(vram: &[u8], frame_offset: usize, x: usize, y: usize) -> u16 {
+        Self::vram_read16(vram, frame_offset + (y * MODE5_WIDTH + x) * 2) & 0x7FFF
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ppu::{PPU, SCREEN_WIDTH};
+
+    const VRAM_SIZE: usize = 96 * 1024;
+
+    #[test]
+    fn mode3_draws_direct_colours() {
+        let mut ppu = PPU::new();
+        let mut vram = vec![0u8; VRAM_SIZE];
+        let pal_ram = vec![0u8; 1024];
+
+        vram[0] = 0x1F; // Red
+        vram[2] = 0xE0; // Green
+        vram[3] = 0x03;
+
+        ppu.write8(0x000, 3);
+        ppu.write8(0x001, 0x04); // BG2 on
+
+        ppu.render_scanline(&vram, &pal_ram, &[0; 1024]);
+
+        assert_eq!(ppu.framebuffer()[0], 0x001F);
+        assert_eq!(ppu.framebuffer()[1], 0x03E0);
+    }
+
+    #[test]
+    fn mode4_page_flip_selects_back_frame() {
+        let mut ppu = PPU::new();
+        let mut vram = vec![0u8; VRAM_SIZE];
+        let mut pal_ram = vec![0u8; 1024];
+
+        pal_ram[0] = 0x00; // Backdrop: black
+        pal_ram[2] = 0xFF; // Entry 1: white
+        pal_ram[3] = 0x7F;
+        vram[0xA000 + 5] = 1;
+
+        ppu.write8(0x000, 4 | 0x10);
+        ppu.write8(0x001, 0x04);
+
+        ppu.render_scanline(&vram, &pal_ram, &[0; 1024]);
+
+        assert_eq!(ppu.framebuffer()[5], 0x7FFF);
+        assert_eq!(ppu.framebuffer()[4], 0x0000);
+        assert_eq!(ppu.framebuffer()[SCREEN_WIDTH - 1], 0x0000);
+    }
+}
@@ -0,0 +1,210 @@
+use crate::ppu::{PPU, SCREEN_WIDTH};
+
+// Layer numbers as used by the BLDCNT target bits
+pub const LAYER_OBJ: usize = 4;
+pub const LAYER_BACKDROP: usize = 5;
+
+const BGCNT_MOSAIC: u16 = 1 << 6;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ColorEffect {
+    None = 0,
+    AlphaBlending = 1,
+    BrightnessIncrease = 2,
+    BrightnessDecrease = 3
+}
+
+/// Colour of one of the layers at a given pixel, along with where it comes from.
+#[derive(Clone, Copy)]
+pub struct LayerPixel {
+    pub color: u16,
+    pub layer: usize,
+    pub priority: u16
+}
+
+impl PPU {
+    pub fn color_effect(&self) -> ColorEffect {
+        match (self.blend_control >> 6) & 3 {
+            0 => ColorEffect::None,
+            1 => ColorEffect::AlphaBlending,
+            2 => ColorEffect::BrightnessIncrease,
+            _ => ColorEffect::BrightnessDecrease
+        }
+    }
+
+    fn is_first_target(&self, layer: usize) -> bool {
+        (self.blend_control & (1 << layer)) != 0
+    }
+
+    fn is_second_target(&self, layer: usize) -> bool {
+        (self.blend_control & (1 << (8 + layer))) != 0
+    }
+
+    /// Applies BLDCNT to the two topmost layers of a pixel. Semi-transparent OBJs are always
+    /// alpha blended against a second target, whatever effect is selected.
+    pub fn apply_color_effects(&self, top: LayerPixel, second: LayerPixel, is_semi_transparent_obj: bool) -> u16 {
+        if is_semi_transparent_obj && self.is_second_target(second.layer) {
+            return self.alpha_blend(top.color, second.color);
+        }
+
+        if !self.is_first_target(top.layer) {
+            return top.color;
+        }
+
+        match self.color_effect() {
+            ColorEffect::AlphaBlending if self.is_second_target(second.layer) => self.alpha_blend(top.color, second.color),
+            ColorEffect::BrightnessIncrease => self.brightness_increase(top.color),
+            ColorEffect::BrightnessDecrease => self.brightness_decrease(top.color),
+            _ => top.color
+        }
+    }
+
+    fn blend_coefficients(&self) -> (u16, u16, u16) {
+        let eva = (self.blend_alpha & 0x1F).min(16);
+        let evb = ((self.blend_alpha >> 8) & 0x1F).min(16);
+        let evy = (self.blend_brightness & 0x1F).min(16);
+
+        (eva, evb, evy)
+    }
+
+    fn map_color_channels(color: u16, operation: impl Fn(u16, u16) -> u16, other_color: u16) -> u16 {
+        (0..3).fold(0, |result, channel| {
+            let shift = channel * 5;
+            let value = operation((color >> shift) & 0x1F, (other_color >> shift) & 0x1F).min(31);
+
+            result | (value << shift)
+        })
+    }
+
+    fn alpha_blend(&self, first_color: u16, second_color: u16) -> u16 {
+        let (eva, evb, _) = self.blend_coefficients();
+
+        Self::map_color_channels(first_color, |first, second| (first * eva + second * evb) >> 4, second_color)
+    }
+
+    fn brightness_increase(&self, color: u16) -> u16 {
+        let (_, _, evy) = self.blend_coefficients();
+
+        Self::map_color_channels(color, |channel, _| channel + (((31 - channel) * evy) >> 4), 0)
+    }
+
+    fn brightness_decrease(&self, color: u16) -> u16 {
+        let (_, _, evy) = self.blend_coefficients();
+
+        Self::map_color_channels(color, |channel, _| channel - ((channel * evy) >> 4), 0)
+    }
+
+    pub fn bg_mosaic_size(&self) -> (usize, usize) {
+        ((self.mosaic & 0xF) as usize + 1, ((self.mosaic >> 4) & 0xF) as usize + 1)
+    }
+
+    pub fn is_bg_mosaic(&self, bg: usize) -> bool {
+        (self.bg_control[bg] & BGCNT_MOSAIC) != 0
+    }
+
+    /// Lines inside a vertical mosaic block repeat the first line of the block.
+    pub fn bg_mosaic_line_offset(&self, bg: usize) -> usize {
+        if self.is_bg_mosaic(bg) {
+            self.vcount() as usize % self.bg_mosaic_size().1
+        } else {
+            0
+        }
+    }
+
+    /// Horizontal mosaic repeats the first pixel of each block on the rendered line.
+    pub fn apply_bg_horizontal_mosaic(&mut self, bg: usize) {
+        let (mosaic_width, _) = self.bg_mosaic_size();
+
+        if !self.is_bg_mosaic(bg) || mosaic_width == 1 {
+            return;
+        }
+
+        for x in 0..SCREEN_WIDTH {
+            self.bg_lines[bg][x] = self.bg_lines[bg][x - x % mosaic_width];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ppu::PPU;
+
+    const VRAM_SIZE: usize = 96 * 1024;
+
+    // Mode 0 with BG0 (priority 0, colour 0x001F) over BG1 (priority 1, colour 0x7C00)
+    fn setup_two_bgs() -> (PPU, Vec<u8>, Vec<u8>) {
+        let mut ppu = PPU::new();
+        let mut vram = vec![0u8; VRAM_SIZE];
+        let mut pal_ram = vec![0u8; 1024];
+
+        pal_ram[2] = 0x1F;
+        pal_ram[4] = 0x00;
+        pal_ram[5] = 0x7C;
+
+        vram[64..128].fill(1);
+        vram[128..192].fill(2);
+        vram[0x4000..0x4800].chunks_mut(2).for_each(|entry| entry[0] = 1);
+        vram[0x4800..0x5000].chunks_mut(2).for_each(|entry| entry[0] = 2);
+
+        ppu.write8(0x000, 0);
+        ppu.write8(0x001, 0x03);
+        ppu.write8(0x008, 0x80);
+        ppu.write8(0x009, 0x08);
+        ppu.write8(0x00A, 0x81);
+        ppu.write8(0x00B, 0x09);
+
+        (ppu, vram, pal_ram)
+    }
+
+    #[test]
+    fn alpha_blending_mixes_first_and_second_targets() {
+        let (mut ppu, vram, pal_ram) = setup_two_bgs();
+
+        ppu.write8(0x050, 0x41); // BG0 first target, alpha blending
+        ppu.write8(0x051, 0x02); // BG1 second target
+        ppu.write8(0x052, 8); // EVA = 0.5
+        ppu.write8(0x053, 8); // EVB = 0.5
+
+        ppu.render_scanline(&vram, &pal_ram, &[0; 1024]);
+
+        assert_eq!(ppu.framebuffer()[0], (15 << 10) | 15);
+    }
+
+    #[test]
+    fn brightness_effects_fade_first_target() {
+        let (mut ppu, vram, pal_ram) = setup_two_bgs();
+
+        ppu.write8(0x050, 0x81); // BG0 first target, brightness increase
+        ppu.write8(0x054, 16);
+
+        ppu.render_scanline(&vram, &pal_ram, &[0; 1024]);
+        assert_eq!(ppu.framebuffer()[0], 0x7FFF);
+
+        ppu.write8(0x050, 0xC1); // Brightness decrease
+        ppu.write8(0x054, 8);
+
+        ppu.render_scanline(&vram, &pal_ram, &[0; 1024]);
+        assert_eq!(ppu.framebuffer()[0], 16);
+    }
+
+    #[test]
+    fn horizontal_bg_mosaic_repeats_pixels() {
+        let mut ppu = PPU::new();
+        let mut vram = vec![0u8; VRAM_SIZE];
+
+        for x in 0..240 {
+            vram[x * 2] = x as u8;
+        }
+
+        ppu.write8(0x000, 3);
+        ppu.write8(0x001, 0x04);
+        ppu.write8(0x00C, 0x40); // BG2 mosaic
+        ppu.write8(0x04C, 0x03); // 4 pixels wide blocks
+
+        ppu.render_scanline(&vram, &[0; 1024], &[0; 1024]);
+
+        assert_eq!(ppu.framebuffer()[5], 4);
+        assert_eq!(ppu.framebuffer()[7], 4);
+        assert_eq!(ppu.framebuffer()[8], 8);
+    }
+}
@@ -0,0 +1,347 @@
+use crate::ppu::{PPU, SCREEN_WIDTH, TRANSPARENT_PIXEL};
+
+const OAM_ENTRIES: usize = 128;
+const OAM_ENTRY_SIZE: usize = 8;
+
+// OBJ tiles live in the upper 32 KiB of the VRAM (the lower half of it is taken by bitmap modes)
+const OBJ_TILES_BASE: usize = 0x10000;
+const OBJ_TILES_SIZE: usize = 0x8000;
+const BITMAP_MODES_FIRST_OBJ_TILE: usize = 512;
+const TILE_SIZE: usize = 32;
+
+const OBJ_PALETTE_BASE: usize = 256;
+
+// Rendering time available for OBJs on each line, in cycles
+const OBJ_CYCLES_PER_LINE: u32 = 1210;
+const OBJ_CYCLES_PER_LINE_HBLANK_FREE: u32 = 954;
+
+const DISPCNT_HBLANK_INTERVAL_FREE: u16 = 1 << 5;
+const DISPCNT_OBJ_1D_MAPPING: u16 = 1 << 6;
+
+// Width and height by shape (square, horizontal, vertical) and size
+const OBJ_DIMENSIONS: [[(i32, i32); 4]; 3] = [
+    [(8, 8), (16, 16), (32, 32), (64, 64)],
+    [(16, 8), (32, 8), (32, 16), (64, 32)],
+    [(8, 16), (8, 32), (16, 32), (32, 64)]
+];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ObjMode {
+    Normal = 0,
+    SemiTransparent = 1,
+    ObjWindow = 2
+}
+
+/// Topmost OBJ pixel on a given screen column, used when composing the line against the BGs.
+#[derive(Clone, Copy)]
+pub struct ObjPixel {
+    pub color: u16,
+    pub priority: u16,
+    pub semi_transparent: bool
+}
+
+impl ObjPixel {
+    pub const TRANSPARENT: ObjPixel = ObjPixel { color: TRANSPARENT_PIXEL, priority: 4, semi_transparent: false };
+}
+
+struct ObjAttributes {
+    y: i32,
+    x: i32,
+    is_affine: bool,
+    is_double_size: bool,
+    mode: ObjMode,
+    is_mosaic: bool,
+    is_256_colors: bool,
+    width: i32,
+    height: i32,
+    affine_group: usize,
+    horizontal_flip: bool,
+    vertical_flip: bool,
+    tile_number: usize,
+    priority: u16,
+    palette_bank: usize
+}
+
+impl ObjAttributes {
+    /// Decodes an OAM entry, returns None for disabled or prohibited OBJs.
+    fn decode(oam: &[u8], index: usize) -> Option<Self> {
+        let entry = index * OAM_ENTRY_SIZE;
+        let attribute0 = PPU::vram_read16(oam, entry);
+        let attribute1 = PPU::vram_read16(oam, entry + 2);
+        let attribute2 = PPU::vram_read16(oam, entry + 4);
+
+        let is_affine = (attribute0 & (1 << 8)) != 0;
+        let shape = (attribute0 >> 14) as usize;
+        let size = (attribute1 >> 14) as usize;
+
+        let mode = match (attribute0 >> 10) & 3 {
+            0 => ObjMode::Normal,
+            1 => ObjMode::SemiTransparent,
+            2 => ObjMode::ObjWindow,
+            _ => return None
+        };
+
+        // On regular OBJs the double-size bit disables the OBJ instead
+        if (!is_affine && (attribute0 & (1 << 9)) != 0) || shape == 3 {
+            return None;
+        }
+
+        let (width, height) = OBJ_DIMENSIONS[shape][size];
+        let x = (attribute1 & 0x1FF) as i32;
+
+        Some(ObjAttributes {
+            y: (attribute0 & 0xFF) as i32,
+            x: if x >= SCREEN_WIDTH as i32 { x - 512 } else { x },
+            is_affine,
+            is_double_size: is_affine && (attribute0 & (1 << 9)) != 0,
+            mode,
+            is_mosaic: (attribute0 & (1 << 12)) != 0,
+            is_256_colors: (attribute0 & (1 << 13)) != 0,
+            width,
+            height,
+            affine_group: ((attribute1 >> 9) & 0x1F) as usize,
+            horizontal_flip: !is_affine && (attribute1 & (1 << 12)) != 0,
+            vertical_flip: !is_affine && (attribute1 & (1 << 13)) != 0,
+            tile_number: (attribute2 & 0x3FF) as usize,
+            priority: (attribute2 >> 10) & 3,
+            palette_bank: (attribute2 >> 12) as usize
+        })
+    }
+
+    /// Size of the area the OBJ takes on screen, doubled for double-size affine OBJs.
+    fn bounding_box(&self) -> (i32, i32) {
+        if self.is_double_size { (self.width * 2, self.height * 2) } else { (self.width, self.height) }
+    }
+
+    fn rendering_cycles(&self) -> u32 {
+        let (bounding_width, _) = self.bounding_box();
+
+        if self.is_affine { 10 + 2 * bounding_width as u32 } else { bounding_width as u32 }
+    }
+
+    /// PA, PB, PC and PD of the affine group, interleaved with the attributes of 4 consecutive OAM entries.
+    fn affine_parameters(&self, oam: &[u8]) -> [i32; 4] {
+        let group_address = self.affine_group * 4 * OAM_ENTRY_SIZE;
+        let mut parameters = [0i32; 4];
+
+        for (i, parameter) in parameters.iter_mut().enumerate() {
+            *parameter = PPU::vram_read16(oam, group_address + i * OAM_ENTRY_SIZE + 6) as i16 as i32;
+        }
+
+        parameters
+    }
+}
+
+impl PPU {
+    pub fn is_obj_1d_mapping(&self) -> bool {
+        (self.display_control & DISPCNT_OBJ_1D_MAPPING) != 0
+    }
+
+    pub fn obj_mosaic_size(&self) -> (i32, i32) {
+        (((self.mosaic >> 8) & 0xF) as i32 + 1, ((self.mosaic >> 12) & 0xF) as i32 + 1)
+    }
+
+    /// Draws the 128 OBJs into the OBJ line buffers, as long as the line's OBJ cycle budget allows it.
+    pub fn render_sprites(&mut self, vram: &[u8], pal_ram: &[u8], oam: &[u8]) {
+        let line = self.vcount() as i32;
+        let mut available_cycles = if (self.display_control & DISPCNT_HBLANK_INTERVAL_FREE) != 0 {
+            OBJ_CYCLES_PER_LINE_HBLANK_FREE
+        } else {
+            OBJ_CYCLES_PER_LINE
+        };
+
+        for index in 0..OAM_ENTRIES {
+            let Some(obj) = ObjAttributes::decode(oam, index) else {
+                continue;
+            };
+
+            // Y wraps around at 256, so OBJs near the bottom also show up at the top
+            let local_y = (line - obj.y) & 0xFF;
+
+            if local_y >= obj.bounding_box().1 {
+                continue;
+            }
+
+            let cycles = obj.rendering_cycles();
+
+            if cycles > available_cycles {
+                break;
+            }
+
+            available_cycles -= cycles;
+
+            self.render_sprite_line(&obj, local_y, vram, pal_ram, oam);
+        }
+    }
+
+    fn render_sprite_line(&mut self, obj: &ObjAttributes, local_y: i32, vram: &[u8], pal_ram: &[u8], oam: &[u8]) {
+        let (bounding_width, bounding_height) = obj.bounding_box();
+        let (mosaic_width, mosaic_height) = if obj.is_mosaic { self.obj_mosaic_size() } else { (1, 1) };
+        let local_y = local_y - local_y % mosaic_height;
+        let [pa, pb, pc, pd] = if obj.is_affine { obj.affine_parameters(oam) } else { [0x100, 0, 0, 0x100] };
+
+        for local_x in 0..bounding_width {
+            let screen_x = obj.x + local_x;
+
+            if !(0..SCREEN_WIDTH as i32).contains(&screen_x) {
+                continue;
+            }
+
+            let local_x = local_x - local_x % mosaic_width;
+
+            let (texture_x, texture_y) = if obj.is_affine {
+                // Rotation/scaling is done around the centre of the OBJ
+                let delta_x = local_x - bounding_width / 2;
+                let delta_y = local_y - bounding_height / 2;
+
+                (((pa * delta_x + pb * delta_y) >> 8) + obj.width / 2, ((pc * delta_x + pd * delta_y) >> 8) + obj.height / 2)
+            } else {
+                (
+                    if obj.horizontal_flip { obj.width - 1 - local_x } else { local_x },
+                    if obj.vertical_flip { obj.height - 1 - local_y } else { local_y }
+                )
+            };
+
+            if !(0..obj.width).contains(&texture_x) || !(0..obj.height).contains(&texture_y) {
+                continue;
+            }
+
+            let color = self.obj_texel(obj, texture_x as usize, texture_y as usize, vram, pal_ram);
+
+            if color == TRANSPARENT_PIXEL {
+                continue;
+            }
+
+            let screen_x = screen_x as usize;
+
+            if obj.mode == ObjMode::ObjWindow {
+                self.obj_window_line[screen_x] = true;
+            } else if self.obj_line[screen_x].color == TRANSPARENT_PIXEL || obj.priority < self.obj_line[screen_x].priority {
+                // Lower OAM entries have already been drawn, they stay on top when priorities are equal
+                self.obj_line[screen_x] = ObjPixel {
+                    color,
+                    priority: obj.priority,
+                    semi_transparent: obj.mode == ObjMode::SemiTransparent
+                };
+            }
+        }
+    }
+
+    fn obj_texel(&self, obj: &ObjAttributes, texture_x: usize, texture_y: usize, vram: &[u8], pal_ram: &[u8]) -> u16 {
+        let tiles_per_row = obj.width as usize / 8;
+        // Tile numbers count in 32 bytes units, a 256 colours tile takes two of them
+        let tile_units = if obj.is_256_colors { 2 } else { 1 };
+        let row_stride = if self.is_obj_1d_mapping() { tiles_per_row * tile_units } else { 32 };
+        let first_tile = if obj.is_256_colors { obj.tile_number & !1 } else { obj.tile_number };
+
+        let tile_number = (first_tile + (texture_y / 8) * row_stride + (texture_x / 8) * tile_units) & 0x3FF;
+
+        if self.bg_mode() >= 3 && tile_number < BITMAP_MODES_FIRST_OBJ_TILE {
+            return TRANSPARENT_PIXEL;
+        }
+
+        let (pixel_x, pixel_y) = (texture_x % 8, texture_y % 8);
+
+        if obj.is_256_colors {
+            let address = OBJ_TILES_BASE + (tile_number * TILE_SIZE + pixel_y * 8 + pixel_x) % OBJ_TILES_SIZE;
+
+            match vram[address] {
+                0 => TRANSPARENT_PIXEL,
+                color_index => Self::palette_color(pal_ram, OBJ_PALETTE_BASE + color_index as usize)
+            }
+        } else {
+            let address = OBJ_TILES_BASE + (tile_number * TILE_SIZE + pixel_y * 4 + pixel_x / 2) % OBJ_TILES_SIZE;
+
+            match (vram[address] >> ((pixel_x & 1) * 4)) & 0xF {
+                0 => TRANSPARENT_PIXEL,
+                color_index => Self::palette_color(pal_ram, OBJ_PALETTE_BASE + obj.palette_bank * 16 + color_index as usize)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ppu::PPU;
+
+    const VRAM_SIZE: usize = 96 * 1024;
+
+    fn write_oam_entry(oam: &mut [u8], index: usize, attributes: [u16; 3]) {
+        for (i, attribute) in attributes.iter().enumerate() {
+            oam[index * 8 + i * 2] = *attribute as u8;
+            oam[index * 8 + i * 2 + 1] = (*attribute >> 8) as u8;
+        }
+    }
+
+    fn setup() -> (PPU, Vec<u8>, Vec<u8>, Vec<u8>) {
+        let mut ppu = PPU::new();
+        let mut vram = vec![0u8; VRAM_SIZE];
+        let mut pal_ram = vec![0u8; 1024];
+        let mut oam = vec![0u8; 1024];
+
+        // Disable every OBJ
+        for index in 0..128 {
+            write_oam_entry(&mut oam, index, [0x0200, 0, 0]);
+        }
+
+        // OBJ palette entries 1 and 2
+        pal_ram[0x202] = 0x1F;
+        pal_ram[0x204] = 0xE0;
+
+        // OBJ tile 1 filled with colour 1, tile 2 with colour 2 (4bpp)
+        vram[0x10020..0x10040].fill(0x11);
+        vram[0x10040..0x10060].fill(0x22);
+
+        ppu.write8(0x000, 0x40); // Mode 0, 1D mapping
+        ppu.write8(0x001, 0x10); // OBJ on
+
+        (ppu, vram, pal_ram, oam)
+    }
+
+    #[test]
+    fn lower_oam_entry_wins_with_same_priority() {
+        let (mut ppu, vram, pal_ram, mut oam) = setup();
+
+        write_oam_entry(&mut oam, 0, [0, 4, 1]);
+        write_oam_entry(&mut oam, 1, [0, 0, 2]);
+
+        ppu.render_scanline(&vram, &pal_ram, &oam);
+
+        assert_eq!(ppu.framebuffer()[0], 0x00E0);
+        assert_eq!(ppu.framebuffer()[4], 0x001F);
+        assert_eq!(ppu.framebuffer()[11], 0x001F);
+        assert_eq!(ppu.framebuffer()[12], 0);
+    }
+
+    #[test]
+    fn horizontal_obj_uses_1d_tile_mapping() {
+        let (mut ppu, vram, pal_ram, mut oam) = setup();
+
+        // 16x8 OBJ starting on tile 1, its right half takes tile 2 with 1D mapping
+        write_oam_entry(&mut oam, 0, [0x4000, 0, 1]);
+
+        ppu.render_scanline(&vram, &pal_ram, &oam);
+
+        assert_eq!(ppu.framebuffer()[7], 0x001F);
+        assert_eq!(ppu.framebuffer()[8], 0x00E0);
+    }
+
+    #[test]
+    fn obj_cycle_budget_limits_sprites_per_line() {
+        let (mut ppu, vram, pal_ram, mut oam) = setup();
+
+        // 18 off-screen 64x64 OBJs still take 64 cycles each from the 1210 available
+        for index in 0..18 {
+            write_oam_entry(&mut oam, index, [0x0000, 0xC000 | 300, 1]);
+        }
+
+        // 8x8 OBJ fits in the remaining cycles, the next 64x64 one doesn't
+        write_oam_entry(&mut oam, 18, [0x0000, 0x0000, 1]);
+        write_oam_entry(&mut oam, 19, [0x0000, 0xC000 | 16, 1]);
+
+        ppu.render_scanline(&vram, &pal_ram, &oam);
+
+        assert_eq!(ppu.framebuffer()[0], 0x001F);
+        assert_eq!(ppu.framebuffer()[20], 0);
+    }
+}
@@ -0,0 +1,279 @@
+use crate::ppu::{PPU, SCREEN_WIDTH, TRANSPARENT_PIXEL};
+
+// Tile data can't be fetched from the OBJ part of the VRAM in tiled modes
+const BG_VRAM_SIZE: usize = 0x10000;
+
+const CHAR_BLOCK_SIZE: usize = 0x4000;
+const SCREEN_BLOCK_SIZE: usize = 0x800;
+const SCREEN_BLOCK_PIXELS: usize = 256;
+
+const TILE_4BPP_SIZE: usize = 32;
+const TILE_8BPP_SIZE: usize = 64;
+
+const BGCNT_256_COLORS: u16 = 1 << 7;
+const BGCNT_AFFINE_WRAPAROUND: u16 = 1 << 13;
+
+/// BG2/BG3 rotation and scaling parameters, the reference point is 20.8 fixed point.
+#[derive(Clone, Copy)]
+pub struct AffineBackground {
+    pub pa: i16,
+    pub pb: i16,
+    pub pc: i16,
+    pub pd: i16,
+    pub reference_x: u32,
+    pub reference_y: u32,
+
+    // Reference point latched at V-Blank (or when written) and moved by PB/PD after every line
+    pub internal_x: i32,
+    pub internal_y: i32
+}
+
+impl AffineBackground {
+    pub fn new() -> Self {
+        AffineBackground {
+            pa: 0x100,
+            pb: 0,
+            pc: 0,
+            pd: 0x100,
+            reference_x: 0,
+            reference_y: 0,
+            internal_x: 0,
+            internal_y: 0
+        }
+    }
+
+    fn sign_extend_28bit(value: u32) -> i32 {
+        ((value << 4) as i32) >> 4
+    }
+
+    pub fn latch_reference_x(&mut self) {
+        self.internal_x = Self::sign_extend_28bit(self.reference_x);
+    }
+
+    pub fn latch_reference_y(&mut self) {
+        self.internal_y = Self::sign_extend_28bit(self.reference_y);
+    }
+
+    /// Reference point as it was `lines` lines ago, where a vertical mosaic block started.
+    pub fn moved_back_lines(&self, lines: usize) -> Self {
+        let mut affine = *self;
+        affine.internal_x = affine.internal_x.wrapping_sub(self.pb as i32 * lines as i32);
+        affine.internal_y = affine.internal_y.wrapping_sub(self.pd as i32 * lines as i32);
+
+        affine
+    }
+
+    pub fn finish_scanline(&mut self) {
+        self.internal_x = self.internal_x.wrapping_add(self.pb as i32);
+        self.internal_y = self.internal_y.wrapping_add(self.pd as i32);
+    }
+
+    /// Texture coordinates (integer part) for the screen pixel `x` of the current line.
+    pub fn texture_coordinates(&self, x: usize) -> (i32, i32) {
+        let texture_x = self.internal_x.wrapping_add(self.pa as i32 * x as i32) >> 8;
+        let texture_y = self.internal_y.wrapping_add(self.pc as i32 * x as i32) >> 8;
+
+        (texture_x, texture_y)
+    }
+}
+
+impl Default for AffineBackground {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PPU {
+    fn char_base_address(bg_control: u16) -> usize {
+        ((bg_control >> 2) & 3) as usize * CHAR_BLOCK_SIZE
+    }
+
+    fn screen_base_address(bg_control: u16) -> usize {
+        ((bg_control >> 8) & 0x1F) as usize * SCREEN_BLOCK_SIZE
+    }
+
+    /// Text (regular) backgrounds, 256x256 up to 512x512 pixels made of 32x32 tiles screen blocks.
+    pub fn render_text_bg(&mut self, bg: usize, vram: &[u8], pal_ram: &[u8]) {
+        let bg_control = self.bg_control[bg];
+        let char_base = Self::char_base_address(bg_control);
+        let screen_base = Self::screen_base_address(bg_control);
+        let is_256_colors = (bg_control & BGCNT_256_COLORS) != 0;
+
+        let (width, height): (usize, usize) = match bg_control >> 14 {
+            0 => (256, 256),
+            1 => (512, 256),
+            2 => (256, 512),
+            _ => (512, 512)
+        };
+
+        let line = self.vcount() as usize - self.bg_mosaic_line_offset(bg);
+        let bg_y = (line + self.bg_vofs[bg] as usize) & (height - 1);
+        let tile_y = bg_y % 8;
+
+        for x in 0..SCREEN_WIDTH {
+            let bg_x = (x + self.bg_hofs[bg] as usize) & (width - 1);
+
+            let screen_block = (bg_x / SCREEN_BLOCK_PIXELS) + (bg_y / SCREEN_BLOCK_PIXELS) * (width / SCREEN_BLOCK_PIXELS);
+            let map_entry_index = ((bg_y % SCREEN_BLOCK_PIXELS) / 8) * 32 + (bg_x % SCREEN_BLOCK_PIXELS) / 8;
+            let map_entry_address = (screen_base + screen_block * SCREEN_BLOCK_SIZE + map_entry_index * 2) % BG_VRAM_SIZE;
+            let map_entry = Self::vram_read16(vram, map_entry_address);
+
+            let tile_number = (map_entry & 0x3FF) as usize;
+            let pixel_x = if (map_entry & (1 << 10)) != 0 { 7 - bg_x % 8 } else { bg_x % 8 };
+            let pixel_y = if (map_entry & (1 << 11)) != 0 { 7 - tile_y } else { tile_y };
+            let palette_bank = (map_entry >> 12) as usize;
+
+            self.bg_lines[bg][x] = if is_256_colors {
+                let address = char_base + tile_number * TILE_8BPP_SIZE + pixel_y * 8 + pixel_x;
+                Self::tile_8bpp_pixel(vram, pal_ram, address)
+            } else {
+                let address = char_base + tile_number * TILE_4BPP_SIZE + pixel_y * 4 + pixel_x / 2;
+
+                if address >= BG_VRAM_SIZE {
+                    TRANSPARENT_PIXEL
+                } else {
+                    let color_index = (vram[address] >> ((pixel_x & 1) * 4)) & 0xF;
+
+                    if color_index == 0 {
+                        TRANSPARENT_PIXEL
+                    } else {
+                        Self::palette_color(pal_ram, palette_bank * 16 + color_index as usize)
+                    }
+                }
+            };
+        }
+    }
+
+    /// Rotation/scaling backgrounds (BG2 and BG3), always 256 colours with 8-bit map entries.
+    pub fn render_affine_bg(&mut self, bg: usize, vram: &[u8], pal_ram: &[u8]) {
+        let bg_control = self.bg_control[bg];
+        let char_base = Self::char_base_address(bg_control);
+        let screen_base = Self::screen_base_address(bg_control);
+        let size = 128i32 << (bg_control >> 14);
+        let wraparound = (bg_control & BGCNT_AFFINE_WRAPAROUND) != 0;
+        let affine = self.bg_affine[bg - 2].moved_back_lines(self.bg_mosaic_line_offset(bg));
+
+        for x in 0..SCREEN_WIDTH {
+            let (mut texture_x, mut texture_y) = affine.texture_coordinates(x);
+
+            if wraparound {
+                texture_x = texture_x.rem_euclid(size);
+                texture_y = texture_y.rem_euclid(size);
+            } else if !(0..size).contains(&texture_x) || !(0..size).contains(&texture_y) {
+                self.bg_lines[bg][x] = TRANSPARENT_PIXEL;
+                continue;
+            }
+
+            let map_entry_address = screen_base + (texture_y as usize / 8) * (size as usize / 8) + texture_x as usize / 8;
+            let tile_number = vram[map_entry_address % BG_VRAM_SIZE] as usize;
+            let address = char_base + tile_number * TILE_8BPP_SIZE + (texture_y as usize % 8) * 8 + texture_x as usize % 8;
+
+            self.bg_lines[bg][x] = Self::tile_8bpp_pixel(vram, pal_ram, address);
+        }
+    }
+
+    fn tile_8bpp_pixel(vram: &[u8], pal_ram: &[u8], address: usize) -> u16 {
+        if address >= BG_VRAM_SIZE {
+            return TRANSPARENT_PIXEL;
+        }
+
+        match vram[address] {
+            0 => TRANSPARENT_PIXEL,
+            color_index => Self::palette_color(pal_ram, color_index as usize)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ppu::PPU;
+
+    const VRAM_SIZE: usize = 96 * 1024;
+
+    fn palette_with_colors() -> Vec<u8> {
+        let mut pal_ram = vec![0u8; 1024];
+
+        for index in 1..256 {
+            pal_ram[index * 2] = index as u8;
+        }
+
+        pal_ram
+    }
+
+    #[test]
+    fn text_bg_applies_scroll_and_horizontal_flip() {
+        let mut ppu = PPU::new();
+        let mut vram = vec![0u8; VRAM_SIZE];
+        let pal_ram = palette_with_colors();
+
+        // Tile 1 (4bpp, char base 0) has colour 1 on its leftmost pixel of every row
+        for row in 0..8 {
+            vram[32 + row * 4] = 0x01;
+        }
+
+        // Screen base block 8: map entry (1, 0) -> tile 1 flipped horizontally
+        vram[0x4000 + 2] = 0x01;
+        vram[0x4000 + 3] = 0x04;
+
+        ppu.write8(0x000, 0);
+        ppu.write8(0x001, 0x01); // BG0 on
+        ppu.write8(0x009, 0x08); // BG0CNT: screen base 8
+        ppu.write8(0x010, 4); // BG0HOFS
+
+        ppu.render_scanline(&vram, &pal_ram, &[0; 1024]);
+
+        // Flipped, the coloured pixel ends at the tile's x = 7 -> bg x = 15 -> screen x = 11
+        assert_eq!(ppu.framebuffer()[11], 1);
+        assert_eq!(ppu.framebuffer()[4], 0);
+    }
+
+    #[test]
+    fn bg_priority_orders_layers() {
+        let mut ppu = PPU::new();
+        let mut vram = vec![0u8; VRAM_SIZE];
+        let pal_ram = palette_with_colors();
+
+        // Tile 1: 8bpp filled with colour 2, tile 2: 8bpp filled with colour 3
+        vram[64..128].fill(2);
+        vram[128..192].fill(3);
+
+        // BG0 map at block 8 uses tile 1, BG1 map at block 9 uses tile 2
+        vram[0x4000] = 1;
+        vram[0x4800] = 2;
+
+        ppu.write8(0x000, 0);
+        ppu.write8(0x001, 0x03); // BG0 and BG1 on
+        ppu.write8(0x008, 0x81); // BG0CNT: 256 colours, priority 1
+        ppu.write8(0x009, 0x08);
+        ppu.write8(0x00A, 0x80); // BG1CNT: 256 colours, priority 0
+        ppu.write8(0x00B, 0x09);
+
+        ppu.render_scanline(&vram, &pal_ram, &[0; 1024]);
+
+        assert_eq!(ppu.framebuffer()[0], 3);
+        assert_eq!(ppu.framebuffer()[8], 0);
+    }
+
+    #[test]
+    fn affine_bg_scales_and_wraps() {
+        let mut ppu = PPU::new();
+        let mut vram = vec![0u8; VRAM_SIZE];
+        let pal_ram = palette_with_colors();
+
+        vram[64..128].fill(5); // Tile 1
+        vram[0x4000] = 1; // Map entry (0, 0) on a 128x128 map
+
+        ppu.write8(0x000, 2);
+        ppu.write8(0x001, 0x04); // BG2 on
+        ppu.write8(0x00C, 0x00);
+        ppu.write8(0x00D, 0x28); // Screen base 8, wraparound
+        ppu.write8(0x020, 0x80); // PA = 0.5
+        ppu.write8(0x021, 0x00);
+
+        ppu.render_scanline(&vram, &pal_ram, &[0; 1024]);
+
+        // Half scale: the 8 pixels wide tile covers 16 screen pixels
+        assert_eq!(ppu.framebuffer()[15], 5);
+        assert_eq!(ppu.framebuffer()[16], 0);
+    }
+}
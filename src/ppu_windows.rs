@@ -0,0 +1,132 @@
+use crate::ppu::{PPU, SCREEN_WIDTH};
+
+const DISPCNT_WIN0_ENABLE: u16 = 1 << 13;
+const DISPCNT_WIN1_ENABLE: u16 = 1 << 14;
+const DISPCNT_OBJ_WINDOW_ENABLE: u16 = 1 << 15;
+
+pub const WINDOW_OBJ_ENABLE: u8 = 1 << 4;
+pub const WINDOW_EFFECTS_ENABLE: u8 = 1 << 5;
+// Everything is visible, and colour effects are allowed, when no window is in use
+const WINDOW_ALL_ENABLED: u8 = 0x3F;
+
+impl PPU {
+    pub fn is_any_window_enabled(&self) -> bool {
+        (self.display_control & (DISPCNT_WIN0_ENABLE | DISPCNT_WIN1_ENABLE | DISPCNT_OBJ_WINDOW_ENABLE)) != 0
+    }
+
+    /// Window coordinates are inclusive of the first one and exclusive of the second one.
+    /// When the first is larger than the second the window wraps around the screen edge.
+    fn is_inside_window_range(coordinate: u16, start: u16, end: u16) -> bool {
+        if start <= end {
+            coordinate >= start && coordinate < end
+        } else {
+            coordinate >= start || coordinate < end
+        }
+    }
+
+    fn is_inside_window(&self, window: usize, x: u16, line: u16) -> bool {
+        let (left, right) = (self.window_horizontal[window] >> 8, self.window_horizontal[window] & 0xFF);
+        let (top, bottom) = (self.window_vertical[window] >> 8, self.window_vertical[window] & 0xFF);
+
+        Self::is_inside_window_range(x, left, right) && Self::is_inside_window_range(line, top, bottom)
+    }
+
+    /// Works out which window region each pixel of the line falls into, and keeps its layers and colour effect enable bits.
+    /// Priority between regions is WIN0, WIN1, OBJ window and finally the outside region.
+    pub fn compute_window_masks(&mut self) {
+        if !self.is_any_window_enabled() {
+            self.window_line.fill(WINDOW_ALL_ENABLED);
+            return;
+        }
+
+        let line = self.vcount();
+        let win0_enabled = (self.display_control & DISPCNT_WIN0_ENABLE) != 0;
+        let win1_enabled = (self.display_control & DISPCNT_WIN1_ENABLE) != 0;
+        let obj_window_enabled = (self.display_control & DISPCNT_OBJ_WINDOW_ENABLE) != 0 && self.is_obj_enabled();
+
+        for x in 0..SCREEN_WIDTH {
+            self.window_line[x] = if win0_enabled && self.is_inside_window(0, x as u16, line) {
+                self.window_inside as u8
+            } else if win1_enabled && self.is_inside_window(1, x as u16, line) {
+                (self.window_inside >> 8) as u8
+            } else if obj_window_enabled && self.obj_window_line[x] {
+                (self.window_outside >> 8) as u8
+            } else {
+                self.window_outside as u8
+            } & WINDOW_ALL_ENABLED;
+        }
+    }
+
+    pub fn is_bg_visible_in_window(&self, bg: usize, x: usize) -> bool {
+        (self.window_line[x] & (1 << bg)) != 0
+    }
+
+    pub fn is_obj_visible_in_window(&self, x: usize) -> bool {
+        (self.window_line[x] & WINDOW_OBJ_ENABLE) != 0
+    }
+
+    pub fn are_effects_enabled_in_window(&self, x: usize) -> bool {
+        (self.window_line[x] & WINDOW_EFFECTS_ENABLE) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ppu::PPU;
+
+    const VRAM_SIZE: usize = 96 * 1024;
+
+    fn setup_mode3_with_red_bg2() -> (PPU, Vec<u8>, Vec<u8>) {
+        let mut ppu = PPU::new();
+        let mut vram = vec![0u8; VRAM_SIZE];
+        let mut pal_ram = vec![0u8; 1024];
+
+        for pixel in vram[..240 * 2].chunks_mut(2) {
+            pixel[0] = 0x1F;
+        }
+
+        pal_ram[0] = 0xE0; // Backdrop: green-ish
+
+        ppu.write8(0x000, 3);
+
+        (ppu, vram, pal_ram)
+    }
+
+    #[test]
+    fn win0_hides_bg_outside_its_rectangle() {
+        let (mut ppu, vram, pal_ram) = setup_mode3_with_red_bg2();
+
+        ppu.write8(0x001, 0x24); // BG2 and WIN0 on
+        ppu.write8(0x040, 20); // WIN0H: X2
+        ppu.write8(0x041, 10); // WIN0H: X1
+        ppu.write8(0x044, 160); // WIN0V: Y2
+        ppu.write8(0x045, 0); // WIN0V: Y1
+        ppu.write8(0x048, 0x04); // WININ: WIN0 shows BG2
+        ppu.write8(0x04A, 0x00); // WINOUT: nothing outside
+
+        ppu.render_scanline(&vram, &pal_ram, &[0; 1024]);
+
+        assert_eq!(ppu.framebuffer()[9], 0x00E0);
+        assert_eq!(ppu.framebuffer()[10], 0x001F);
+        assert_eq!(ppu.framebuffer()[19], 0x001F);
+        assert_eq!(ppu.framebuffer()[20], 0x00E0);
+    }
+
+    #[test]
+    fn inverted_window_wraps_around() {
+        let (mut ppu, vram, pal_ram) = setup_mode3_with_red_bg2();
+
+        ppu.write8(0x001, 0x44); // BG2 and WIN1 on
+        ppu.write8(0x042, 10); // WIN1H: X2 < X1
+        ppu.write8(0x043, 200);
+        ppu.write8(0x046, 160);
+        ppu.write8(0x047, 0);
+        ppu.write8(0x049, 0x04); // WININ: WIN1 shows BG2
+
+        ppu.render_scanline(&vram, &pal_ram, &[0; 1024]);
+
+        assert_eq!(ppu.framebuffer()[5], 0x001F);
+        assert_eq!(ppu.framebuffer()[100], 0x00E0);
+        assert_eq!(ppu.framebuffer()[220], 0x001F);
+    }
+}
@@ -0,0 +1,244 @@
+use std::f64::consts::PI;
+
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+
+// Windowed sinc kernel, covering BASE_TAPS input frames around each output frame when upsampling.
+// Downsampling stretches it, so that it keeps the same shape at the output rate.
+const BASE_TAPS: usize = 16;
+// Kernel positions precomputed between two input frames, others are interpolated
+const PHASES: usize = 256;
+
+/// Converts a stereo stream to another sample rate with a Blackman windowed sinc filter, which
+/// also acts as the anti-aliasing low-pass filter when downsampling.
+/// The input rate may change on the fly (e.g. when SOUNDBIAS selects another sampling cycle).
+pub struct Resampler {
+    output_rate: u32,
+    // Input rate the kernel was computed for
+    input_rate: u32,
+    taps: usize,
+    kernel: Vec<f32>,
+
+    // Last input frames, stored twice so that the `taps` ones ending at `history_index` are contiguous
+    history: Vec<[f32; 2]>,
+    history_index: usize,
+    // Position of the next output frame after the center of the history, in [0, 1)
+    position: f64,
+
+    // Interleaved stereo output, between -1.0 and 1.0
+    output: Vec<f32>
+}
+
+impl Resampler {
+    pub fn new(output_rate: u32) -> Self {
+        Resampler {
+            output_rate,
+            input_rate: 0,
+            taps: 0,
+            kernel: Vec::new(),
+            history: Vec::new(),
+            history_index: 0,
+            position: 0.0,
+            output: Vec::new()
+        }
+    }
+
+    pub fn output_rate(&self) -> u32 {
+        self.output_rate
+    }
+
+    /// `output_rate` can't be 0, `GBA::set_audio_sample_rate` rejects it before it gets here.
+    pub fn set_output_rate(&mut self, output_rate: u32) {
+        self.output_rate = output_rate;
+        // Forces the kernel to be computed again with the new cutoff
+        self.input_rate = 0;
+    }
+
+    fn blackman_window(x: f64, half_width: f64) -> f64 {
+        let ratio = x / half_width;
+        0.42 + 0.5 * (PI * ratio).cos() + 0.08 * (2.0 * PI * ratio).cos()
+    }
+
+    fn sinc(x: f64) -> f64 {
+        if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) }
+    }
+
+    /// Kernel weights for every phase, each row being normalized so that it keeps DC levels as they are.
+    fn compute_kernel(&mut self) {
+        // The cutoff follows the lowest of both Nyquist frequencies
+        let cutoff = (self.output_rate as f64 / self.input_rate as f64).min(1.0);
+        let taps = ((BASE_TAPS as f64 / cutoff).ceil() as usize + 1) & !1;
+        let half_width = (taps / 2) as f64;
+
+        if taps != self.taps {
+            self.taps = taps;
+            self.history = vec![[0.0; 2]; 2 * taps];
+            self.history_index = 0;
+        }
+
+        self.kernel = Vec::with_capacity((PHASES + 1) * taps);
+
+        for phase in 0..=PHASES {
+            let position = phase as f64 / PHASES as f64;
+            let weights: Vec<f64> = (0..taps)
+                .map(|tap| {
+                    // Distance from the output frame to the input frame, the newest one being the last tap
+                    let distance = position + half_width - 1.0 - tap as f64;
+
+                    if distance.abs() >= half_width {
+                        0.0
+                    } else {
+                        cutoff * Self::sinc(cutoff * distance) * Self::blackman_window(distance, half_width)
+                    }
+                })
+                .collect();
+            let sum: f64 = weights.iter().sum();
+
+            self.kernel.extend(weights.iter().map(|weight| (weight / sum) as f32));
+        }
+    }
+
+    fn interpolate(&self) -> [f32; 2] {
+        let phase_position = self.position * PHASES as f64;
+        let phase = phase_position as usize;
+        let blend = (phase_position - phase as f64) as f32;
+
+        let taps = self.taps;
+        let row = &self.kernel[phase * taps..(phase + 1) * taps];
+        let next_row = &self.kernel[(phase + 1) * taps..(phase + 2) * taps];
+        let history = &self.history[self.history_index + 1..self.history_index + 1 + taps];
+
+        let mut frame = [0.0; 2];
+
+        for (tap, input_frame) in history.iter().enumerate() {
+            let weight = row[tap] + (next_row[tap] - row[tap]) * blend;
+
+            frame[0] += input_frame[0] * weight;
+            frame[1] += input_frame[1] * weight;
+        }
+
+        frame
+    }
+
+    pub fn push(&mut self, frame: [i16; 2], input_rate: u32) {
+        if input_rate != self.input_rate {
+            self.input_rate = input_rate;
+            self.compute_kernel();
+        }
+
+        let frame = frame.map(|sample| sample as f32 / 32768.0);
+        self.history_index = (self.history_index + 1) % self.taps;
+        self.history[self.history_index] = frame;
+        self.history[self.history_index + self.taps] = frame;
+
+        let step = input_rate as f64 / self.output_rate as f64;
+
+        while self.position < 1.0 {
+            let output_frame = self.interpolate();
+            self.output.extend(output_frame.map(|sample| sample.clamp(-1.0, 1.0)));
+
+            self.position += step;
+        }
+
+        self.position -= 1.0;
+    }
+
+    pub fn output(&self) -> &[f32] {
+        &self.output
+    }
+
+    pub fn clear_output(&mut self) {
+        self.output.clear();
+    }
+}
+
+// The output rate is a host setting: states keep the input side only, so that they can be loaded
+// whatever the output rate is
+impl SaveState for Resampler {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u32(self.input_rate);
+
+        // Input frames the kernel currently covers, oldest first
+        writer.write_usize(self.taps);
+        for frame in self.history.iter().skip(self.history_index + 1).take(self.taps) {
+            writer.write_f32(frame[0]);
+            writer.write_f32(frame[1]);
+        }
+
+        writer.write_f64(self.position);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.input_rate = reader.read_u32()?;
+
+        let frame_count = reader.read_usize()?;
+        let mut frames = Vec::new();
+        for _ in 0..frame_count {
+            frames.push([reader.read_f32()?, reader.read_f32()?]);
+        }
+
+        self.position = reader.read_f64()?;
+
+        if !self.position.is_finite() || (self.input_rate == 0 && frame_count != 0) {
+            return Err(SaveStateError::InvalidValue);
+        }
+
+        // The kernel is computed on the first input otherwise
+        self.taps = 0;
+        self.kernel.clear();
+        self.history.clear();
+        self.history_index = 0;
+
+        if self.input_rate == 0 {
+            return Ok(());
+        }
+
+        // The number of taps follows the output rate, so the oldest frames may not fit or be missing
+        self.compute_kernel();
+        self.history = vec![[0.0; 2]; 2 * self.taps];
+
+        let kept_frames = &frames[frame_count.saturating_sub(self.taps)..];
+        let first_frame = self.taps - kept_frames.len();
+
+        for (frame_index, &frame) in kept_frames.iter().enumerate() {
+            // With the newest frame at index 0, the oldest one comes right after it
+            let index = (first_frame + frame_index + 1) % self.taps;
+            self.history[index] = frame;
+            self.history[index + self.taps] = frame;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_frame_count_follows_rate_ratio() {
+        let mut resampler = Resampler::new(48000);
+
+        for _ in 0..32768 {
+            resampler.push([16384, -16384], 32768);
+        }
+
+        let output = resampler.output();
+        assert!((output.len() as i32 / 2 - 48000).abs() <= 1);
+        assert!((output[output.len() - 2] - 0.5).abs() < 1e-4);
+        assert!((output[output.len() - 1] + 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn downsampling_filters_out_frequencies_above_nyquist() {
+        let mut resampler = Resampler::new(32768);
+
+        // 100 kHz tone sampled at 262144 Hz, way above the 16384 Hz output Nyquist frequency
+        for index in 0..8192 {
+            let sample = (2.0 * PI * 100_000.0 * index as f64 / 262_144.0).sin() * 16384.0;
+            resampler.push([sample as i16; 2], 262_144);
+        }
+
+        let peak = resampler.output()[64..].iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        assert!(peak < 0.01);
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+// A full snapshot is taken once per second of emulation, the frames in between only keep what
+// changed since then
+const KEYFRAME_INTERVAL_FRAMES: usize = 60;
+
+/// XORs `state` with `base` (zero-padded to the same length) and run-length encodes the result.
+/// The output is the state length, followed by pairs of a count of unchanged bytes and a count of
+/// changed bytes, the changed bytes themselves coming after the second count.
+pub fn encode_delta(base: &[u8], state: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    write_varint(&mut delta, state.len());
+
+    let xor_at = |index: usize| state[index] ^ base.get(index).copied().unwrap_or(0);
+    let mut index = 0;
+
+    while index < state.len() {
+        let unchanged_start = index;
+        while index < state.len() && xor_at(index) == 0 {
+            index += 1;
+        }
+
+        let changed_start = index;
+        while index < state.len() && xor_at(index) != 0 {
+            index += 1;
+        }
+
+        write_varint(&mut delta, changed_start - unchanged_start);
+        write_varint(&mut delta, index - changed_start);
+        delta.extend((changed_start..index).map(xor_at));
+    }
+
+    delta
+}
+
+/// Rebuilds the state encoded by `encode_delta` against the same `base`.
+pub fn decode_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut position = 0;
+    let length = read_varint(delta, &mut position);
+
+    let mut state = vec![0; length];
+    let common_length = length.min(base.len());
+    state[..common_length].copy_from_slice(&base[..common_length]);
+
+    let mut index = 0;
+
+    while position < delta.len() {
+        index += read_varint(delta, &mut position);
+        let changed_length = read_varint(delta, &mut position);
+
+        for (byte, xor) in state[index..index + changed_length].iter_mut().zip(&delta[position..]) {
+            *byte ^= xor;
+        }
+
+        index += changed_length;
+        position += changed_length;
+    }
+
+    state
+}
+
+// LEB128, 7 bits at a time starting with the lowest ones
+fn write_varint(output: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        output.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+
+    output.push(value as u8);
+}
+
+fn read_varint(input: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = input[*position];
+        *position += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+
+        if (byte & 0x80) == 0 {
+            return value;
+        }
+    }
+}
+
+struct Snapshot {
+    // Encoded against an empty state, shared by all the snapshots taken until the next keyframe
+    keyframe: Rc<Vec<u8>>,
+    delta: Vec<u8>
+}
+
+/// Save states of the last frames, oldest first. Each one is stored as a delta against the last
+/// keyframe, and no more than `depth` of them are kept.
+pub struct RewindBuffer {
+    depth: usize,
+    snapshots: VecDeque<Snapshot>,
+
+    // Decoded copy of the keyframe the next deltas are taken against
+    keyframe: Vec<u8>,
+    encoded_keyframe: Option<Rc<Vec<u8>>>,
+    frames_since_keyframe: usize
+}
+
+impl RewindBuffer {
+    /// A depth of 0 disables the buffer.
+    pub fn new(depth: usize) -> Self {
+        RewindBuffer {
+            depth,
+            snapshots: VecDeque::with_capacity(depth),
+            keyframe: Vec::new(),
+            encoded_keyframe: None,
+            frames_since_keyframe: 0
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.keyframe = Vec::new();
+        self.encoded_keyframe = None;
+    }
+
+    pub fn push(&mut self, state: &[u8]) {
+        if self.depth == 0 {
+            return;
+        }
+
+        let encoded_keyframe = match &self.encoded_keyframe {
+            Some(encoded_keyframe) if self.frames_since_keyframe < KEYFRAME_INTERVAL_FRAMES => encoded_keyframe.clone(),
+            _ => {
+                let encoded_keyframe = Rc::new(encode_delta(&[], state));
+                self.keyframe = state.to_vec();
+                self.encoded_keyframe = Some(encoded_keyframe.clone());
+                self.frames_since_keyframe = 0;
+                encoded_keyframe
+            }
+        };
+
+        self.snapshots.push_back(Snapshot {
+            keyframe: encoded_keyframe,
+            delta: encode_delta(&self.keyframe, state)
+        });
+        self.frames_since_keyframe += 1;
+
+        while self.snapshots.len() > self.depth {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// Drops the `frames` most recent snapshots, always keeping the oldest one, and returns the
+    /// state that is now the latest along with the number of snapshots dropped.
+    pub fn rewind(&mut self, frames: usize) -> Option<(Vec<u8>, usize)> {
+        let frames = frames.min(self.snapshots.len().checked_sub(1)?);
+        self.snapshots.truncate(self.snapshots.len() - frames);
+
+        // The decoded keyframe may now be newer than every remaining snapshot
+        self.encoded_keyframe = None;
+
+        let snapshot = self.snapshots.back()?;
+        let keyframe = decode_delta(&[], &snapshot.keyframe);
+
+        Some((decode_delta(&keyframe, &snapshot.delta), frames))
+    }
+
+    /// Bytes taken by the stored snapshots and keyframes.
+    #[cfg(test)]
+    pub fn memory_usage(&self) -> usize {
+        let mut memory_usage = self.keyframe.len();
+        let mut previous_keyframe: Option<&Rc<Vec<u8>>> = None;
+
+        for snapshot in self.snapshots.iter() {
+            if !previous_keyframe.is_some_and(|keyframe| Rc::ptr_eq(keyframe, &snapshot.keyframe)) {
+                memory_usage += snapshot.keyframe.len();
+            }
+
+            memory_usage += snapshot.delta.len();
+            previous_keyframe = Some(&snapshot.keyframe);
+        }
+
+        memory_usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deltas_rebuild_states_of_any_length() {
+        let base: Vec<u8> = (0..1000).map(|i| (i * 13) as u8).collect();
+
+        let mut state = base.clone();
+        state[10] ^= 0xFF;
+        state[500..520].fill(0);
+        state.extend_from_slice(&[1, 2, 3]);
+
+        let delta = encode_delta(&base, &state);
+        assert!(delta.len() < 64);
+        assert_eq!(decode_delta(&base, &delta), state);
+
+        assert_eq!(decode_delta(&base, &encode_delta(&base, &base[..300])), &base[..300]);
+        assert_eq!(decode_delta(&[], &encode_delta(&[], &base)), base);
+    }
+
+    #[test]
+    fn rewind_returns_older_states_up_to_the_depth() {
+        let mut buffer = RewindBuffer::new(100);
+        let state = |frame: usize| {
+            let mut state = vec![0u8; 4096];
+            state[frame % 4096] = frame as u8 | 1;
+            state[..8].copy_from_slice(&frame.to_le_bytes());
+            state
+        };
+
+        for frame in 0..250 {
+            buffer.push(&state(frame));
+        }
+
+        assert_eq!(buffer.len(), 100);
+        assert!(buffer.memory_usage() < 100 * 4096 / 4);
+
+        assert_eq!(buffer.rewind(0), Some((state(249), 0)));
+        assert_eq!(buffer.rewind(5), Some((state(244), 5)));
+
+        // Snapshots taken after a rewind follow the remaining ones
+        buffer.push(&state(1000));
+        assert_eq!(buffer.rewind(1), Some((state(244), 1)));
+
+        assert_eq!(buffer.rewind(1000), Some((state(150), 94)));
+        assert_eq!(buffer.len(), 1);
+
+        buffer.clear();
+        assert_eq!(buffer.rewind(1), None);
+    }
+}
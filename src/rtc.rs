@@ -0,0 +1,466 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+
+const CYCLES_PER_SECOND: u64 = 1 << 24;
+
+// Serial lines, as GPIO pins
+const PIN_SCK: u8 = 1 << 0;
+const PIN_SIO: u8 = 1 << 1;
+const PIN_CS: u8 = 1 << 2;
+
+// Command bytes are 0110 followed by the command and the read bit
+const COMMAND_MAGIC: u8 = 0b0110;
+const COMMAND_RESET: u8 = 0;
+const COMMAND_STATUS: u8 = 1;
+const COMMAND_DATE_TIME: u8 = 2;
+const COMMAND_TIME: u8 = 3;
+const COMMAND_ALARM: u8 = 4;
+
+const STATUS_24_HOUR: u8 = 1 << 6;
+// Per-minute interrupt, alarm interrupt enable and 24 hour mode are the writable bits
+const STATUS_WRITABLE_BITS: u8 = 0b0110_1010;
+
+const HOUR_PM: u8 = 1 << 7;
+
+// The chip counts years from 2000 to 2099
+const FIRST_YEAR: i64 = 2000;
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Where the RTC gets the time from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RtcClock {
+    /// UTC time of the host system
+    Host,
+    /// Starts at the given Unix time and follows the emulated time, for reproducible runs
+    Fixed(u64)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum TransferState {
+    // Waiting for CS to go high
+    Idle,
+    Command,
+    Write,
+    Read,
+    // The transfer is over until CS goes low
+    Done
+}
+
+// Bytes sent or received after the command byte, 0 for the commands without parameters
+fn command_length(command: u8) -> usize {
+    match command {
+        COMMAND_STATUS => 1,
+        COMMAND_DATE_TIME => 7,
+        COMMAND_TIME => 3,
+        COMMAND_ALARM => 2,
+        _ => 0
+    }
+}
+
+// Days since 1970-01-01 of a date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+fn to_bcd(value: i64) -> u8 {
+    (((value / 10) << 4) | (value % 10)) as u8
+}
+
+fn from_bcd(value: u8) -> i64 {
+    ((value >> 4) * 10 + (value & 0xF)) as i64
+}
+
+/// Seiko S-3511 real-time clock, talking over a 3-wire serial bus on the GPIO port. Command bytes
+/// are sent MSB first and parameters LSB first.
+pub struct Rtc {
+    clock: RtcClock,
+    // Seconds added to the clock, set when a game writes the time
+    offset_seconds: i64,
+
+    status: u8,
+    // Hour and minute of the alarm. The interrupt output isn't connected to anything
+    alarm: [u8; 2],
+
+    state: TransferState,
+    previous_pins: u8,
+    command: u8,
+    shift: u8,
+    bits: u32,
+    buffer: [u8; 7],
+    byte_index: usize,
+    length: usize,
+    output_bit: u8
+}
+
+impl Rtc {
+    pub fn new(clock: RtcClock) -> Self {
+        Rtc {
+            clock,
+            offset_seconds: 0,
+            status: STATUS_24_HOUR,
+            alarm: [0; 2],
+            state: TransferState::Idle,
+            previous_pins: 0,
+            command: 0,
+            shift: 0,
+            bits: 0,
+            buffer: [0; 7],
+            byte_index: 0,
+            length: 0,
+            output_bit: 0
+        }
+    }
+
+    pub fn set_clock(&mut self, clock: RtcClock) {
+        self.clock = clock;
+        self.offset_seconds = 0;
+    }
+
+    fn clock_seconds(&self, timestamp: u64) -> i64 {
+        match self.clock {
+            RtcClock::Host => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs() as i64),
+            RtcClock::Fixed(start) => (start + timestamp / CYCLES_PER_SECOND) as i64
+        }
+    }
+
+    /// Year, month, day, day of week, hour, minute and second, as the chip reports them.
+    pub fn date_time(&self, timestamp: u64) -> [u8; 7] {
+        let seconds = self.clock_seconds(timestamp) + self.offset_seconds;
+        let days = seconds.div_euclid(SECONDS_PER_DAY);
+        let time_of_day = seconds.rem_euclid(SECONDS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+
+        let hour = time_of_day / 3600;
+        let displayed_hour = if (self.status & STATUS_24_HOUR) != 0 { hour } else { hour % 12 };
+        let pm_flag = if hour >= 12 { HOUR_PM } else { 0 };
+
+        [
+            to_bcd((year - FIRST_YEAR).rem_euclid(100)),
+            to_bcd(month),
+            to_bcd(day),
+            // 1970-01-01 was a Thursday, 0 is Sunday
+            to_bcd((days + 4).rem_euclid(7)),
+            to_bcd(displayed_hour) | pm_flag,
+            to_bcd(time_of_day / 60 % 60),
+            to_bcd(time_of_day % 60)
+        ]
+    }
+
+    fn set_date_time(&mut self, timestamp: u64, date: Option<[u8; 3]>, time: [u8; 3]) {
+        let current = self.date_time(timestamp);
+        let [year, month, day] = date.unwrap_or([current[0], current[1], current[2]]);
+
+        let mut hour = from_bcd(time[0] & 0x3F);
+        if (self.status & STATUS_24_HOUR) == 0 && (time[0] & HOUR_PM) != 0 {
+            hour += 12;
+        }
+
+        let days = days_from_civil(FIRST_YEAR + from_bcd(year), from_bcd(month & 0x1F), from_bcd(day & 0x3F));
+        let seconds = days * SECONDS_PER_DAY + hour * 3600 + from_bcd(time[1] & 0x7F) * 60 + from_bcd(time[2] & 0x7F);
+
+        self.offset_seconds = seconds - self.clock_seconds(timestamp);
+    }
+
+    /// Level of the SIO pin driven by the RTC.
+    pub fn read_pins(&self) -> u8 {
+        if self.state == TransferState::Read { self.output_bit * PIN_SIO } else { 0 }
+    }
+
+    pub fn write_pins(&mut self, pins: u8, timestamp: u64) {
+        let previous_pins = std::mem::replace(&mut self.previous_pins, pins);
+
+        if (pins & PIN_CS) == 0 {
+            self.state = TransferState::Idle;
+            return;
+        }
+
+        if self.state == TransferState::Idle {
+            self.state = TransferState::Command;
+            self.shift = 0;
+            self.bits = 0;
+        }
+
+        let sck_rising = (previous_pins & PIN_SCK) == 0 && (pins & PIN_SCK) != 0;
+        let sck_falling = (previous_pins & PIN_SCK) != 0 && (pins & PIN_SCK) == 0;
+        let sio = (pins & PIN_SIO) >> 1;
+
+        match self.state {
+            TransferState::Command if sck_rising => {
+                self.shift = (self.shift << 1) | sio;
+                self.bits += 1;
+
+                if self.bits == 8 {
+                    self.run_command(self.shift, timestamp);
+                }
+            },
+            TransferState::Write if sck_rising => {
+                self.shift |= sio << self.bits;
+                self.bits += 1;
+
+                if self.bits == 8 {
+                    self.buffer[self.byte_index] = self.shift;
+                    self.byte_index += 1;
+                    self.shift = 0;
+                    self.bits = 0;
+
+                    if self.byte_index == self.length {
+                        self.finish_write(timestamp);
+                        self.state = TransferState::Done;
+                    }
+                }
+            },
+            // The next bit is put on SIO while SCK is low, and read by the game once it's high again
+            TransferState::Read if sck_falling => {
+                if self.byte_index == self.length {
+                    self.state = TransferState::Done;
+                    return;
+                }
+
+                self.output_bit = (self.buffer[self.byte_index] >> self.bits) & 1;
+                self.bits += 1;
+
+                if self.bits == 8 {
+                    self.bits = 0;
+                    self.byte_index += 1;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn run_command(&mut self, command_byte: u8, timestamp: u64) {
+        self.bits = 0;
+        self.shift = 0;
+        self.byte_index = 0;
+        self.state = TransferState::Done;
+
+        if (command_byte >> 4) != COMMAND_MAGIC {
+            return;
+        }
+
+        self.command = (command_byte >> 1) & 7;
+        let is_read = (command_byte & 1) != 0;
+
+        self.length = command_length(self.command);
+
+        if self.command == COMMAND_RESET {
+            self.status = 0;
+            self.alarm = [0; 2];
+            self.offset_seconds = 0;
+        } else if self.length == 0 {
+            // Unsupported command
+        } else if is_read {
+            let date_time = self.date_time(timestamp);
+
+            match self.command {
+                COMMAND_STATUS => self.buffer[0] = self.status,
+                COMMAND_DATE_TIME => self.buffer = date_time,
+                COMMAND_TIME => self.buffer[..3].copy_from_slice(&date_time[4..]),
+                _ => self.buffer[..2].copy_from_slice(&self.alarm)
+            }
+
+            self.state = TransferState::Read;
+        } else {
+            self.state = TransferState::Write;
+        }
+    }
+
+    fn finish_write(&mut self, timestamp: u64) {
+        let buffer = self.buffer;
+
+        match self.command {
+            COMMAND_STATUS => self.status = buffer[0] & STATUS_WRITABLE_BITS,
+            COMMAND_DATE_TIME => self.set_date_time(timestamp, Some([buffer[0], buffer[1], buffer[2]]), [buffer[4], buffer[5], buffer[6]]),
+            COMMAND_TIME => self.set_date_time(timestamp, None, [buffer[0], buffer[1], buffer[2]]),
+            _ => self.alarm = [buffer[0], buffer[1]]
+        }
+    }
+}
+
+impl SaveState for Rtc {
+    // The clock source is a setting of the host, only the time offset set by the game is saved
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_i64(self.offset_seconds);
+        writer.write_u8(self.status);
+        writer.write_bytes(&self.alarm);
+
+        writer.write_u8(self.state as u8);
+        writer.write_u8(self.previous_pins);
+        writer.write_u8(self.command);
+        writer.write_u8(self.shift);
+        writer.write_u32(self.bits);
+        writer.write_bytes(&self.buffer);
+        writer.write_usize(self.byte_index);
+        writer.write_usize(self.length);
+        writer.write_u8(self.output_bit);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.offset_seconds = reader.read_i64()?;
+        self.status = reader.read_u8()?;
+        reader.read_into(&mut self.alarm)?;
+
+        self.state = match reader.read_u8()? {
+            0 => TransferState::Idle,
+            1 => TransferState::Command,
+            2 => TransferState::Write,
+            3 => TransferState::Read,
+            4 => TransferState::Done,
+            _ => return Err(SaveStateError::InvalidValue)
+        };
+        self.previous_pins = reader.read_u8()?;
+        self.command = reader.read_u8()?;
+        self.shift = reader.read_u8()?;
+        self.bits = reader.read_u32()? & 7;
+        reader.read_into(&mut self.buffer)?;
+        let byte_index = reader.read_usize()?;
+        self.length = reader.read_index(self.buffer.len() + 1)?;
+        self.output_bit = reader.read_u8()? & 1;
+
+        // A transfer goes through the bytes of its command, and a write is over once the last one is in
+        let transfer_valid = match self.state {
+            TransferState::Write => self.length == command_length(self.command) && byte_index < self.length,
+            TransferState::Read => self.length == command_length(self.command) && self.length != 0 && byte_index <= self.length,
+            _ => byte_index <= self.length
+        };
+
+        if !transfer_valid {
+            return Err(SaveStateError::InvalidValue);
+        }
+
+        self.byte_index = byte_index;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2004-06-15 13:45:30 UTC, a Tuesday
+    const TEST_TIME: u64 = 1_087_307_130;
+
+    fn send_byte(rtc: &mut Rtc, byte: u8, msb_first: bool) {
+        for index in 0..8 {
+            let bit = if msb_first { (byte >> (7 - index)) & 1 } else { (byte >> index) & 1 };
+            rtc.write_pins(PIN_CS | (bit << 1), 0);
+            rtc.write_pins(PIN_CS | PIN_SCK | (bit << 1), 0);
+        }
+    }
+
+    fn receive_bytes(rtc: &mut Rtc, output: &mut [u8], timestamp: u64) {
+        for byte in output.iter_mut() {
+            for index in 0..8 {
+                rtc.write_pins(PIN_CS, timestamp);
+                rtc.write_pins(PIN_CS | PIN_SCK, timestamp);
+                *byte |= (rtc.read_pins() >> 1) << index;
+            }
+        }
+    }
+
+    fn start_transfer(rtc: &mut Rtc) {
+        rtc.write_pins(PIN_SCK, 0);
+        rtc.write_pins(PIN_SCK | PIN_CS, 0);
+    }
+
+    #[test]
+    fn date_conversions_round_trip() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(days_from_civil(2024, 2, 29)), (2024, 2, 29));
+        assert_eq!(to_bcd(59), 0x59);
+        assert_eq!(from_bcd(0x23), 23);
+    }
+
+    #[test]
+    fn date_time_is_read_from_the_fixed_clock() {
+        let mut rtc = Rtc::new(RtcClock::Fixed(TEST_TIME));
+
+        start_transfer(&mut rtc);
+        send_byte(&mut rtc, 0x65, true);
+
+        let mut date_time = [0; 7];
+        receive_bytes(&mut rtc, &mut date_time, 0);
+
+        assert_eq!(date_time, [0x04, 0x06, 0x15, 0x02, 0x13 | HOUR_PM, 0x45, 0x30]);
+        // Follows the emulated time
+        assert_eq!(rtc.date_time(2 * CYCLES_PER_SECOND)[6], 0x32);
+    }
+
+    #[test]
+    fn written_time_and_status_are_kept() {
+        let mut rtc = Rtc::new(RtcClock::Fixed(TEST_TIME));
+
+        start_transfer(&mut rtc);
+        send_byte(&mut rtc, 0x66, true);
+        for byte in [0x08, 0x30, 0x00] {
+            send_byte(&mut rtc, byte, false);
+        }
+
+        start_transfer(&mut rtc);
+        send_byte(&mut rtc, 0x62, true);
+        send_byte(&mut rtc, 0xFF, false);
+
+        assert_eq!(rtc.status, STATUS_WRITABLE_BITS);
+        assert_eq!(rtc.date_time(0)[..], [0x04, 0x06, 0x15, 0x02, 0x08, 0x30, 0x00]);
+
+        start_transfer(&mut rtc);
+        send_byte(&mut rtc, 0x60, true);
+        assert_eq!(rtc.status, 0);
+        assert_eq!(rtc.date_time(0)[4], 0x01 | HOUR_PM);
+    }
+
+    #[test]
+    fn save_states_with_impossible_transfers_are_rejected() {
+        let mut rtc = Rtc::new(RtcClock::Fixed(TEST_TIME));
+        let load = |rtc: &Rtc| {
+            let mut writer = StateWriter::new();
+            rtc.save_state(&mut writer);
+            let data = writer.finish();
+
+            Rtc::new(RtcClock::Host).load_state(&mut StateReader::new(&data).unwrap())
+        };
+
+        // In the middle of writing the date and time
+        start_transfer(&mut rtc);
+        send_byte(&mut rtc, 0x64, true);
+        send_byte(&mut rtc, 0x04, false);
+        assert_eq!((rtc.state, rtc.byte_index), (TransferState::Write, 1));
+        assert_eq!(load(&rtc), Ok(()));
+
+        rtc.byte_index = 7;
+        assert_eq!(load(&rtc), Err(SaveStateError::InvalidValue));
+
+        rtc.byte_index = 2;
+        rtc.length = 1;
+        assert_eq!(load(&rtc), Err(SaveStateError::InvalidValue));
+
+        // Reading the status with the length of the date and time
+        rtc.state = TransferState::Read;
+        rtc.command = COMMAND_STATUS;
+        rtc.byte_index = 0;
+        rtc.length = 7;
+        assert_eq!(load(&rtc), Err(SaveStateError::InvalidValue));
+    }
+}
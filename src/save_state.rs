@@ -0,0 +1,253 @@
+use std::error::Error;
+use std::fmt;
+
+const SAVE_STATE_MAGIC: [u8; 4] = *b"FBAS";
+/// Bumped whenever the layout of the saved state changes.
+pub const SAVE_STATE_VERSION: u32 = 1;
+
+/// Reasons a save state can't be loaded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SaveStateError {
+    /// Not a save state at all
+    InvalidFormat,
+    /// Saved by another version of the emulator
+    UnsupportedVersion {
+        /// Version of the save state
+        found: u32,
+        /// Version this build reads, `SAVE_STATE_VERSION`
+        expected: u32
+    },
+    /// Saved with another game, or another save memory type
+    CartridgeMismatch,
+    /// Ends before all of the machine was restored
+    Truncated,
+    /// A field holds a value the emulator can't be in, the state is corrupted
+    InvalidValue
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveStateError::InvalidFormat => write!(formatter, "not a save state"),
+            SaveStateError::UnsupportedVersion { found, expected } => {
+                write!(formatter, "save state version {} is not supported, expected version {}", found, expected)
+            },
+            SaveStateError::CartridgeMismatch => write!(formatter, "save state was made with another cartridge"),
+            SaveStateError::Truncated => write!(formatter, "save state is truncated"),
+            SaveStateError::InvalidValue => write!(formatter, "save state is corrupted")
+        }
+    }
+}
+
+impl Error for SaveStateError {}
+
+/// Serializes the machine state as little-endian values, one after the other.
+pub struct StateWriter {
+    data: Vec<u8>
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut writer = StateWriter { data: Vec::new() };
+
+        writer.write_bytes(&SAVE_STATE_MAGIC);
+        writer.write_u32(SAVE_STATE_VERSION);
+
+        writer
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_i32(&mut self, value: i32) {
+        self.write_u32(value as u32);
+    }
+
+    pub fn write_i64(&mut self, value: i64) {
+        self.write_u64(value as u64);
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.write_u32(value.to_bits());
+    }
+
+    pub fn write_f64(&mut self, value: f64) {
+        self.write_u64(value.to_bits());
+    }
+
+    // Indices and lengths, which always fit in 32 bits
+    pub fn write_usize(&mut self, value: usize) {
+        self.write_u32(value as u32);
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads back what a `StateWriter` wrote, in the same order.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl<'a> StateReader<'a> {
+    /// Checks the header of `data`, the reader then starts with the first saved value.
+    pub fn new(data: &'a [u8]) -> Result<Self, SaveStateError> {
+        let mut reader = StateReader { data, position: 0 };
+
+        if reader.read_bytes(SAVE_STATE_MAGIC.len()).ok() != Some(&SAVE_STATE_MAGIC[..]) {
+            return Err(SaveStateError::InvalidFormat);
+        }
+
+        let version = reader.read_u32()?;
+
+        if version != SAVE_STATE_VERSION {
+            return Err(SaveStateError::UnsupportedVersion { found: version, expected: SAVE_STATE_VERSION });
+        }
+
+        Ok(reader)
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.position == self.data.len()
+    }
+
+    pub fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], SaveStateError> {
+        let bytes = self.data.get(self.position..self.position + length).ok_or(SaveStateError::Truncated)?;
+        self.position += length;
+
+        Ok(bytes)
+    }
+
+    pub fn read_into(&mut self, destination: &mut [u8]) -> Result<(), SaveStateError> {
+        destination.copy_from_slice(self.read_bytes(destination.len())?);
+        Ok(())
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], SaveStateError> {
+        let mut bytes = [0; N];
+        self.read_into(&mut bytes)?;
+
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SaveStateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SaveStateError::InvalidValue)
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, SaveStateError> {
+        Ok(u16::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, SaveStateError> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SaveStateError> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_i32(&mut self) -> Result<i32, SaveStateError> {
+        Ok(self.read_u32()? as i32)
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64, SaveStateError> {
+        Ok(self.read_u64()? as i64)
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, SaveStateError> {
+        Ok(f32::from_bits(self.read_u32()?))
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64, SaveStateError> {
+        Ok(f64::from_bits(self.read_u64()?))
+    }
+
+    pub fn read_usize(&mut self) -> Result<usize, SaveStateError> {
+        Ok(self.read_u32()? as usize)
+    }
+
+    /// Reads an index, failing when it isn't below `limit`.
+    pub fn read_index(&mut self, limit: usize) -> Result<usize, SaveStateError> {
+        let index = self.read_usize()?;
+        if index < limit { Ok(index) } else { Err(SaveStateError::InvalidValue) }
+    }
+}
+
+/// Implemented by every component holding a part of the machine state.
+pub trait SaveState {
+    fn save_state(&self, writer: &mut StateWriter);
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_read_back_in_order() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0x12);
+        writer.write_bool(true);
+        writer.write_u64(u64::MAX - 1);
+        writer.write_f32(-0.5);
+        let data = writer.finish();
+
+        let mut reader = StateReader::new(&data).unwrap();
+        assert_eq!(reader.read_u8(), Ok(0x12));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u64(), Ok(u64::MAX - 1));
+        assert_eq!(reader.read_f32(), Ok(-0.5));
+        assert!(reader.is_at_end());
+        assert_eq!(reader.read_u8(), Err(SaveStateError::Truncated));
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut data = StateWriter::new().finish();
+        data[4..8].copy_from_slice(&(SAVE_STATE_VERSION + 1).to_le_bytes());
+
+        assert_eq!(
+            StateReader::new(&data).err(),
+            Some(SaveStateError::UnsupportedVersion { found: SAVE_STATE_VERSION + 1, expected: SAVE_STATE_VERSION })
+        );
+        assert_eq!(StateReader::new(b"junk").err(), Some(SaveStateError::InvalidFormat));
+    }
+}
@@ -0,0 +1,191 @@
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EventType {
+    FrameEnd,
+    HBlankStart,
+    HDrawStart,
+    DmaStart(usize),
+    TimerOverflow(usize),
+    ApuSample,
+    ApuFrameSequencer
+}
+
+#[derive(Clone, Copy)]
+struct Event {
+    timestamp: u64,
+    event_type: EventType
+}
+
+/// Timestamp-ordered queue of the future events registered by the system components.
+/// The CPU runs until the next event is due instead of polling every peripheral each step.
+pub struct Scheduler {
+    timestamp: u64,
+    events: Vec<Event> // Kept sorted by timestamp, events with the same timestamp fire in insertion order
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler {
+            timestamp: 0,
+            events: Vec::with_capacity(16)
+        }
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn advance(&mut self, cycles: u32) {
+        self.timestamp += cycles as u64;
+    }
+
+    /// Schedules `event_type` to fire `cycles` cycles after the current timestamp.
+    pub fn schedule(&mut self, event_type: EventType, cycles: u64) {
+        self.schedule_at(event_type, self.timestamp + cycles);
+    }
+
+    /// Schedules `event_type` to fire at the absolute `timestamp`.
+    pub fn schedule_at(&mut self, event_type: EventType, timestamp: u64) {
+        let index = self.events.partition_point(|event| event.timestamp <= timestamp);
+        self.events.insert(index, Event { timestamp, event_type });
+    }
+
+    pub fn cancel(&mut self, event_type: EventType) {
+        self.events.retain(|event| event.event_type != event_type);
+    }
+
+    #[cfg(test)]
+    pub fn is_scheduled(&self, event_type: EventType) -> bool {
+        self.events.iter().any(|event| event.event_type == event_type)
+    }
+
+    pub fn next_event_timestamp(&self) -> u64 {
+        self.events.first().map_or(u64::MAX, |event| event.timestamp)
+    }
+
+    /// Fast forwards the timestamp up to the next event (used while the CPU is halted).
+    pub fn skip_to_next_event(&mut self) {
+        let next_timestamp = self.next_event_timestamp();
+
+        if next_timestamp != u64::MAX && next_timestamp > self.timestamp {
+            self.timestamp = next_timestamp;
+        }
+    }
+
+    /// Removes and returns the next due event alongside the timestamp it was scheduled for,
+    /// which allows periodic events to reschedule themselves without accumulating drift.
+    pub fn pop_pending_event(&mut self) -> Option<(EventType, u64)> {
+        match self.events.first() {
+            Some(event) if event.timestamp <= self.timestamp => {
+                let event = self.events.remove(0);
+                Some((event.event_type, event.timestamp))
+            },
+            _ => None
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventType {
+    fn to_bits(self) -> (u8, u8) {
+        match self {
+            EventType::FrameEnd => (0, 0),
+            EventType::HBlankStart => (1, 0),
+            EventType::HDrawStart => (2, 0),
+            EventType::DmaStart(channel) => (3, channel as u8),
+            EventType::TimerOverflow(timer) => (4, timer as u8),
+            EventType::ApuSample => (5, 0),
+            EventType::ApuFrameSequencer => (6, 0)
+        }
+    }
+
+    fn from_bits(kind: u8, index: u8) -> Option<Self> {
+        let index = index as usize;
+
+        match kind {
+            0 => Some(EventType::FrameEnd),
+            1 => Some(EventType::HBlankStart),
+            2 => Some(EventType::HDrawStart),
+            3 if index < 4 => Some(EventType::DmaStart(index)),
+            4 if index < 4 => Some(EventType::TimerOverflow(index)),
+            5 => Some(EventType::ApuSample),
+            6 => Some(EventType::ApuFrameSequencer),
+            _ => None
+        }
+    }
+}
+
+impl SaveState for Scheduler {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u64(self.timestamp);
+        writer.write_usize(self.events.len());
+
+        for event in self.events.iter() {
+            let (kind, index) = event.event_type.to_bits();
+            writer.write_u64(event.timestamp);
+            writer.write_u8(kind);
+            writer.write_u8(index);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.timestamp = reader.read_u64()?;
+        self.events.clear();
+
+        for _ in 0..reader.read_usize()? {
+            let timestamp = reader.read_u64()?;
+            let event_type = EventType::from_bits(reader.read_u8()?, reader.read_u8()?).ok_or(SaveStateError::InvalidValue)?;
+
+            // Saved in firing order already
+            self.events.push(Event { timestamp, event_type });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_pop_in_timestamp_order() {
+        let mut scheduler = Scheduler::new();
+
+        scheduler.schedule(EventType::FrameEnd, 20);
+        scheduler.schedule_at(EventType::FrameEnd, 10);
+
+        assert_eq!(scheduler.next_event_timestamp(), 10);
+        assert_eq!(scheduler.pop_pending_event(), None);
+
+        scheduler.advance(25);
+
+        assert_eq!(scheduler.pop_pending_event(), Some((EventType::FrameEnd, 10)));
+        assert_eq!(scheduler.pop_pending_event(), Some((EventType::FrameEnd, 20)));
+        assert_eq!(scheduler.pop_pending_event(), None);
+        assert_eq!(scheduler.next_event_timestamp(), u64::MAX);
+    }
+
+    #[test]
+    fn cancel_and_skip_work() {
+        let mut scheduler = Scheduler::new();
+
+        scheduler.schedule(EventType::FrameEnd, 100);
+        assert!(scheduler.is_scheduled(EventType::FrameEnd));
+
+        scheduler.skip_to_next_event();
+        assert_eq!(scheduler.timestamp(), 100);
+
+        scheduler.cancel(EventType::FrameEnd);
+        assert!(!scheduler.is_scheduled(EventType::FrameEnd));
+
+        scheduler.skip_to_next_event();
+        assert_eq!(scheduler.timestamp(), 100);
+    }
+}
@@ -0,0 +1,187 @@
+use std::path::Path;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+// Truecolour, 8 bits per channel
+const PNG_BIT_DEPTH: u8 = 8;
+const PNG_COLOR_TYPE_RGB: u8 = 2;
+const PNG_FILTER_NONE: u8 = 0;
+
+// zlib header for deflate with a 32 KiB window and no compression, a multiple of 31 as required
+const ZLIB_HEADER: [u8; 2] = [0x78, 0x01];
+const DEFLATE_STORED_BLOCK_MAX: usize = 0xFFFF;
+
+/// File formats screenshots can be saved in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ImageFormat {
+    /// Uncompressed PNG
+    Png,
+    /// Binary portable pixmap (P6), readable by about every image tool
+    Ppm
+}
+
+impl ImageFormat {
+    /// Format matching the extension of `path`, case insensitive.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "png" => Some(ImageFormat::Png),
+            "ppm" => Some(ImageFormat::Ppm),
+            _ => None
+        }
+    }
+
+    /// Encodes RGB888 pixels, 3 bytes per pixel row by row.
+    pub fn encode(self, width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+        match self {
+            ImageFormat::Png => encode_png(width, height, rgb),
+            ImageFormat::Ppm => encode_ppm(width, height, rgb)
+        }
+    }
+}
+
+pub fn encode_ppm(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    let mut image = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    image.extend_from_slice(&rgb[..width * height * 3]);
+
+    image
+}
+
+/// Writes a PNG without compression: the pixel data goes in stored deflate blocks, which keeps
+/// the encoder small at the cost of bigger files.
+pub fn encode_png(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    let mut image = PNG_SIGNATURE.to_vec();
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // Default compression, filtering and no interlacing
+    header.extend_from_slice(&[PNG_BIT_DEPTH, PNG_COLOR_TYPE_RGB, 0, 0, 0]);
+    write_png_chunk(&mut image, b"IHDR", &header);
+
+    // Every row starts with the filter it was encoded with
+    let mut scanlines = Vec::with_capacity(height * (width * 3 + 1));
+    for row in rgb.chunks_exact(width * 3).take(height) {
+        scanlines.push(PNG_FILTER_NONE);
+        scanlines.extend_from_slice(row);
+    }
+
+    write_png_chunk(&mut image, b"IDAT", &zlib_stored(&scanlines));
+    write_png_chunk(&mut image, b"IEND", &[]);
+
+    image
+}
+
+fn write_png_chunk(image: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    image.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+    let crc_start = image.len();
+    image.extend_from_slice(chunk_type);
+    image.extend_from_slice(data);
+
+    let crc = crc32(&image[crc_start..]);
+    image.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = ZLIB_HEADER.to_vec();
+    let block_count = data.len().div_ceil(DEFLATE_STORED_BLOCK_MAX).max(1);
+
+    for index in 0..block_count {
+        let block = &data[(index * DEFLATE_STORED_BLOCK_MAX).min(data.len())..((index + 1) * DEFLATE_STORED_BLOCK_MAX).min(data.len())];
+        let length = block.len() as u16;
+
+        // BFINAL on the last block, BTYPE 00 for stored, then the length and its complement
+        stream.push((index == block_count - 1) as u8);
+        stream.extend_from_slice(&length.to_le_bytes());
+        stream.extend_from_slice(&(!length).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+
+    stream
+}
+
+// Reflected CRC-32 with the 0xEDB88320 polynomial, as used by PNG and zlib
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+
+    for &byte in data {
+        crc ^= byte as u32;
+
+        for _ in 0..8 {
+            crc = if (crc & 1) != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MODULO: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+
+    for &byte in data {
+        a = (a + byte as u32) % MODULO;
+        b = (b + a) % MODULO;
+    }
+
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_match_reference_values() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn png_holds_the_pixels_in_stored_blocks() {
+        let (width, height) = (200, 120);
+        let rgb: Vec<u8> = (0..width * height * 3).map(|i| (i * 7) as u8).collect();
+        let image = encode_png(width, height, &rgb);
+
+        assert_eq!(image[..8], PNG_SIGNATURE);
+        assert_eq!(image[12..16], *b"IHDR");
+        assert_eq!(image[16..24], [0, 0, 0, 200, 0, 0, 0, 120]);
+
+        let idat_length = u32::from_be_bytes(image[33..37].try_into().unwrap()) as usize;
+        assert_eq!(image[37..41], *b"IDAT");
+        let stream = &image[41..41 + idat_length];
+
+        // Walks the stored blocks back to the scanlines
+        let mut scanlines = Vec::new();
+        let mut position = 2;
+        loop {
+            let last = stream[position] == 1;
+            let length = u16::from_le_bytes([stream[position + 1], stream[position + 2]]) as usize;
+            assert_eq!(!u16::from_le_bytes([stream[position + 3], stream[position + 4]]) as usize, length);
+
+            scanlines.extend_from_slice(&stream[position + 5..position + 5 + length]);
+            position += 5 + length;
+
+            if last {
+                break;
+            }
+        }
+
+        assert_eq!(stream[position..], adler32(&scanlines).to_be_bytes());
+        assert_eq!(scanlines.len(), height * (width * 3 + 1));
+        assert_eq!(scanlines[0], PNG_FILTER_NONE);
+        assert_eq!(scanlines[width * 3 + 2..2 * width * 3 + 2], rgb[width * 3..2 * width * 3]);
+
+        assert_eq!(image[image.len() - 12..], [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+    }
+
+    #[test]
+    fn ppm_has_a_text_header() {
+        let image = ImageFormat::from_path(Path::new("frame.PPM")).unwrap().encode(2, 1, &[1, 2, 3, 4, 5, 6]);
+
+        assert_eq!(image, b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06");
+        assert_eq!(ImageFormat::from_path(Path::new("frame.bmp")), None);
+    }
+}
@@ -0,0 +1,309 @@
+use std::ops::RangeInclusive;
+
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+
+// Solar sensor pins
+const SOLAR_PIN_CLOCK: u8 = 1 << 0;
+const SOLAR_PIN_RESET: u8 = 1 << 1;
+// Active low, the RTC sharing the port is selected when high
+const SOLAR_PIN_NOT_SELECTED: u8 = 1 << 2;
+const SOLAR_PIN_FLAG: u8 = 1 << 3;
+
+// Gyro sensor pins
+const GYRO_PIN_START: u8 = 1 << 0;
+const GYRO_PIN_CLOCK: u8 = 1 << 1;
+const GYRO_PIN_DATA: u8 = 1 << 2;
+
+// Gyro output when the cartridge doesn't rotate, and the change at full speed
+const GYRO_CENTER: f32 = 0x6C0 as f32;
+const GYRO_RANGE: f32 = 0x500 as f32;
+
+// ADXL202 registers, in the SRAM area
+const TILT_REGS: RangeInclusive<usize> = 0x0E00_8000..=0x0E00_85FF;
+const TILT_START_LOW: usize = 0x0E00_8000;
+const TILT_START_HIGH: usize = 0x0E00_8100;
+const TILT_X_LOW: usize = 0x0E00_8200;
+const TILT_X_HIGH: usize = 0x0E00_8300;
+const TILT_Y_LOW: usize = 0x0E00_8400;
+const TILT_Y_HIGH: usize = 0x0E00_8500;
+const TILT_SAMPLE_READY: u8 = 1 << 7;
+
+// Tilt output when the cartridge is flat, and the change when it's on its side
+const TILT_CENTER: f32 = 0x3A0 as f32;
+const TILT_RANGE: f32 = 0x200 as f32;
+
+// Both ADCs have 12 bits
+const SENSOR_SAMPLE_MAX: f32 = 0xFFF as f32;
+
+fn sensor_sample(value: f32, center: f32, range: f32) -> u16 {
+    (center + value.clamp(-1.0, 1.0) * range).round().clamp(0.0, SENSOR_SAMPLE_MAX) as u16
+}
+
+/// Photodiode of Boktai cartridges. The game resets a counter, then clocks it until the flag
+/// goes up: the more light, the fewer pulses it takes.
+pub struct SolarSensor {
+    // From 0 (dark) to 255 (direct sunlight)
+    light_level: u8,
+    counter: u8,
+    previous_pins: u8
+}
+
+impl SolarSensor {
+    pub fn new() -> Self {
+        SolarSensor {
+            light_level: 0,
+            counter: 0,
+            previous_pins: 0
+        }
+    }
+
+    pub fn set_light_level(&mut self, light_level: u8) {
+        self.light_level = light_level;
+    }
+
+    pub fn read_pins(&self) -> u8 {
+        if self.counter >= 0xFF - self.light_level { SOLAR_PIN_FLAG } else { 0 }
+    }
+
+    pub fn write_pins(&mut self, pins: u8) {
+        let previous_pins = std::mem::replace(&mut self.previous_pins, pins);
+
+        if (pins & SOLAR_PIN_NOT_SELECTED) != 0 {
+            return;
+        }
+
+        if (pins & SOLAR_PIN_RESET) != 0 {
+            self.counter = 0;
+        } else if (previous_pins & SOLAR_PIN_CLOCK) == 0 && (pins & SOLAR_PIN_CLOCK) != 0 {
+            self.counter = self.counter.saturating_add(1);
+        }
+    }
+}
+
+impl Default for SolarSensor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Gyro sensor of WarioWare: Twisted!, a 12-bit ADC sampled on the start pin and shifted out
+/// MSB first on the falling edges of the clock.
+pub struct GyroSensor {
+    sample: u16,
+    shift: u16,
+    output_bit: u8,
+    previous_pins: u8
+}
+
+impl GyroSensor {
+    pub fn new() -> Self {
+        GyroSensor {
+            sample: GYRO_CENTER as u16,
+            shift: 0,
+            output_bit: 0,
+            previous_pins: 0
+        }
+    }
+
+    /// Rotation speed around the axis facing the player, from -1.0 to 1.0.
+    pub fn set_rotation(&mut self, rotation: f32) {
+        self.sample = sensor_sample(rotation, GYRO_CENTER, GYRO_RANGE);
+    }
+
+    pub fn read_pins(&self) -> u8 {
+        self.output_bit * GYRO_PIN_DATA
+    }
+
+    pub fn write_pins(&mut self, pins: u8) {
+        let previous_pins = std::mem::replace(&mut self.previous_pins, pins);
+
+        if (pins & GYRO_PIN_START) != 0 {
+            self.shift = self.sample;
+        }
+
+        if (previous_pins & GYRO_PIN_CLOCK) != 0 && (pins & GYRO_PIN_CLOCK) == 0 {
+            self.output_bit = (self.shift >> 15) as u8;
+            self.shift <<= 1;
+        }
+    }
+}
+
+impl Default for GyroSensor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// ADXL202 2-axis accelerometer, mapped in the SRAM area of Yoshi Topsy-Turvy and Koro Koro Puzzle.
+/// Writing 0x55 then 0xAA takes a sample of both axes.
+pub struct TiltSensor {
+    x: u16,
+    y: u16,
+    sampled: [u16; 2],
+    start_armed: bool,
+    sample_ready: bool
+}
+
+impl TiltSensor {
+    pub fn new() -> Self {
+        TiltSensor {
+            x: TILT_CENTER as u16,
+            y: TILT_CENTER as u16,
+            sampled: [0; 2],
+            start_armed: false,
+            sample_ready: false
+        }
+    }
+
+    /// Tilt along both axes, from -1.0 to 1.0. Positive x is to the right, positive y to the bottom.
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.x = sensor_sample(x, TILT_CENTER, TILT_RANGE);
+        self.y = sensor_sample(y, TILT_CENTER, TILT_RANGE);
+    }
+
+    pub fn is_register(address: usize) -> bool {
+        TILT_REGS.contains(&address)
+    }
+
+    pub fn read8(&self, address: usize) -> u8 {
+        match address & !0xFF {
+            TILT_X_LOW => self.sampled[0] as u8,
+            TILT_X_HIGH => (self.sampled[0] >> 8) as u8 | if self.sample_ready { TILT_SAMPLE_READY } else { 0 },
+            TILT_Y_LOW => self.sampled[1] as u8,
+            TILT_Y_HIGH => (self.sampled[1] >> 8) as u8,
+            _ => 0
+        }
+    }
+
+    pub fn write8(&mut self, address: usize, value: u8) {
+        match (address & !0xFF, value) {
+            (TILT_START_LOW, 0x55) => self.start_armed = true,
+            (TILT_START_HIGH, 0xAA) if self.start_armed => {
+                self.sampled = [self.x, self.y];
+                self.sample_ready = true;
+                self.start_armed = false;
+            },
+            _ => self.start_armed = false
+        }
+    }
+}
+
+impl Default for TiltSensor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SaveState for SolarSensor {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.light_level);
+        writer.write_u8(self.counter);
+        writer.write_u8(self.previous_pins);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.light_level = reader.read_u8()?;
+        self.counter = reader.read_u8()?;
+        self.previous_pins = reader.read_u8()?;
+
+        Ok(())
+    }
+}
+
+impl SaveState for GyroSensor {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.sample);
+        writer.write_u16(self.shift);
+        writer.write_u8(self.output_bit);
+        writer.write_u8(self.previous_pins);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.sample = reader.read_u16()?;
+        self.shift = reader.read_u16()?;
+        self.output_bit = reader.read_u8()? & 1;
+        self.previous_pins = reader.read_u8()?;
+
+        Ok(())
+    }
+}
+
+impl SaveState for TiltSensor {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for value in [self.x, self.y, self.sampled[0], self.sampled[1]] {
+            writer.write_u16(value);
+        }
+
+        writer.write_bool(self.start_armed);
+        writer.write_bool(self.sample_ready);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.x = reader.read_u16()?;
+        self.y = reader.read_u16()?;
+        self.sampled = [reader.read_u16()?, reader.read_u16()?];
+
+        self.start_armed = reader.read_bool()?;
+        self.sample_ready = reader.read_bool()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn solar_flag_rises_sooner_with_more_light() {
+        let count_pulses = |light_level| {
+            let mut sensor = SolarSensor::new();
+            sensor.set_light_level(light_level);
+            sensor.write_pins(SOLAR_PIN_RESET);
+
+            let mut pulses = 0;
+            while sensor.read_pins() == 0 {
+                sensor.write_pins(0);
+                sensor.write_pins(SOLAR_PIN_CLOCK);
+                pulses += 1;
+            }
+
+            pulses
+        };
+
+        assert!(count_pulses(200) < count_pulses(50));
+        assert_eq!(count_pulses(0xFF), 0);
+    }
+
+    #[test]
+    fn gyro_sample_is_shifted_out_msb_first() {
+        let mut sensor = GyroSensor::new();
+        sensor.set_rotation(0.0);
+        sensor.write_pins(GYRO_PIN_START | GYRO_PIN_CLOCK);
+
+        let mut value = 0u16;
+        for _ in 0..16 {
+            sensor.write_pins(GYRO_PIN_CLOCK);
+            sensor.write_pins(0);
+            value = (value << 1) | (sensor.read_pins() >> 2) as u16;
+        }
+
+        assert_eq!(value, 0x6C0);
+    }
+
+    #[test]
+    fn tilt_is_sampled_after_the_start_sequence() {
+        let mut sensor = TiltSensor::new();
+        sensor.set_tilt(1.0, -1.0);
+
+        assert_eq!(sensor.read8(TILT_X_HIGH) & TILT_SAMPLE_READY, 0);
+
+        sensor.write8(TILT_START_LOW, 0x55);
+        sensor.write8(TILT_START_HIGH, 0xAA);
+
+        assert_eq!(sensor.read8(TILT_X_LOW), 0xA0);
+        assert_eq!(sensor.read8(TILT_X_HIGH), TILT_SAMPLE_READY | 0x05);
+        assert_eq!(sensor.read8(TILT_Y_LOW), 0xA0);
+        assert_eq!(sensor.read8(TILT_Y_HIGH), 0x01);
+    }
+}
@@ -1,9 +1,25 @@
 use std::ops::RangeInclusive;
 
+use crate::apu::{APU, FRAME_SEQUENCER_CYCLES};
+use crate::backup::BackupType;
+use crate::cartridge::Cartridge;
+use crate::dma::{DMAController, DMAStartTiming};
+use crate::interrupts::{InterruptController, InterruptType};
+use crate::keypad::Keypad;
+use crate::ppu::{PPU, HBLANK_CYCLES, HDRAW_CYCLES, SCREEN_HEIGHT};
+use crate::rtc::RtcClock;
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+use crate::scheduler::{EventType, Scheduler};
+use crate::timers::TimerController;
+use crate::waitstates::WaitstateControl;
+
+pub const BIOS_SIZE: usize = 16 * 1024;
 const IWRAM_SIZE: usize = 32 * 1024;
 const EWRAM_SIZE: usize = 256 * 1024;
 const VRAM_SIZE: usize = 96 * 1024;
+#[allow(clippy::identity_op)]
 const OAM_SIZE: usize = 1 * 1024;
+#[allow(clippy::identity_op)]
 const PAL_RAM_SIZE: usize = 1 * 1024;
 
 const BIOS_AREA: RangeInclusive<usize> = 0x0000000..=0x0000_3FFF;
@@ -13,6 +29,19 @@ const IOREGS_AREA: RangeInclusive<usize> = 0x0400_0000..=0x0400_03FF;
 const PAL_AREA: RangeInclusive<usize> = 0x0500_0000..=0x0500_03FF;
 const VRAM_AREA: RangeInclusive<usize> = 0x0600_0000..=0x0601_7FFF;
 const OAM_AREA: RangeInclusive<usize> = 0x0700_0000..=0x0700_03FF;
+const ROM_AREA: RangeInclusive<usize> = 0x0800_0000..=0x0DFF_FFFF;
+const BACKUP_AREA: RangeInclusive<usize> = 0x0E00_0000..=0x0FFF_FFFF;
+
+// I/O registers blocks, as offsets from the start of the I/O area
+const LCD_REGS: RangeInclusive<usize> = 0x000..=0x05F;
+const SOUND_REGS: RangeInclusive<usize> = 0x060..=0x0AF;
+const DMA_REGS: RangeInclusive<usize> = 0x0B0..=0x0DF;
+const TIMER_REGS: RangeInclusive<usize> = 0x100..=0x10F;
+const KEYPAD_REGS: RangeInclusive<usize> = 0x130..=0x133;
+const INTERRUPT_CONTROL_REGS: RangeInclusive<usize> = 0x200..=0x203;
+const WAITCNT_REG: RangeInclusive<usize> = 0x204..=0x205;
+const INTERRUPT_MASTER_ENABLE_REGS: RangeInclusive<usize> = 0x208..=0x20B;
+const POWER_CONTROL_REGS: RangeInclusive<usize> = 0x300..=0x301;
 
 pub trait MemoryOperation {
     fn read8(&self, address: usize) -> u8;
@@ -33,11 +62,13 @@ pub trait MemoryOperation {
 
     fn write8(&mut self, address: usize, value: u8);
     
+    #[allow(clippy::identity_op)]
     fn write16(&mut self, address: usize, value: u16) {
         self.write8(address, value as u8 & 0xFFu8);
         self.write8(address.wrapping_add(1), (value >> 8) as u8 & 0xFFu8);
     }
     
+    #[allow(clippy::identity_op)]
     fn write32(&mut self, address: usize, value: u32) {
         self.write16(address, value as u16 & 0xFFFFu16);
         self.write16(address.wrapping_add(2), (value >> 16) as u16 & 0xFFFFu16);
@@ -45,44 +76,176 @@ pub trait MemoryOperation {
 }
 
 pub struct SysMem {
+    bios: [u8; BIOS_SIZE],
     iwram: [u8; IWRAM_SIZE],
     ewram: [u8; EWRAM_SIZE],
     vram: [u8; VRAM_SIZE],
     oam: [u8; OAM_SIZE],
-    pal_ram: [u8; PAL_RAM_SIZE]
+    pal_ram: [u8; PAL_RAM_SIZE],
+
+    pub(crate) scheduler: Scheduler,
+    pub(crate) interrupts: InterruptController,
+    pub(crate) keypad: Keypad,
+    pub(crate) ppu: PPU,
+    pub(crate) apu: APU,
+    pub(crate) dma: DMAController,
+    pub(crate) timers: TimerController,
+    pub(crate) waitstates: WaitstateControl,
+    pub(crate) cartridge: Cartridge
 }
 
 impl SysMem {
     pub fn new() -> Self {
-        SysMem {
+        let mut sys_mem = SysMem {
+            bios: [0; BIOS_SIZE],
             iwram: [0; IWRAM_SIZE],
             ewram: [0; EWRAM_SIZE],
             vram: [0; VRAM_SIZE],
             oam: [0; OAM_SIZE],
-            pal_ram: [0; PAL_RAM_SIZE]
+            pal_ram: [0; PAL_RAM_SIZE],
+
+            scheduler: Scheduler::new(),
+            interrupts: InterruptController::new(),
+            keypad: Keypad::new(),
+            ppu: PPU::new(),
+            apu: APU::new(),
+            dma: DMAController::new(),
+            timers: TimerController::new(),
+            waitstates: WaitstateControl::new(),
+            cartridge: Cartridge::empty()
+        };
+
+        sys_mem.scheduler.schedule(EventType::HBlankStart, HDRAW_CYCLES);
+        sys_mem.scheduler.schedule(EventType::ApuSample, sys_mem.apu.sample_cycles() as u64);
+        sys_mem.scheduler.schedule(EventType::ApuFrameSequencer, FRAME_SEQUENCER_CYCLES as u64);
+
+        sys_mem
+    }
+
+    /// Maps the BIOS from address 0, it has to be exactly 16 KiB.
+    pub fn load_bios(&mut self, bios: &[u8]) {
+        self.bios.copy_from_slice(bios);
+    }
+
+    /// Maps the ROM in the Game Pak area, with the given save memory or the one found in the ROM.
+    pub fn load_rom(&mut self, rom: Vec<u8>, backup_type: Option<BackupType>, rtc_clock: RtcClock) {
+        let backup_type = backup_type.unwrap_or_else(|| BackupType::detect(&rom));
+        self.cartridge = Cartridge::new(rom, backup_type.create(), rtc_clock);
+    }
+
+    pub fn start_hblank(&mut self, timestamp: u64) {
+        self.ppu.start_hblank(&mut self.interrupts);
+
+        if self.ppu.is_in_vdraw() {
+            self.ppu.render_scanline(&self.vram, &self.pal_ram, &self.oam);
+            self.trigger_dma(DMAStartTiming::HBlank);
+        }
+
+        self.scheduler.schedule_at(EventType::HDrawStart, timestamp + HBLANK_CYCLES);
+    }
+
+    pub fn start_hdraw(&mut self, timestamp: u64) {
+        self.ppu.start_hdraw(&mut self.interrupts);
+
+        if self.ppu.vcount() as usize == SCREEN_HEIGHT {
+            self.trigger_dma(DMAStartTiming::VBlank);
+        }
+
+        self.scheduler.schedule_at(EventType::HBlankStart, timestamp + HDRAW_CYCLES);
+    }
+
+    pub fn generate_audio_sample(&mut self, timestamp: u64) {
+        self.apu.generate_sample();
+        self.scheduler.schedule_at(EventType::ApuSample, timestamp + self.apu.sample_cycles() as u64);
+    }
+
+    pub fn step_frame_sequencer(&mut self, timestamp: u64) {
+        self.apu.step_frame_sequencer();
+        self.scheduler.schedule_at(EventType::ApuFrameSequencer, timestamp + FRAME_SEQUENCER_CYCLES as u64);
+    }
+
+    /// Requests the keypad interrupt when the KEYCNT condition is met by the buttons being held.
+    pub fn update_keypad_interrupt(&mut self) {
+        if self.keypad.irq_condition_met() {
+            self.interrupts.request(InterruptType::Keypad);
+        }
+    }
+
+    fn read_io8(&self, offset: usize) -> u8 {
+        if LCD_REGS.contains(&offset) {
+            self.ppu.read8(offset)
+        } else if SOUND_REGS.contains(&offset) {
+            self.apu.read8(offset)
+        } else if DMA_REGS.contains(&offset) {
+            self.dma.read8(offset)
+        } else if TIMER_REGS.contains(&offset) {
+            self.timers.read8(offset, self.scheduler.timestamp())
+        } else if KEYPAD_REGS.contains(&offset) {
+            self.keypad.read8(offset)
+        } else if WAITCNT_REG.contains(&offset) {
+            self.waitstates.read8(offset)
+        } else if INTERRUPT_CONTROL_REGS.contains(&offset) || INTERRUPT_MASTER_ENABLE_REGS.contains(&offset) || POWER_CONTROL_REGS.contains(&offset) {
+            self.interrupts.read8(offset)
+        } else {
+            0
+        }
+    }
+
+    fn write_io8(&mut self, offset: usize, value: u8) {
+        if LCD_REGS.contains(&offset) {
+            self.ppu.write8(offset, value);
+        } else if SOUND_REGS.contains(&offset) {
+            self.apu.write8(offset, value);
+        } else if DMA_REGS.contains(&offset) {
+            self.dma.write8(offset, value, &mut self.scheduler);
+        } else if TIMER_REGS.contains(&offset) {
+            self.timers.write8(offset, value, &mut self.scheduler);
+        } else if KEYPAD_REGS.contains(&offset) {
+            self.keypad.write8(offset, value);
+            self.update_keypad_interrupt();
+        } else if WAITCNT_REG.contains(&offset) {
+            self.waitstates.write8(offset, value);
+        } else if INTERRUPT_CONTROL_REGS.contains(&offset) || INTERRUPT_MASTER_ENABLE_REGS.contains(&offset) || POWER_CONTROL_REGS.contains(&offset) {
+            self.interrupts.write8(offset, value);
         }
     }
 }
 
+impl Default for SysMem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MemoryOperation for SysMem {
     fn read8(&self, address: usize) -> u8 {
         if BIOS_AREA.contains(&address) {
-            0
+            // Reads aren't limited to code running from the BIOS itself
+            self.bios[address]
         } else if EWRAM_AREA.contains(&address) {
             self.ewram[address & 0x3FFFF]
         } else if IWRAM_AREA.contains(&address) {
             self.iwram[address & 0x7FFF]
         } else if IOREGS_AREA.contains(&address) {
-            0
+            self.read_io8(address & 0x3FF)
         } else if PAL_AREA.contains(&address) {
             self.pal_ram[address & 0x3FF]
         } else if VRAM_AREA.contains(&address) {
             self.vram[address & 0x17FFF]
         } else if OAM_AREA.contains(&address) {
             self.oam[address & 0x3FF]
+        } else if self.cartridge.is_eeprom_address(address) {
+            // Only the low byte of the 16-bit bus carries the serial bit
+            if (address & 1) == 0 { self.cartridge.read_eeprom16(self.scheduler.timestamp()) as u8 } else { 0 }
+        } else if ROM_AREA.contains(&address) {
+            self.cartridge.read_rom8(address)
+        } else if BACKUP_AREA.contains(&address) {
+            self.cartridge.read_backup8(address)
         }
         else {
-            panic!("Reading at memory address {address:#X}!")
+            // Unused memory area. Open bus is not emulated, and a panic would stop the emulation as
+            // soon as the CPU fetches past the BIOS or a game probes an unmapped address
+            0
         }
     }
 
@@ -94,16 +257,129 @@ impl MemoryOperation for SysMem {
         } else if IWRAM_AREA.contains(&address) {
             self.iwram[address & 0x7FFF] = value;
         } else if IOREGS_AREA.contains(&address) {
-            
+            self.write_io8(address & 0x3FF, value);
         } else if PAL_AREA.contains(&address) {
             self.pal_ram[address & 0x3FF] = value;
         } else if VRAM_AREA.contains(&address) {
             self.vram[address & 0x17FFF] = value;
         } else if OAM_AREA.contains(&address) {
             self.oam[address & 0x3FF] = value;
+        } else if ROM_AREA.contains(&address) {
+            let timestamp = self.scheduler.timestamp();
+            self.cartridge.write_rom8(address, value, timestamp);
+        } else if BACKUP_AREA.contains(&address) {
+            self.cartridge.write_backup8(address, value);
         }
         else {
-            panic!("Writting at memory address {address:#X}!")
+            // Unused memory area, writes are ignored
+        }
+    }
+
+    // The backup area sits on an 8-bit bus: wider reads see the addressed byte repeated,
+    // and wider writes only store the byte lane matching the address
+    fn read16(&self, address: usize) -> u16 {
+        // A single serial bit per access
+        if self.cartridge.is_eeprom_address(address) {
+            return self.cartridge.read_eeprom16(self.scheduler.timestamp());
+        }
+
+        if BACKUP_AREA.contains(&address) {
+            return self.read8(address) as u16 * 0x0101;
+        }
+
+        let lo : u16 = self.read8(address) as u16;
+        let hi : u16 = self.read8(address.wrapping_add(1)) as u16;
+
+        (hi << 8) | lo
+    }
+
+    fn read32(&self, address: usize) -> u32 {
+        if BACKUP_AREA.contains(&address) {
+            return self.read8(address) as u32 * 0x0101_0101;
         }
+
+        let lo : u32 = self.read16(address) as u32;
+        let hi : u32 = self.read16(address.wrapping_add(2)) as u32;
+
+        (hi << 16) | lo
     }
-}
\ No newline at end of file
+
+    fn write16(&mut self, address: usize, value: u16) {
+        if self.cartridge.is_eeprom_address(address) {
+            let timestamp = self.scheduler.timestamp();
+            self.cartridge.write_eeprom16(value, timestamp);
+            return;
+        }
+
+        if BACKUP_AREA.contains(&address) {
+            self.write8(address, (value >> (8 * (address & 1))) as u8);
+            return;
+        }
+
+        self.write8(address, value as u8);
+        self.write8(address.wrapping_add(1), (value >> 8) as u8);
+    }
+
+    fn write32(&mut self, address: usize, value: u32) {
+        if BACKUP_AREA.contains(&address) {
+            self.write8(address, (value >> (8 * (address & 3))) as u8);
+            return;
+        }
+
+        self.write16(address, value as u16);
+        self.write16(address.wrapping_add(2), (value >> 16) as u16);
+    }
+}
+
+impl SaveState for SysMem {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.iwram);
+        writer.write_bytes(&self.ewram);
+        writer.write_bytes(&self.vram);
+        writer.write_bytes(&self.oam);
+        writer.write_bytes(&self.pal_ram);
+
+        self.scheduler.save_state(writer);
+        self.interrupts.save_state(writer);
+        self.keypad.save_state(writer);
+        self.ppu.save_state(writer);
+        self.apu.save_state(writer);
+        self.dma.save_state(writer);
+        self.timers.save_state(writer);
+        self.waitstates.save_state(writer);
+        self.cartridge.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        reader.read_into(&mut self.iwram)?;
+        reader.read_into(&mut self.ewram)?;
+        reader.read_into(&mut self.vram)?;
+        reader.read_into(&mut self.oam)?;
+        reader.read_into(&mut self.pal_ram)?;
+
+        self.scheduler.load_state(reader)?;
+        self.interrupts.load_state(reader)?;
+        self.keypad.load_state(reader)?;
+        self.ppu.load_state(reader)?;
+        self.apu.load_state(reader)?;
+        self.dma.load_state(reader)?;
+        self.timers.load_state(reader)?;
+        self.waitstates.load_state(reader)?;
+        self.cartridge.load_state(reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unmapped_addresses_read_as_zero_and_ignore_writes() {
+        let mut sys_mem = SysMem::new();
+
+        for address in [0x0000_4000, 0x0100_0000, 0x0400_0400, 0x1000_0000] {
+            sys_mem.write32(address, 0xFFFF_FFFF);
+            assert_eq!(sys_mem.read32(address), 0);
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use crate::interrupts::InterruptType;
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+use crate::scheduler::{EventType, Scheduler};
+use crate::system_memory::SysMem;
+
+const TIMERS: usize = 4;
+const TIMER_REGS_SIZE: usize = 4;
+const TIMER_REGS_START: usize = 0x100;
+
+const PRESCALERS: [u64; 4] = [1, 64, 256, 1024];
+
+const TIMER_COUNT_UP: u8 = 1 << 2;
+const TIMER_IRQ_ENABLE: u8 = 1 << 6;
+const TIMER_ENABLE: u8 = 1 << 7;
+// Control bits changing how the counter runs, the IRQ enable bit doesn't
+const TIMER_COUNTING_BITS: u8 = TIMER_ENABLE | TIMER_COUNT_UP | 3;
+
+#[derive(Clone, Copy)]
+pub struct Timer {
+    reload: u16,
+    control: u8,
+
+    // Counter value at `start_timestamp`, the current value is worked out from the elapsed cycles
+    counter: u16,
+    start_timestamp: u64
+}
+
+impl Timer {
+    fn new() -> Self {
+        Timer {
+            reload: 0,
+            control: 0,
+            counter: 0,
+            start_timestamp: 0
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        (self.control & TIMER_ENABLE) != 0
+    }
+
+    fn prescaler(&self) -> u64 {
+        PRESCALERS[(self.control & 3) as usize]
+    }
+
+    /// Count-up (cascade) timers only tick when the previous timer overflows.
+    fn is_ticking_on_its_own(&self) -> bool {
+        self.is_enabled() && (self.control & TIMER_COUNT_UP) == 0
+    }
+
+    fn counter_at(&self, timestamp: u64) -> u16 {
+        if self.is_ticking_on_its_own() {
+            let elapsed_ticks = timestamp.saturating_sub(self.start_timestamp) / self.prescaler();
+            (self.counter as u64 + elapsed_ticks) as u16
+        } else {
+            self.counter
+        }
+    }
+
+    fn overflow_timestamp(&self) -> u64 {
+        self.start_timestamp + (0x10000 - self.counter as u64) * self.prescaler()
+    }
+}
+
+pub struct TimerController {
+    timers: [Timer; TIMERS]
+}
+
+impl TimerController {
+    pub fn new() -> Self {
+        TimerController {
+            timers: [Timer::new(); TIMERS]
+        }
+    }
+
+    pub fn read8(&self, offset: usize, timestamp: u64) -> u8 {
+        let timer = &self.timers[(offset - TIMER_REGS_START) / TIMER_REGS_SIZE];
+
+        // Reading TMxCNT_L returns the counter, not the reload value that was written
+        match (offset - TIMER_REGS_START) % TIMER_REGS_SIZE {
+            0 => timer.counter_at(timestamp) as u8,
+            1 => (timer.counter_at(timestamp) >> 8) as u8,
+            2 => timer.control,
+            _ => 0
+        }
+    }
+
+    pub fn write8(&mut self, offset: usize, value: u8, scheduler: &mut Scheduler) {
+        let timer_index = (offset - TIMER_REGS_START) / TIMER_REGS_SIZE;
+        let timestamp = scheduler.timestamp();
+        let timer = &mut self.timers[timer_index];
+
+        match (offset - TIMER_REGS_START) % TIMER_REGS_SIZE {
+            0 => timer.reload = (timer.reload & 0xFF00) | value as u16,
+            1 => timer.reload = (timer.reload & 0x00FF) | ((value as u16) << 8),
+            2 => {
+                // Timer 0 has no previous timer to count up with
+                let control = value & if timer_index == 0 { 0xC3 } else { 0xC7 };
+
+                // Only the IRQ enable bit changes, the counter goes on with the cycles elapsed since its last tick
+                if ((timer.control ^ control) & TIMER_COUNTING_BITS) == 0 {
+                    timer.control = control;
+                    return;
+                }
+
+                let was_enabled = timer.is_enabled();
+
+                // The counter keeps its current value when the timer is stopped or reconfigured
+                timer.counter = timer.counter_at(timestamp);
+                timer.start_timestamp = timestamp;
+                timer.control = control;
+
+                if !was_enabled && timer.is_enabled() {
+                    timer.counter = timer.reload;
+                }
+
+                scheduler.cancel(EventType::TimerOverflow(timer_index));
+
+                if timer.is_ticking_on_its_own() {
+                    scheduler.schedule_at(EventType::TimerOverflow(timer_index), timer.overflow_timestamp());
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+impl Default for TimerController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SysMem {
+    /// Handles a timer reaching 0x10000 at `timestamp`, along with the count-up timers it makes overflow.
+    pub fn handle_timer_overflow(&mut self, timer_index: usize, timestamp: u64) {
+        let timer = &mut self.timers.timers[timer_index];
+
+        timer.counter = timer.reload;
+        timer.start_timestamp = timestamp;
+        self.scheduler.schedule_at(EventType::TimerOverflow(timer_index), timer.overflow_timestamp());
+
+        let mut overflowed_timer = timer_index;
+
+        loop {
+            self.on_timer_overflow(overflowed_timer);
+
+            let next_index = overflowed_timer + 1;
+
+            if next_index == TIMERS {
+                break;
+            }
+
+            let next_timer = &mut self.timers.timers[next_index];
+
+            if !next_timer.is_enabled() || next_timer.is_ticking_on_its_own() {
+                break;
+            }
+
+            next_timer.counter = next_timer.counter.wrapping_add(1);
+
+            if next_timer.counter != 0 {
+                break;
+            }
+
+            next_timer.counter = next_timer.reload;
+            overflowed_timer = next_index;
+        }
+    }
+
+    fn on_timer_overflow(&mut self, timer_index: usize) {
+        let timer = &self.timers.timers[timer_index];
+
+        if (timer.control & TIMER_IRQ_ENABLE) != 0 {
+            let interrupt = match timer_index {
+                0 => InterruptType::Timer0,
+                1 => InterruptType::Timer1,
+                2 => InterruptType::Timer2,
+                _ => InterruptType::Timer3
+            };
+
+            self.interrupts.request(interrupt);
+        }
+
+        // Timers 0 and 1 clock the Direct Sound FIFOs
+        if timer_index < 2 {
+            self.advance_direct_sound(timer_index);
+        }
+    }
+}
+
+impl SaveState for TimerController {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for timer in self.timers.iter() {
+            writer.write_u16(timer.reload);
+            writer.write_u8(timer.control);
+            writer.write_u16(timer.counter);
+            writer.write_u64(timer.start_timestamp);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        for timer in self.timers.iter_mut() {
+            timer.reload = reader.read_u16()?;
+            timer.control = reader.read_u8()?;
+            timer.counter = reader.read_u16()?;
+            timer.start_timestamp = reader.read_u64()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scheduler::EventType;
+    use crate::system_memory::{MemoryOperation, SysMem};
+
+    #[test]
+    fn counter_follows_prescaler() {
+        let mut sys_mem = SysMem::new();
+
+        sys_mem.write16(0x0400_0100, 0xFF00);
+        sys_mem.write16(0x0400_0102, 0x0081); // Enabled, prescaler 64
+        sys_mem.scheduler.advance(64 * 10 + 3);
+
+        assert_eq!(sys_mem.read16(0x0400_0100), 0xFF0A);
+        assert!(sys_mem.scheduler.is_scheduled(EventType::TimerOverflow(0)));
+    }
+
+    #[test]
+    fn toggling_the_irq_keeps_the_prescaler_phase() {
+        let mut sys_mem = SysMem::new();
+
+        sys_mem.write16(0x0400_0100, 0xFF00);
+        sys_mem.write16(0x0400_0102, 0x0081); // Enabled, prescaler 64
+
+        for _ in 0..10 {
+            sys_mem.scheduler.advance(60);
+            sys_mem.write16(0x0400_0102, 0x00C1);
+            sys_mem.scheduler.advance(4);
+            sys_mem.write16(0x0400_0102, 0x0081);
+        }
+
+        assert_eq!(sys_mem.read16(0x0400_0100), 0xFF0A);
+
+        // Changing the prescaler starts counting from the current value
+        sys_mem.scheduler.advance(63);
+        sys_mem.write16(0x0400_0102, 0x0080);
+        sys_mem.scheduler.advance(1);
+        assert_eq!(sys_mem.read16(0x0400_0100), 0xFF0B);
+    }
+
+    #[test]
+    fn overflow_reloads_and_cascades() {
+        let mut sys_mem = SysMem::new();
+
+        sys_mem.write16(0x0400_0200, 1 << 4); // IE: timer 1
+        sys_mem.write16(0x0400_0104, 0xFFFF);
+        sys_mem.write16(0x0400_0106, 0x00C4); // Timer 1: count-up, IRQ
+        sys_mem.write16(0x0400_0100, 0xFFF0);
+        sys_mem.write16(0x0400_0102, 0x0080);
+
+        sys_mem.scheduler.advance(16);
+        assert_eq!(sys_mem.scheduler.pop_pending_event(), Some((EventType::TimerOverflow(0), 16)));
+        sys_mem.handle_timer_overflow(0, 16);
+
+        assert_eq!(sys_mem.read16(0x0400_0100), 0xFFF0);
+        assert_eq!(sys_mem.read16(0x0400_0104), 0xFFFF);
+        assert_eq!(sys_mem.read16(0x0400_0202), 1 << 4);
+    }
+}
@@ -0,0 +1,129 @@
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+use std::sync::OnceLock;
+
+pub const FRAMEBUFFER_PIXELS: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
+
+/// Pixel layout a video sink receives.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PixelFormat {
+    /// 15-bit colours as stored by the GBA: red in bits 0-4, green in bits 5-9 and blue in bits 10-14
+    Rgb555,
+    /// 4 bytes per pixel in R, G, B, A order
+    Rgba8888
+}
+
+/// Pixels handed over to a video sink, in the format it asked for.
+#[derive(Clone, Copy)]
+pub enum Pixels<'a> {
+    /// Pixels for `PixelFormat::Rgb555`
+    Rgb555(&'a [u16]),
+    /// Pixels for `PixelFormat::Rgba8888`
+    Rgba8888(&'a [u8])
+}
+
+/// Receives the video output of the GBA. Both callbacks do nothing by default, so a sink
+/// only has to implement the one it cares about.
+pub trait VideoSink {
+    /// Format of the pixels given to the callbacks, RGB555 by default.
+    fn pixel_format(&self) -> PixelFormat {
+        PixelFormat::Rgb555
+    }
+
+    /// Called once the line `line` (0 to 159) has been rendered.
+    fn scanline_finished(&mut self, _line: usize, _pixels: Pixels) {}
+
+    /// Called at the start of V-Blank with the whole 240x160 picture.
+    fn frame_finished(&mut self, _pixels: Pixels) {}
+}
+
+/// How RGB555 colours are turned into 8-bit channels.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ColorCorrection {
+    /// Each channel is scaled as is, which looks much more saturated than on the console
+    #[default]
+    None,
+    /// Mimics the dark and washed out GBA screen: a steep gamma curve with colours bleeding into each other
+    Lcd
+}
+
+// Gamma of the GBA LCD, and of the screen the picture ends up on
+const LCD_GAMMA: f64 = 4.0;
+const OUTPUT_GAMMA: f64 = 2.2;
+
+/// Expands a 5-bit channel to 8 bits, so that 31 maps to 255.
+fn expand_channel(value: u16) -> u8 {
+    let value = (value & 0x1F) as u8;
+    (value << 3) | (value >> 2)
+}
+
+fn lcd_color(color: u16) -> [u8; 3] {
+    let linear = |value: u16| ((value & 0x1F) as f64 / 31.0).powf(LCD_GAMMA);
+    let (red, green, blue) = (linear(color), linear(color >> 5), linear(color >> 10));
+
+    // Each output channel mixes the three input ones, and the total is darker than pure white
+    let output = |from_red: f64, from_green: f64, from_blue: f64| {
+        let mixed = (from_red * red + from_green * green + from_blue * blue) / 255.0;
+        (mixed.powf(1.0 / OUTPUT_GAMMA) * 255.0 * 255.0 / 280.0).round() as u8
+    };
+
+    [output(255.0, 50.0, 0.0), output(10.0, 230.0, 30.0), output(50.0, 10.0, 220.0)]
+}
+
+// The LCD curve is too slow to compute for every pixel, so it's done once for all 32768 colours
+fn lcd_color_table() -> &'static [[u8; 3]] {
+    static TABLE: OnceLock<Box<[[u8; 3]]>> = OnceLock::new();
+    TABLE.get_or_init(|| (0..0x8000).map(lcd_color).collect())
+}
+
+pub fn rgb555_to_rgb888(color: u16, correction: ColorCorrection) -> [u8; 3] {
+    match correction {
+        ColorCorrection::None => [expand_channel(color), expand_channel(color >> 5), expand_channel(color >> 10)],
+        ColorCorrection::Lcd => lcd_color_table()[(color & 0x7FFF) as usize]
+    }
+}
+
+pub fn rgb555_to_rgba8888(color: u16, correction: ColorCorrection) -> [u8; 4] {
+    let [red, green, blue] = rgb555_to_rgb888(color, correction);
+    [red, green, blue, 0xFF]
+}
+
+/// Converts `source` into `destination`, which must hold 3 bytes per source pixel.
+pub fn convert_to_rgb888(source: &[u16], destination: &mut [u8], correction: ColorCorrection) {
+    for (color, pixel) in source.iter().zip(destination.chunks_exact_mut(3)) {
+        pixel.copy_from_slice(&rgb555_to_rgb888(*color, correction));
+    }
+}
+
+/// Converts `source` into `destination`, which must hold 4 bytes per source pixel.
+pub fn convert_to_rgba8888(source: &[u16], destination: &mut [u8], correction: ColorCorrection) {
+    for (color, pixel) in source.iter().zip(destination.chunks_exact_mut(4)) {
+        pixel.copy_from_slice(&rgb555_to_rgba8888(*color, correction));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rgb555_channels_expand_to_full_range() {
+        assert_eq!(rgb555_to_rgba8888(0x7FFF, ColorCorrection::None), [0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(rgb555_to_rgba8888(0x001F, ColorCorrection::None), [0xFF, 0x00, 0x00, 0xFF]);
+        assert_eq!(rgb555_to_rgba8888(0x0010 << 5, ColorCorrection::None), [0x00, 0x84, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn lcd_correction_darkens_and_mixes_colours() {
+        assert_eq!(rgb555_to_rgb888(0x0000, ColorCorrection::Lcd), [0, 0, 0]);
+
+        let white = rgb555_to_rgb888(0x7FFF, ColorCorrection::Lcd);
+        assert!(white.iter().all(|&channel| (200..255).contains(&channel)));
+
+        let [red, green, blue] = rgb555_to_rgb888(0x001F, ColorCorrection::Lcd);
+        assert!(red > green && green > 0 && blue > 0);
+
+        // Midtones get a lot darker
+        assert!(rgb555_to_rgb888(0x0010, ColorCorrection::Lcd)[0] < rgb555_to_rgb888(0x0010, ColorCorrection::None)[0]);
+    }
+}
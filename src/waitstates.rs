@@ -0,0 +1,336 @@
+use crate::save_state::{SaveState, SaveStateError, StateReader, StateWriter};
+
+// Game Pak waitstates selectable through WAITCNT, as extra cycles on top of the access cycle
+const SRAM_WAITSTATES: [u32; 4] = [4, 3, 2, 8];
+const WS_NON_SEQUENTIAL_WAITSTATES: [u32; 4] = [4, 3, 2, 8];
+const WS0_SEQUENTIAL_WAITSTATES: [u32; 2] = [2, 1];
+const WS1_SEQUENTIAL_WAITSTATES: [u32; 2] = [4, 1];
+const WS2_SEQUENTIAL_WAITSTATES: [u32; 2] = [8, 1];
+
+const WAITCNT_PREFETCH_ENABLE: u16 = 1 << 14;
+
+// Sequential Game Pak accesses can't cross a 128 KiB page, the first access of a page is non-sequential
+const ROM_PAGE_MASK: u32 = 0x1FFFF;
+
+const PREFETCH_BUFFER_HALFWORDS: u32 = 8;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AccessWidth {
+    // For the LDRB/STRB instructions, still to be written
+    #[allow(dead_code)]
+    Byte,
+    Halfword,
+    Word
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AccessType {
+    NonSequential,
+    Sequential
+}
+
+fn region(address: u32) -> u32 {
+    (address >> 24) & 0xF
+}
+
+fn is_rom_region(address: u32) -> bool {
+    (0x8..=0xD).contains(&region(address))
+}
+
+/// Game Pak prefetch buffer: while the CPU doesn't use the ROM bus, the following halfwords
+/// of the code being run are read ahead so that opcode fetches hitting them take a single cycle.
+#[derive(Clone, Copy)]
+struct PrefetchBuffer {
+    active: bool,
+    // Address of the oldest buffered halfword, or of the one being fetched when the buffer is empty
+    head_address: u32,
+    buffered_halfwords: u32,
+    // Cycles left before the halfword being fetched is in the buffer
+    countdown: u32
+}
+
+impl PrefetchBuffer {
+    fn new() -> Self {
+        PrefetchBuffer {
+            active: false,
+            head_address: 0,
+            buffered_halfwords: 0,
+            countdown: 0
+        }
+    }
+}
+
+/// WAITCNT, along with the access timings of every memory region.
+pub struct WaitstateControl {
+    wait_control: u16,
+
+    // 16-bit Game Pak access cycles for WS0, WS1 and WS2
+    rom_non_sequential_cycles: [u32; 3],
+    rom_sequential_cycles: [u32; 3],
+    sram_cycles: u32,
+
+    prefetch: PrefetchBuffer
+}
+
+impl WaitstateControl {
+    pub fn new() -> Self {
+        let mut waitstates = WaitstateControl {
+            wait_control: 0,
+            rom_non_sequential_cycles: [0; 3],
+            rom_sequential_cycles: [0; 3],
+            sram_cycles: 0,
+            prefetch: PrefetchBuffer::new()
+        };
+
+        waitstates.update_timings();
+
+        waitstates
+    }
+
+    pub fn read8(&self, offset: usize) -> u8 {
+        match offset {
+            0x204 => self.wait_control as u8,
+            0x205 => (self.wait_control >> 8) as u8,
+            _ => 0
+        }
+    }
+
+    pub fn write8(&mut self, offset: usize, value: u8) {
+        match offset {
+            0x204 => self.wait_control = (self.wait_control & 0xFF00) | value as u16,
+            // The Game Pak type flag (bit 15) is read-only, and always 0 for GBA cartridges
+            0x205 => self.wait_control = (self.wait_control & 0x00FF) | ((value as u16 & 0x7F) << 8),
+            _ => {}
+        }
+
+        self.update_timings();
+    }
+
+    fn is_prefetch_enabled(&self) -> bool {
+        (self.wait_control & WAITCNT_PREFETCH_ENABLE) != 0
+    }
+
+    fn update_timings(&mut self) {
+        let control = self.wait_control as usize;
+
+        self.sram_cycles = 1 + SRAM_WAITSTATES[control & 3];
+        self.rom_non_sequential_cycles = [
+            1 + WS_NON_SEQUENTIAL_WAITSTATES[(control >> 2) & 3],
+            1 + WS_NON_SEQUENTIAL_WAITSTATES[(control >> 5) & 3],
+            1 + WS_NON_SEQUENTIAL_WAITSTATES[(control >> 8) & 3]
+        ];
+        self.rom_sequential_cycles = [
+            1 + WS0_SEQUENTIAL_WAITSTATES[(control >> 4) & 1],
+            1 + WS1_SEQUENTIAL_WAITSTATES[(control >> 7) & 1],
+            1 + WS2_SEQUENTIAL_WAITSTATES[(control >> 10) & 1]
+        ];
+
+        if !self.is_prefetch_enabled() {
+            self.prefetch.active = false;
+        }
+    }
+
+    /// Cycles taken by a single access, without the prefetch buffer.
+    pub fn access_cycles(&self, address: u32, width: AccessWidth, access: AccessType) -> u32 {
+        match region(address) {
+            // EWRAM has a 16-bit bus and 2 waitstates
+            0x2 if width == AccessWidth::Word => 6,
+            0x2 => 3,
+            // Palette RAM and VRAM have a 16-bit bus
+            0x5 | 0x6 if width == AccessWidth::Word => 2,
+            0x8..=0xD => {
+                let waitstate = ((region(address) - 0x8) / 2) as usize;
+                let access = if (address & ROM_PAGE_MASK) == 0 { AccessType::NonSequential } else { access };
+
+                let first_cycles = match access {
+                    AccessType::NonSequential => self.rom_non_sequential_cycles[waitstate],
+                    AccessType::Sequential => self.rom_sequential_cycles[waitstate]
+                };
+
+                // 32-bit accesses are split in two 16-bit ones, the second one being sequential
+                if width == AccessWidth::Word {
+                    first_cycles + self.rom_sequential_cycles[waitstate]
+                } else {
+                    first_cycles
+                }
+            },
+            // The SRAM bus is only 8 bits wide, wider accesses only see one byte
+            0xE | 0xF => self.sram_cycles,
+            // BIOS, IWRAM, I/O registers, OAM and unused areas
+            _ => 1
+        }
+    }
+
+    /// Data access by the CPU or DMA. Accessing the Game Pak interrupts the prefetcher, other
+    /// accesses leave it time to fetch.
+    pub fn data_access_cycles(&mut self, address: u32, width: AccessWidth, access: AccessType) -> u32 {
+        let cycles = self.access_cycles(address, width, access);
+
+        if is_rom_region(address) {
+            self.prefetch.active = false;
+        } else {
+            self.run_prefetch(cycles);
+        }
+
+        cycles
+    }
+
+    /// Cycles the CPU spends without accessing memory.
+    // For the internal cycles of multiplies and register shifts, still to be written
+    #[allow(dead_code)]
+    pub fn idle_cycles(&mut self, cycles: u32) {
+        self.run_prefetch(cycles);
+    }
+
+    pub fn opcode_fetch_cycles(&mut self, address: u32, width: AccessWidth, access: AccessType) -> u32 {
+        if !is_rom_region(address) || !self.is_prefetch_enabled() {
+            return self.data_access_cycles(address, width, access);
+        }
+
+        let halfwords = if width == AccessWidth::Word { 2 } else { 1 };
+        let mut cycles = 0;
+
+        for index in 0..halfwords {
+            let halfword_address = address.wrapping_add(index * 2);
+            let halfword_access = if index == 0 { access } else { AccessType::Sequential };
+
+            cycles += self.fetch_halfword_with_prefetch(halfword_address, halfword_access);
+        }
+
+        cycles
+    }
+
+    fn fetch_halfword_with_prefetch(&mut self, address: u32, access: AccessType) -> u32 {
+        if self.prefetch.active && self.prefetch.head_address == address {
+            let was_buffered = self.prefetch.buffered_halfwords > 0;
+
+            let cycles = if was_buffered {
+                self.prefetch.buffered_halfwords -= 1;
+                1
+            } else {
+                // Waits for the halfword being fetched, which then goes straight to the CPU
+                let remaining_cycles = self.prefetch.countdown;
+                self.prefetch.countdown = self.access_cycles(address.wrapping_add(2), AccessWidth::Halfword, AccessType::Sequential);
+                remaining_cycles
+            };
+
+            self.prefetch.head_address = address.wrapping_add(2);
+
+            // The prefetcher keeps going during the single cycle taken by a buffered opcode
+            if was_buffered {
+                self.run_prefetch(1);
+            }
+
+            return cycles;
+        }
+
+        // Missed: the CPU reads the halfword itself and the prefetcher starts again right after it
+        let cycles = self.access_cycles(address, AccessWidth::Halfword, access);
+
+        self.prefetch = PrefetchBuffer {
+            active: true,
+            head_address: address.wrapping_add(2),
+            buffered_halfwords: 0,
+            countdown: self.access_cycles(address.wrapping_add(2), AccessWidth::Halfword, AccessType::Sequential)
+        };
+
+        cycles
+    }
+
+    fn run_prefetch(&mut self, mut cycles: u32) {
+        while self.prefetch.active && cycles > 0 && self.prefetch.buffered_halfwords < PREFETCH_BUFFER_HALFWORDS {
+            if cycles < self.prefetch.countdown {
+                self.prefetch.countdown -= cycles;
+                return;
+            }
+
+            cycles -= self.prefetch.countdown;
+            self.prefetch.buffered_halfwords += 1;
+
+            let next_address = self.prefetch.head_address.wrapping_add(self.prefetch.buffered_halfwords * 2);
+            self.prefetch.countdown = self.access_cycles(next_address, AccessWidth::Halfword, AccessType::Sequential);
+        }
+    }
+}
+
+impl Default for WaitstateControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SaveState for WaitstateControl {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.wait_control);
+        writer.write_bool(self.prefetch.active);
+        writer.write_u32(self.prefetch.head_address);
+        writer.write_u32(self.prefetch.buffered_halfwords);
+        writer.write_u32(self.prefetch.countdown);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.wait_control = reader.read_u16()?;
+        // The access timings follow from WAITCNT
+        self.update_timings();
+
+        self.prefetch = PrefetchBuffer {
+            active: reader.read_bool()?,
+            head_address: reader.read_u32()?,
+            buffered_halfwords: reader.read_u32()?,
+            countdown: reader.read_u32()?
+        };
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waitcnt_selects_rom_and_sram_timings() {
+        let mut waitstates = WaitstateControl::new();
+
+        // Reset values: 4 non-sequential and 2 sequential waitstates on WS0
+        assert_eq!(waitstates.access_cycles(0x0800_0100, AccessWidth::Halfword, AccessType::NonSequential), 5);
+        assert_eq!(waitstates.access_cycles(0x0800_0100, AccessWidth::Word, AccessType::Sequential), 6);
+
+        waitstates.write8(0x204, 0x17); // SRAM: 8, WS0: 3/1
+        assert_eq!(waitstates.access_cycles(0x0E00_0000, AccessWidth::Byte, AccessType::NonSequential), 9);
+        assert_eq!(waitstates.access_cycles(0x0800_0100, AccessWidth::Word, AccessType::NonSequential), 4 + 2);
+        assert_eq!(waitstates.access_cycles(0x0900_0000, AccessWidth::Halfword, AccessType::Sequential), 4);
+        assert_eq!(waitstates.read8(0x204), 0x17);
+    }
+
+    #[test]
+    fn ram_regions_apply_bus_width_penalties() {
+        let waitstates = WaitstateControl::new();
+
+        assert_eq!(waitstates.access_cycles(0x0200_0000, AccessWidth::Halfword, AccessType::Sequential), 3);
+        assert_eq!(waitstates.access_cycles(0x0200_0000, AccessWidth::Word, AccessType::Sequential), 6);
+        assert_eq!(waitstates.access_cycles(0x0300_0000, AccessWidth::Word, AccessType::NonSequential), 1);
+        assert_eq!(waitstates.access_cycles(0x0600_0000, AccessWidth::Word, AccessType::NonSequential), 2);
+        assert_eq!(waitstates.access_cycles(0x0700_0000, AccessWidth::Word, AccessType::NonSequential), 1);
+    }
+
+    #[test]
+    fn prefetched_opcodes_take_one_cycle() {
+        let mut waitstates = WaitstateControl::new();
+
+        waitstates.write8(0x205, 0x40); // Prefetch on
+        assert_eq!(waitstates.opcode_fetch_cycles(0x0800_0000, AccessWidth::Halfword, AccessType::NonSequential), 5);
+
+        // Enough time to fill the buffer while running from elsewhere
+        waitstates.idle_cycles(3 * 8);
+
+        for index in 1..=8 {
+            assert_eq!(waitstates.opcode_fetch_cycles(0x0800_0000 + index * 2, AccessWidth::Halfword, AccessType::Sequential), 1);
+        }
+
+        // A data access to the Game Pak flushes the buffer
+        waitstates.idle_cycles(3 * 8);
+        waitstates.data_access_cycles(0x0800_1000, AccessWidth::Halfword, AccessType::NonSequential);
+        assert_eq!(waitstates.opcode_fetch_cycles(0x0800_0012, AccessWidth::Halfword, AccessType::Sequential), 3);
+    }
+}